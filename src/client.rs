@@ -1,11 +1,19 @@
 use rpassword;
 use getopts::Options;
 use std::string::String;
-use data_encoding::BASE32HEX;
+use data_encoding::{BASE32HEX, HEXLOWER};
 use wallet::{EncryptedWallet, Wallet, to32b};
 use net;
-use result::Result;
+use result::{Error, Result};
 use data;
+use std::io::ErrorKind;
+use std::net::UdpSocket;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// first wait for a reply in milliseconds, doubled after every retransmission
+const TIMEOUT_MS: u64 = 250;
+/// sends of a request before giving up
+const TRIES: u32 = 5;
 
 struct Cfg {
     host: String,
@@ -36,7 +44,14 @@ fn print_usage(program: &str, opts: Options) {
 fn load_wallet(cfg: &Cfg, pass: String) -> Wallet {
     println!("loading from {:?}", cfg.wallet);
     match EncryptedWallet::from_file(&cfg.wallet) {
-        Ok(ew) => ew.decrypt(pass.as_bytes()).expect("decrypt wallet"),
+        Ok(ew) => match ew.decrypt(pass.as_bytes()) {
+            Err(Error::OldKeypair(i)) => panic!(
+                "key {} of {:?} was made by an older loom and can't sign, its \
+                 transactions are rejected, create a new wallet",
+                i, cfg.wallet
+            ),
+            w => w.expect("decrypt wallet"),
+        },
         _ => Wallet::new(),
     }
 }
@@ -65,9 +80,69 @@ where
     let fpk = BASE32HEX.decode(from.as_bytes()).expect("from key");
     let tpk = BASE32HEX.decode(to.as_bytes()).expect("to key");
     let kix = w.find(vec_to_array(fpk))?;
-    let msg = w.tx(kix, vec_to_array(tpk), amnt, 1);
-    let s = net::socket()?;
-    s.connect(cfg.host.clone())?;
+    let nonce = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos()))
+        .unwrap_or(0);
+    let msg = w.tx_nonce(kix, vec_to_array(tpk), amnt, 1, nonce);
+    let s = net::connect(&cfg.host)?;
+    let rmsgs = request(&s, &[msg])?;
+    if rmsgs[0].pld.state != data::State::Deposited {
+        return Err(Error::Rejected);
+    }
+    Ok(())
+}
+
+/// send `msgs` until the reply group that starts with the signature of
+/// `msgs[0]` arrives, with twice the wait after every retransmission. loomd executes a
+/// transaction once and answers the copies with the same result
+fn request(s: &UdpSocket, msgs: &[data::Message]) -> Result<Vec<data::Message>> {
+    let mut wait = Duration::from_millis(TIMEOUT_MS);
+    for _ in 0..TRIES {
+        let mut num = 0;
+        while num < msgs.len() {
+            net::write(s, msgs, &mut num)?;
+        }
+        let deadline = Instant::now() + wait;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            s.set_read_timeout(Some(deadline - now))?;
+            let mut rmsgs = data::Messages::new();
+            let n = match rmsgs.with_mut(|m, d| net::read_from(s, m, d)) {
+                Ok(n) => n,
+                Err(Error::IO(ref e)) if e.kind() == ErrorKind::ConnectionRefused => continue,
+                Err(Error::IO(ref e))
+                    if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+                {
+                    break
+                }
+                Err(e) => return Err(e),
+            };
+            //loomd sends the replies to a client together
+            let mut ix = 0;
+            for &(z, _) in &rmsgs.data[..n] {
+                let end = ix + z;
+                while ix < end {
+                    let num = data::group_len(&rmsgs.msgs[ix..end]);
+                    if rmsgs.msgs[ix].sig[..] == msgs[0].sig[..] {
+                        return Ok(rmsgs.msgs[ix..ix + num].to_vec());
+                    }
+                    ix += num;
+                }
+            }
+        }
+        wait *= 2;
+    }
+    Err(Error::Timeout)
+}
+
+fn airdrop(cfg: &Cfg, to: String, amnt: u64) -> Result<()> {
+    let tpk = BASE32HEX.decode(to.as_bytes()).expect("to key");
+    let msg = Wallet::airdrop(vec_to_array(tpk), amnt);
+    let s = net::connect(&cfg.host)?;
     let mut num = 0;
     while num < 1 {
         net::write(&s, &[msg], &mut num)?;
@@ -75,6 +150,81 @@ where
     Ok(())
 }
 
+fn stats(cfg: &Cfg, addr: String) -> Result<()> {
+    let tpk = BASE32HEX.decode(addr.as_bytes()).expect("target key");
+    let st = get_stats(cfg, vec_to_array(tpk))?;
+    println!(
+        "tokens {:?} address tokens {:?} dropped {:?}",
+        st.key_tokens, st.addr_tokens, st.dropped
+    );
+    Ok(())
+}
+
+/// the rate limit state of `key` and of this client's address
+fn get_stats(cfg: &Cfg, key: [u8; 32]) -> Result<data::Stats> {
+    let msg = Wallet::get_stats(key);
+    let s = net::connect(&cfg.host)?;
+    let rmsgs = request(&s, &[msg])?;
+    if rmsgs.len() != 1 || rmsgs[0].pld.kind != data::Kind::GetStats {
+        return Err(Error::InvalidGroup);
+    }
+    Ok(*rmsgs[0].pld.get_stats())
+}
+
+fn audit<T>(cfg: &Cfg, r: Option<T>, from: String) -> Result<()>
+where
+    T: ::std::io::BufRead,
+{
+    let pass = getpass(r);
+    let w = load_wallet(cfg, pass);
+    let fpk = BASE32HEX.decode(from.as_bytes()).expect("from key");
+    let kix = w.find(vec_to_array(fpk))?;
+    let a = get_audit(cfg, &w, kix)?;
+    println!(
+        "supply {:?} fees {:?} ok {:?} failed audits {:?}",
+        a.supply,
+        a.fees,
+        a.ok == 1,
+        a.failures
+    );
+    Ok(())
+}
+
+/// have the daemon audit its supply, key `kix` pays the fee
+fn get_audit(cfg: &Cfg, w: &Wallet, kix: usize) -> Result<data::Audit> {
+    let msg = w.get_audit(kix, 1);
+    let s = net::connect(&cfg.host)?;
+    let rmsgs = request(&s, &[msg])?;
+    if rmsgs.len() != 1 || rmsgs[0].pld.kind != data::Kind::GetAudit {
+        return Err(Error::InvalidGroup);
+    }
+    Ok(*rmsgs[0].pld.get_audit())
+}
+
+fn hash<T>(cfg: &Cfg, r: Option<T>, from: String) -> Result<()>
+where
+    T: ::std::io::BufRead,
+{
+    let pass = getpass(r);
+    let w = load_wallet(cfg, pass);
+    let fpk = BASE32HEX.decode(from.as_bytes()).expect("from key");
+    let kix = w.find(vec_to_array(fpk))?;
+    let h = get_hash(cfg, &w, kix)?;
+    println!("height {:?} hash {}", h.height, HEXLOWER.encode(&h.hash));
+    Ok(())
+}
+
+/// the state hash of the daemon, key `kix` pays the fee
+fn get_hash(cfg: &Cfg, w: &Wallet, kix: usize) -> Result<data::GetHash> {
+    let msg = w.get_hash(kix, 1);
+    let s = net::connect(&cfg.host)?;
+    let rmsgs = request(&s, &[msg])?;
+    if rmsgs.len() != 1 || rmsgs[0].pld.kind != data::Kind::GetHash {
+        return Err(Error::InvalidGroup);
+    }
+    Ok(*rmsgs[0].pld.get_hash())
+}
+
 fn balance<T>(cfg: &Cfg, r: Option<T>, from: String, addr: String) -> Result<()>
 where
     T: ::std::io::BufRead,
@@ -85,21 +235,62 @@ where
     let tpk = BASE32HEX.decode(addr.as_bytes()).expect("target key");
     let kix = w.find(vec_to_array(fpk))?;
     let msg = w.check_balance(kix, vec_to_array(tpk), 1);
-    let s = net::socket()?;
-    s.connect(cfg.host.clone())?;
-    let mut num = 0;
-    while num < 1 {
-        net::write(&s, &[msg], &mut num)?;
+    let s = net::connect(&cfg.host)?;
+    let rmsgs = request(&s, &[msg])?;
+    if rmsgs.len() != 1 || rmsgs[0].pld.kind != data::Kind::GetBalance {
+        return Err(Error::InvalidGroup);
     }
-    let mut rmsgs = data::Messages::new();
-    rmsgs
-        .with_mut(|m, d| net::read_from(&s, m, d))
-        .expect("read rmsgs");
-    assert_eq!(rmsgs.data[0].0, 1);
-    println!("balance is {:?}", rmsgs.msgs[0].pld.get_bal().amount);
+    println!("balance is {:?}", rmsgs[0].pld.get_bal().amount);
     Ok(())
 }
 
+fn history<T>(cfg: &Cfg, r: Option<T>, from: String, addr: String, start: u32) -> Result<()>
+where
+    T: ::std::io::BufRead,
+{
+    let pass = getpass(r);
+    let w = load_wallet(cfg, pass);
+    let fpk = BASE32HEX.decode(from.as_bytes()).expect("from key");
+    let tpk = BASE32HEX.decode(addr.as_bytes()).expect("target key");
+    let kix = w.find(vec_to_array(fpk))?;
+    for (other, rec) in get_history(cfg, &w, kix, vec_to_array(tpk), start)? {
+        let dir = if rec.incoming == 1 { "from" } else { "to" };
+        println!(
+            "{:?}.{:?} {} {:?} {:?}",
+            rec.height,
+            rec.index,
+            dir,
+            BASE32HEX.encode(&other),
+            rec.amount
+        );
+    }
+    Ok(())
+}
+
+/// one page of the transfers of `acc` starting at the `start` newest
+fn get_history(
+    cfg: &Cfg,
+    w: &Wallet,
+    kix: usize,
+    acc: [u8; 32],
+    start: u32,
+) -> Result<Vec<([u8; 32], data::Record)>> {
+    let msg = w.get_history(kix, acc, start, data::MAX_BATCH as u32 - 1, 1);
+    let s = net::connect(&cfg.host)?;
+    let rmsgs = request(&s, &[msg])?;
+    if rmsgs[0].pld.kind != data::Kind::GetHistory {
+        return Err(Error::InvalidGroup);
+    }
+    let found = rmsgs[0].pld.get_hist().num as usize;
+    if rmsgs.len() != found + 1 || rmsgs[1..].iter().any(|m| m.pld.kind != data::Kind::Record) {
+        return Err(Error::InvalidGroup);
+    }
+    Ok(rmsgs[1..found + 1]
+        .iter()
+        .map(|m| (m.pld.from, *m.pld.get_rec()))
+        .collect())
+}
+
 fn list<T>(cfg: &Cfg, r: Option<T>)
 where
     T: ::std::io::BufRead,
@@ -107,12 +298,42 @@ where
     let pass = getpass(r);
     let w = load_wallet(cfg, pass);
     println!("wallet has {:?} keys", w.pubkeys.len());
-    for k in w.pubkeys {
-        let pretty = BASE32HEX.encode(&to32b(k));
-        println!("key {:?}", pretty);
+    let keys: Vec<[u8; 32]> = w.pubkeys.iter().map(|k| to32b(*k)).collect();
+    let bals = balances(cfg, &w, &keys).unwrap_or_else(|e| {
+        println!("balances unavailable {:?}", e);
+        Vec::new()
+    });
+    for (i, k) in keys.iter().enumerate() {
+        let pretty = BASE32HEX.encode(k);
+        match bals.get(i) {
+            Some(b) => println!("key {:?} balance {:?}", pretty, b),
+            None => println!("key {:?}", pretty),
+        }
     }
 }
 
+/// balances of `keys` with as few `GetBalances` queries as possible,
+/// the first key of the wallet pays the fees
+fn balances(cfg: &Cfg, w: &Wallet, keys: &[[u8; 32]]) -> Result<Vec<u64>> {
+    let mut bals = Vec::new();
+    if keys.is_empty() {
+        return Ok(bals);
+    }
+    let s = net::connect(&cfg.host)?;
+    for chunk in keys.chunks(data::MAX_BALANCES) {
+        let msgs = w.check_balances(0, chunk, 1)?;
+        let rmsgs = request(&s, &msgs)?;
+        let keys = rmsgs[1..].iter().zip(chunk).all(|(m, k)| {
+            m.pld.kind == data::Kind::BalanceKey && m.pld.get_bal().key == *k
+        });
+        if rmsgs.len() != msgs.len() || rmsgs[0].pld.kind != data::Kind::GetBalances || !keys {
+            return Err(Error::InvalidGroup);
+        }
+        bals.extend(rmsgs[1..].iter().map(|m| m.pld.get_bal().amount));
+    }
+    Ok(bals)
+}
+
 pub fn rund(args: Vec<String>) {
     let nopass = None::<::std::io::Empty>;
     run(args, nopass);
@@ -132,17 +353,24 @@ where
     opts.optflag("x", "", "transfer");
     opts.optflag("b", "", "check the balance of destination address");
     opts.optflag("l", "list", "list your addresses and balances");
+    opts.optflag("y", "", "show the transfer history of destination address");
+    opts.optflag("d", "", "ask a testnet faucet for tokens for destination address");
+    opts.optflag("q", "", "show the rate limits of destination address");
+    opts.optflag("u", "", "have the node audit its supply, source address pays the fee");
+    opts.optflag("s", "", "show the state hash of the node, source address pays the fee");
     opts.optflag("h", "help", "print this help menu");
     opts.optopt(
         "H",
         "",
-        "loom node address to use instead of loom.looprotocol.com:12345",
+        "loom node address to use instead of loom.looprotocol.com:12345, IPv6 addresses \
+         go in brackets like [::1]:12345",
         "HOST:PORT",
     );
     opts.optopt("W", "", "loom wallet instead of loom.wallet", "PATH");
     opts.optopt("t", "", "destination address", "ADDRESS");
     opts.optopt("f", "", "source address", "ADDRESS");
     opts.optopt("a", "", "amount", "AMOUNT");
+    opts.optopt("p", "", "skip the START newest transfers of the history", "START");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => panic!(f.to_string()),
@@ -172,6 +400,30 @@ where
         let to = matches.opt_str("t").expect("missing target address");
         balance(&cfg, reader, from, to).expect("transfer");
         return;
+    } else if matches.opt_present("d") {
+        let to = matches.opt_str("t").expect("missing destination address");
+        let a = matches
+            .opt_str("a")
+            .map(|a| a.parse().expect("ammount is not a number"))
+            .unwrap_or(0);
+        airdrop(&cfg, to, a).expect("airdrop");
+    } else if matches.opt_present("y") {
+        let from = matches.opt_str("f").expect("missing source key address");
+        let to = matches.opt_str("t").expect("missing target address");
+        let start = matches
+            .opt_str("p")
+            .map(|p| p.parse().expect("start is not a number"))
+            .unwrap_or(0);
+        history(&cfg, reader, from, to, start).expect("history");
+    } else if matches.opt_present("q") {
+        let to = matches.opt_str("t").expect("missing target address");
+        stats(&cfg, to).expect("stats");
+    } else if matches.opt_present("u") {
+        let from = matches.opt_str("f").expect("missing source key address");
+        audit(&cfg, reader, from).expect("audit");
+    } else if matches.opt_present("s") {
+        let from = matches.opt_str("f").expect("missing source key address");
+        hash(&cfg, reader, from).expect("hash");
     } else if matches.opt_present("l") {
         list(&cfg, reader);
     }
@@ -181,9 +433,14 @@ where
 mod tests {
     use client;
     use daemon;
+    use data;
+    use net;
+    use wallet::{to32b, Wallet};
     use std::io::Cursor;
     use data_encoding::BASE32HEX;
     use std::fs::remove_file;
+    use std::thread::sleep;
+    use std::time::Duration;
 
     #[test]
     fn help_test() {
@@ -228,7 +485,7 @@ mod tests {
         ];
         let mut t = daemon::run(args).expect("daemon load");
 
-        let addr: String = "UFC5KNCKS6KMC7VDIBVJ4R3IIJ0RLQL8VSVOAO4GQSMAV1QIPFP0====".into();
+        let addr: String = "GKH79L674J8L8E8MRM1FKUHQ2EPC3LSTSUCVLPQJS4ADINVHQNIG====".into();
         let args = vec![
             "loom".into(),
             "-W".into(),
@@ -245,6 +502,269 @@ mod tests {
         t.shutdown().expect("success");
     }
 
+    #[test]
+    fn balances_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "14347".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let cfg = client::Cfg {
+            host: "127.0.0.1:14347".into(),
+            wallet: "testdata/loom.wallet".into(),
+        };
+        let w = client::load_wallet(&cfg, "foobar".into());
+        let mut keys = vec![to32b(w.pubkeys[0])];
+        keys.extend((1..data::MAX_BALANCES + 5).map(|i| [i as u8; 32]));
+        let bals = client::balances(&cfg, &w, &keys).expect("balances");
+        assert_eq!(bals.len(), keys.len());
+        assert_eq!(bals[0], 1000000000 - 1);
+        assert!(bals[1..].iter().all(|b| *b == 0));
+        t.shutdown().expect("success");
+    }
+
+    #[test]
+    fn history_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "14348".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let cfg = client::Cfg {
+            host: "127.0.0.1:14348".into(),
+            wallet: "testdata/loom.wallet".into(),
+        };
+        let w = client::load_wallet(&cfg, "foobar".into());
+        let from = to32b(w.pubkeys[0]);
+        let s = net::socket().expect("socket");
+        s.connect("127.0.0.1:14348").expect("connect");
+        for i in 1..4 {
+            let mut num = 0;
+            let msg = w.tx(0, [i as u8; 32], i, 1);
+            while num < 1 {
+                net::write(&s, &[msg], &mut num).expect("write");
+            }
+        }
+        //the balances come back after the transfers are executed
+        let bals = client::balances(&cfg, &w, &[[3u8; 32]]).expect("balances");
+        assert_eq!(bals, [3]);
+        let h = client::get_history(&cfg, &w, 0, from, 0).expect("history");
+        assert_eq!(h.len(), 3);
+        assert_eq!(h[0].0, [3u8; 32]);
+        assert_eq!(h[0].1.amount, 3);
+        assert_eq!(h[0].1.incoming, 0);
+        let h = client::get_history(&cfg, &w, 0, [1u8; 32], 0).expect("history");
+        assert_eq!(h.len(), 1);
+        assert_eq!(h[0].0, from);
+        assert_eq!(h[0].1.incoming, 1);
+        assert_eq!(client::get_history(&cfg, &w, 0, from, 3).expect("history").len(), 0);
+        t.shutdown().expect("success");
+    }
+
+    #[test]
+    fn ipv6_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "[::1]:14350".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let cfg = client::Cfg {
+            host: "[::1]:14350".into(),
+            wallet: "testdata/loom.wallet".into(),
+        };
+        let w = client::load_wallet(&cfg, "foobar".into());
+        let bals = client::balances(&cfg, &w, &[to32b(w.pubkeys[0])]).expect("balances");
+        assert_eq!(bals, [1000000000 - 1]);
+        t.shutdown().expect("success");
+    }
+
+    #[test]
+    fn stats_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "14349".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+            "-r".into(),
+            "5".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let cfg = client::Cfg {
+            host: "127.0.0.1:14349".into(),
+            wallet: "testdata/loom.wallet".into(),
+        };
+        let st = client::get_stats(&cfg, [8u8; 32]).expect("stats");
+        assert_eq!((st.key_tokens, st.addr_tokens, st.dropped), (5, 4, 0));
+        let s = net::socket().expect("socket");
+        s.connect("127.0.0.1:14349").expect("connect");
+        //only the messages that the key signed count against it
+        let mut w = Wallet::new();
+        w.add_keypair(Wallet::new_keypair());
+        let msgs: Vec<data::Message> = (0..10).map(|i| w.tx(0, [9u8; 32], i + 1, 0)).collect();
+        let mut num = 0;
+        while num < msgs.len() {
+            net::write(&s, &msgs, &mut num).expect("write");
+        }
+        sleep(Duration::new(0, 100_000_000));
+        let st = client::get_stats(&cfg, to32b(w.pubkeys[0])).expect("stats");
+        assert!(st.dropped >= 4, "{:?}", st.dropped);
+        assert!(st.key_tokens < 2);
+        t.shutdown().expect("success");
+    }
+
+    #[test]
+    fn audit_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "14354".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let cfg = client::Cfg {
+            host: "127.0.0.1:14354".into(),
+            wallet: "testdata/loom.wallet".into(),
+        };
+        let w = client::load_wallet(&cfg, "foobar".into());
+        let a = client::get_audit(&cfg, &w, 0).expect("audit");
+        assert_eq!((a.ok, a.failures, a.fees), (1, 0, 0));
+        let a = client::get_audit(&cfg, &w, 0).expect("audit");
+        assert_eq!(a.fees, 1);
+        assert!(a.supply > 0);
+        let from: String = "GKH79L674J8L8E8MRM1FKUHQ2EPC3LSTSUCVLPQJS4ADINVHQNIG====".into();
+        let args = vec![
+            "loom".into(),
+            "-W".into(),
+            "testdata/loom.wallet".into(),
+            "-H".into(),
+            "127.0.0.1:14354".into(),
+            "-u".into(),
+            "-f".into(),
+            from,
+        ];
+        client::run(args, pass());
+        t.shutdown().expect("success");
+    }
+
+    #[test]
+    fn hash_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "14355".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let cfg = client::Cfg {
+            host: "127.0.0.1:14355".into(),
+            wallet: "testdata/loom.wallet".into(),
+        };
+        let w = client::load_wallet(&cfg, "foobar".into());
+        let a = client::get_hash(&cfg, &w, 0).expect("hash");
+        let b = client::get_hash(&cfg, &w, 0).expect("hash");
+        //each query is answered by a later batch, which changed the hash
+        assert!(b.height > a.height);
+        assert!(a.hash != b.hash);
+        let from: String = "GKH79L674J8L8E8MRM1FKUHQ2EPC3LSTSUCVLPQJS4ADINVHQNIG====".into();
+        let args = vec![
+            "loom".into(),
+            "-W".into(),
+            "testdata/loom.wallet".into(),
+            "-H".into(),
+            "127.0.0.1:14355".into(),
+            "-s".into(),
+            "-f".into(),
+            from,
+        ];
+        client::run(args, pass());
+        t.shutdown().expect("success");
+    }
+
+    #[test]
+    fn request_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "14351".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let cfg = client::Cfg {
+            host: "127.0.0.1:14351".into(),
+            wallet: "testdata/loom.wallet".into(),
+        };
+        let w = client::load_wallet(&cfg, "foobar".into());
+        let s = net::connect(&cfg.host).expect("connect");
+        let msg = w.tx(0, [7u8; 32], 5, 1);
+        let r = client::request(&s, &[msg]).expect("request");
+        assert_eq!(r[0].pld.state, data::State::Deposited);
+        //a retransmission gets the same answer and isn't executed again
+        let r = client::request(&s, &[msg]).expect("request");
+        assert_eq!(r[0].pld.state, data::State::Deposited);
+        let msg = w.tx_nonce(0, [7u8; 32], 5, 1, 1);
+        assert!(client::request(&s, &[msg]).is_ok());
+        let bals = client::balances(&cfg, &w, &[[7u8; 32]]).expect("balances");
+        assert_eq!(bals, [10]);
+        let msg = w.tx(0, [7u8; 32], u64::MAX / 2, 1);
+        let r = client::request(&s, &[msg]).expect("request");
+        assert_eq!(r[0].pld.state, data::State::Unknown);
+        t.shutdown().expect("success");
+    }
+
+    #[test]
+    fn forward_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "14352".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+        ];
+        let mut leader = daemon::run(args).expect("leader");
+        //the follower has no accounts of its own
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "14353".into(),
+            "-L".into(),
+            "127.0.0.1:14352".into(),
+        ];
+        let mut follower = daemon::run(args).expect("follower");
+        let cfg = client::Cfg {
+            host: "127.0.0.1:14353".into(),
+            wallet: "testdata/loom.wallet".into(),
+        };
+        let w = client::load_wallet(&cfg, "foobar".into());
+        let s = net::connect(&cfg.host).expect("connect");
+        let msg = w.tx(0, [7u8; 32], 5, 1);
+        let r = client::request(&s, &[msg]).expect("request");
+        assert_eq!(r[0].pld.state, data::State::Deposited);
+        //the queries are answered by the leader too
+        let bals = client::balances(&cfg, &w, &[[7u8; 32]]).expect("balances");
+        assert_eq!(bals, [5]);
+        let cfg = client::Cfg {
+            host: "127.0.0.1:14352".into(),
+            wallet: "testdata/loom.wallet".into(),
+        };
+        let bals = client::balances(&cfg, &w, &[[7u8; 32]]).expect("balances");
+        assert_eq!(bals, [5]);
+        follower.shutdown().expect("follower");
+        leader.shutdown().expect("leader");
+    }
+
     #[test]
     fn tx_test() {
         let args = vec![
@@ -256,7 +776,7 @@ mod tests {
         ];
         let mut t = daemon::run(args).expect("daemon load");
 
-        let from: String = "UFC5KNCKS6KMC7VDIBVJ4R3IIJ0RLQL8VSVOAO4GQSMAV1QIPFP0====".into();
+        let from: String = "GKH79L674J8L8E8MRM1FKUHQ2EPC3LSTSUCVLPQJS4ADINVHQNIG====".into();
         assert!(BASE32HEX.decode(from.as_bytes()).is_ok());
         let to: String = "SFC5KNCKS6KMC7VDIBVJ4R3IIJ0RLQL8VSVOAO4GQSMAV1QIPFP0====".into();
         assert!(BASE32HEX.decode(to.as_bytes()).is_ok());
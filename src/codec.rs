@@ -0,0 +1,499 @@
+//! the wire format, every packet is a `VERSION` byte followed by whole
+//! messages of `MESSAGE_SIZE` bytes. a `GetBalances` head is followed by the
+//! number of its `BalanceKey` parts as a `u16` and by the key and the amount
+//! of each, `BALANCE_SIZE` bytes, the rest of a part is always zero.
+//!
+//! a packet from loomd can hold the answers to several requests of the same
+//! client, each a whole group, see `sender::Sender`. a client reads every
+//! group of a packet, not only the first.
+//!
+//! the fields are written one by one in little endian and in the order they
+//! are declared in `data::Payload` and `data::Message`. the `data` union is
+//! written as the struct that belongs to the `kind` and padded with zeros to
+//! `DATA_SIZE`, kinds without data are all zeros. `lvh_count` carries the nonce
+//! of a `Transaction`, see `wallet::tx_nonce`. the signed bytes of a group
+//! are the encoded payloads, see `wallet::group_bytes`, so they are the same
+//! on every platform.
+
+use data::{self, Kind, Message, Payload, MAX_PACKET};
+use result::{Error, Result};
+
+/// version of the wire format, the first byte of a packet
+pub const VERSION: u8 = 3;
+pub const DATA_SIZE: usize = 40;
+pub const PAYLOAD_SIZE: usize = 32 + 32 + 8 + 8 + DATA_SIZE + 4 + 1 + 1 + 2;
+pub const MESSAGE_SIZE: usize = PAYLOAD_SIZE + 64;
+/// bytes of a `BalanceKey` part after its `GetBalances` head
+pub const BALANCE_SIZE: usize = 32 + 8;
+
+struct Out<'a> {
+    buf: &'a mut [u8],
+    at: usize,
+}
+
+impl<'a> Out<'a> {
+    fn bytes(&mut self, b: &[u8]) {
+        self.buf[self.at..self.at + b.len()].copy_from_slice(b);
+        self.at += b.len();
+    }
+    fn u16(&mut self, v: u16) {
+        self.bytes(&v.to_le_bytes());
+    }
+    fn u32(&mut self, v: u32) {
+        self.bytes(&v.to_le_bytes());
+    }
+    fn u64(&mut self, v: u64) {
+        self.bytes(&v.to_le_bytes());
+    }
+    /// zeros up to `end`
+    fn pad(&mut self, end: usize) {
+        for b in &mut self.buf[self.at..end] {
+            *b = 0;
+        }
+        self.at = end;
+    }
+}
+
+struct In<'a> {
+    buf: &'a [u8],
+    at: usize,
+}
+
+impl<'a> In<'a> {
+    fn bytes(&mut self, num: usize) -> &'a [u8] {
+        let b = &self.buf[self.at..self.at + num];
+        self.at += num;
+        b
+    }
+    fn b32(&mut self) -> [u8; 32] {
+        let mut k = [0u8; 32];
+        k.copy_from_slice(self.bytes(32));
+        k
+    }
+    fn u8(&mut self) -> u8 {
+        self.bytes(1)[0]
+    }
+    fn u16(&mut self) -> u16 {
+        let mut b = [0u8; 2];
+        b.copy_from_slice(self.bytes(2));
+        u16::from_le_bytes(b)
+    }
+    fn u32(&mut self) -> u32 {
+        let mut b = [0u8; 4];
+        b.copy_from_slice(self.bytes(4));
+        u32::from_le_bytes(b)
+    }
+    fn u64(&mut self) -> u64 {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(self.bytes(8));
+        u64::from_le_bytes(b)
+    }
+}
+
+fn encode_data(o: &mut Out, p: &Payload) {
+    let end = o.at + DATA_SIZE;
+    //the union is read as the struct that `kind` says it holds
+    unsafe {
+        match p.kind {
+            k if k.is_transfer() => {
+                o.bytes(&p.data.tx.to);
+                o.u64(p.data.tx.amount);
+            }
+            Kind::GetBalance | Kind::BalanceKey => {
+                o.bytes(&p.data.bal.key);
+                o.u64(p.data.bal.amount);
+            }
+            Kind::MultiSig => {
+                o.bytes(&p.data.msig.key);
+                o.u32(p.data.msig.threshold);
+                o.u32(p.data.msig.num);
+            }
+            Kind::Condition => {
+                o.u64(p.data.cond.height);
+                o.u64(p.data.cond.time);
+            }
+            Kind::Claim | Kind::Cancel => {
+                o.bytes(&p.data.redeem.id);
+            }
+            Kind::GetHistory => {
+                o.bytes(&p.data.hist.key);
+                o.u32(p.data.hist.start);
+                o.u32(p.data.hist.num);
+            }
+            Kind::Record => {
+                o.u64(p.data.rec.height);
+                o.u64(p.data.rec.amount);
+                o.u32(p.data.rec.index);
+                o.u32(p.data.rec.incoming);
+            }
+            Kind::GetStats => {
+                o.bytes(&p.data.stats.key);
+                o.u16(p.data.stats.key_tokens);
+                o.u16(p.data.stats.addr_tokens);
+                o.u32(p.data.stats.dropped);
+            }
+            Kind::GetAudit => {
+                o.u64(p.data.audit.supply);
+                o.u64(p.data.audit.fees);
+                o.u64(p.data.audit.failures);
+                o.u32(p.data.audit.ok);
+                o.u32(p.data.audit.unused);
+            }
+            Kind::GetHash => {
+                o.bytes(&p.data.hash.hash);
+                o.u64(p.data.hash.height);
+            }
+            _ => (),
+        }
+    }
+    o.pad(end);
+}
+
+fn decode_data(i: &mut In, kind: Kind) -> data::MessageData {
+    let end = i.at + DATA_SIZE;
+    let d = match kind {
+        k if k.is_transfer() => data::MessageData {
+            tx: data::Transaction {
+                to: i.b32(),
+                amount: i.u64(),
+            },
+        },
+        Kind::GetBalance | Kind::BalanceKey => data::MessageData {
+            bal: data::GetBalance {
+                key: i.b32(),
+                amount: i.u64(),
+            },
+        },
+        Kind::MultiSig => data::MessageData {
+            msig: data::MultiSig {
+                key: i.b32(),
+                threshold: i.u32(),
+                num: i.u32(),
+            },
+        },
+        Kind::Condition => data::MessageData {
+            cond: data::Condition {
+                height: i.u64(),
+                time: i.u64(),
+            },
+        },
+        Kind::Claim | Kind::Cancel => data::MessageData {
+            redeem: data::Redeem { id: i.b32() },
+        },
+        Kind::GetHistory => data::MessageData {
+            hist: data::GetHistory {
+                key: i.b32(),
+                start: i.u32(),
+                num: i.u32(),
+            },
+        },
+        Kind::Record => data::MessageData {
+            rec: data::Record {
+                height: i.u64(),
+                amount: i.u64(),
+                index: i.u32(),
+                incoming: i.u32(),
+            },
+        },
+        Kind::GetStats => data::MessageData {
+            stats: data::Stats {
+                key: i.b32(),
+                key_tokens: i.u16(),
+                addr_tokens: i.u16(),
+                dropped: i.u32(),
+            },
+        },
+        Kind::GetAudit => data::MessageData {
+            audit: data::Audit {
+                supply: i.u64(),
+                fees: i.u64(),
+                failures: i.u64(),
+                ok: i.u32(),
+                unused: i.u32(),
+            },
+        },
+        Kind::GetHash => data::MessageData {
+            hash: data::GetHash {
+                hash: i.b32(),
+                height: i.u64(),
+            },
+        },
+        _ => data::MessageData::default(),
+    };
+    i.at = end;
+    d
+}
+
+fn encode_payload_to(o: &mut Out, p: &Payload) {
+    o.bytes(&p.from);
+    o.bytes(&p.lvh);
+    o.u64(p.lvh_count);
+    o.u64(p.fee);
+    encode_data(o, p);
+    o.u32(p.version);
+    o.bytes(&[p.kind as u8, p.state as u8]);
+    o.u16(p.unused);
+}
+
+/// the `PAYLOAD_SIZE` bytes of `p` appended to `v`
+pub fn encode_payload(p: &Payload, v: &mut Vec<u8>) {
+    let at = v.len();
+    v.resize(at + PAYLOAD_SIZE, 0);
+    let mut o = Out {
+        buf: &mut v[at..],
+        at: 0,
+    };
+    encode_payload_to(&mut o, p);
+}
+
+/// write `m` to the first `MESSAGE_SIZE` bytes of `buf`
+pub fn encode(m: &Message, buf: &mut [u8]) {
+    let mut o = Out { buf, at: 0 };
+    encode_payload_to(&mut o, &m.pld);
+    o.bytes(&m.sig);
+}
+
+/// read a message from the first `MESSAGE_SIZE` bytes of `buf`
+pub fn decode(buf: &[u8]) -> Result<Message> {
+    if buf.len() < MESSAGE_SIZE {
+        return Err(Error::InvalidLength(buf.len()));
+    }
+    //the enums are checked before anything is read into the union
+    let k = buf[PAYLOAD_SIZE - 4];
+    let kind = Kind::from_u8(k).ok_or(Error::InvalidKind(k))?;
+    let s = buf[PAYLOAD_SIZE - 3];
+    let state = data::State::from_u8(s).ok_or(Error::InvalidState(s))?;
+    let mut i = In { buf, at: 0 };
+    let mut m = Message::default();
+    m.pld.from = i.b32();
+    m.pld.lvh = i.b32();
+    m.pld.lvh_count = i.u64();
+    m.pld.fee = i.u64();
+    m.pld.data = decode_data(&mut i, kind);
+    m.pld.version = i.u32();
+    i.u8();
+    i.u8();
+    m.pld.kind = kind;
+    m.pld.state = state;
+    m.pld.unused = i.u16();
+    m.sig.copy_from_slice(i.bytes(64));
+    Ok(m)
+}
+
+/// number of `BalanceKey` parts after `msgs[0]` if it is a `GetBalances`
+fn balance_keys(msgs: &[Message]) -> usize {
+    if msgs[0].pld.kind != Kind::GetBalances {
+        return 0;
+    }
+    msgs[1..]
+        .iter()
+        .take_while(|m| m.pld.kind == Kind::BalanceKey)
+        .count()
+}
+
+/// write as many of `msgs` as fit in one packet to `buf`, returns the number
+/// of bytes and the number of messages written. at least one message is
+/// written, a `GetBalances` with more than `data::MAX_BALANCES` keys is an error
+pub fn encode_packet(msgs: &[Message], buf: &mut [u8; MAX_PACKET]) -> Result<(usize, usize)> {
+    buf[0] = VERSION;
+    let mut o = Out { buf, at: 1 };
+    let mut num = 0;
+    while num < msgs.len() {
+        let m = &msgs[num];
+        if m.pld.kind != Kind::GetBalances {
+            if o.at + MESSAGE_SIZE > MAX_PACKET {
+                break;
+            }
+            encode(m, &mut o.buf[o.at..]);
+            o.at += MESSAGE_SIZE;
+            num += 1;
+            continue;
+        }
+        let keys = balance_keys(&msgs[num..]);
+        if keys > data::MAX_BALANCES {
+            return Err(Error::ToLarge);
+        }
+        let fit = (MAX_PACKET - o.at).saturating_sub(MESSAGE_SIZE + 2) / BALANCE_SIZE;
+        if o.at + MESSAGE_SIZE + 2 > MAX_PACKET || keys > fit {
+            break;
+        }
+        encode(m, &mut o.buf[o.at..]);
+        o.at += MESSAGE_SIZE;
+        o.u16(keys as u16);
+        for k in &msgs[num + 1..num + 1 + keys] {
+            let b = k.pld.get_bal();
+            o.bytes(&b.key);
+            o.u64(b.amount);
+        }
+        num += 1 + keys;
+    }
+    Ok((o.at, num))
+}
+
+/// read the messages of a packet into `msgs`, returns how many there were.
+/// the whole packet is rejected if anything in it is invalid
+pub fn decode_packet(buf: &[u8], msgs: &mut [Message]) -> Result<usize> {
+    if buf.is_empty() {
+        return Err(Error::InvalidLength(0));
+    }
+    if buf[0] != VERSION {
+        return Err(Error::UnknownVersion(buf[0]));
+    }
+    let mut i = In { buf, at: 1 };
+    let mut num = 0;
+    while i.at < buf.len() {
+        if buf.len() - i.at < MESSAGE_SIZE || num == msgs.len() {
+            return Err(Error::InvalidLength(buf.len()));
+        }
+        let m = decode(i.bytes(MESSAGE_SIZE))?;
+        msgs[num] = m;
+        num += 1;
+        if m.pld.kind != Kind::GetBalances {
+            continue;
+        }
+        if buf.len() - i.at < 2 {
+            return Err(Error::InvalidLength(buf.len()));
+        }
+        let keys = i.u16() as usize;
+        if buf.len() - i.at < keys * BALANCE_SIZE || msgs.len() - num < keys {
+            return Err(Error::InvalidLength(buf.len()));
+        }
+        for k in &mut msgs[num..num + keys] {
+            *k = Message::default();
+            k.pld.kind = Kind::BalanceKey;
+            k.pld.data = data::MessageData {
+                bal: data::GetBalance {
+                    key: i.b32(),
+                    amount: i.u64(),
+                },
+            };
+        }
+        num += keys;
+    }
+    if num == 0 {
+        return Err(Error::InvalidLength(buf.len()));
+    }
+    Ok(num)
+}
+
+#[cfg(test)]
+mod tests {
+    use codec::{self, BALANCE_SIZE, MESSAGE_SIZE, PAYLOAD_SIZE, VERSION};
+    use data::{self, Kind, MAX_BATCH, MAX_PACKET};
+    use result::Error;
+    use std::mem::size_of;
+
+    fn tx() -> data::Message {
+        let mut m = data::Message::default();
+        m.pld.kind = Kind::Transaction;
+        m.pld.from = [1u8; 32];
+        m.pld.fee = 0x0102;
+        m.pld.version = 7;
+        m.pld.data = data::MessageData {
+            tx: data::Transaction {
+                to: [2u8; 32],
+                amount: 0x0a0b0c,
+            },
+        };
+        m.sig = [3u8; 64];
+        m
+    }
+    #[test]
+    fn codec_test() {
+        assert_eq!(size_of::<data::Payload>(), PAYLOAD_SIZE);
+        assert_eq!(size_of::<data::Message>(), MESSAGE_SIZE);
+        let m = tx();
+        let mut b = [0u8; MESSAGE_SIZE];
+        codec::encode(&m, &mut b);
+        assert_eq!(b[..32], [1u8; 32]);
+        assert_eq!(b[72..80], [2, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(b[112..120], [0x0c, 0x0b, 0x0a, 0, 0, 0, 0, 0]);
+        assert_eq!(b[120..124], [7, 0, 0, 0]);
+        assert_eq!(b[124], Kind::Transaction as u8);
+        let d = codec::decode(&b).expect("decode");
+        assert_eq!(d.pld.get_tx().amount, 0x0a0b0c);
+        assert_eq!(d.pld.get_tx().to, [2u8; 32]);
+        assert_eq!(d.pld.fee, 0x0102);
+        assert_eq!(d.sig[..], m.sig[..]);
+
+        b[124] = 0xff;
+        assert_matches!(codec::decode(&b).err(), Some(Error::InvalidKind(0xff)));
+        b[124] = Kind::Transaction as u8;
+        b[125] = 3;
+        assert_matches!(codec::decode(&b).err(), Some(Error::InvalidState(3)));
+        assert_matches!(codec::decode(&b[1..]).err(), Some(Error::InvalidLength(_)));
+    }
+    #[test]
+    fn packet_test() {
+        let msgs = vec![tx(); MAX_BATCH + 1];
+        let mut buf = [0u8; MAX_PACKET];
+        let (size, num) = codec::encode_packet(&msgs, &mut buf).expect("encode");
+        assert_eq!(num, MAX_BATCH);
+        assert_eq!(size, 1 + MAX_BATCH * MESSAGE_SIZE);
+        let mut out = vec![data::Message::default(); MAX_BATCH];
+        assert_eq!(codec::decode_packet(&buf[..size], &mut out).expect("packet"), num);
+        assert_eq!(out[MAX_BATCH - 1].pld.get_tx().amount, 0x0a0b0c);
+        assert_matches!(
+            codec::decode_packet(&buf[..size - 1], &mut out),
+            Err(Error::InvalidLength(_))
+        );
+        assert_matches!(codec::decode_packet(&buf[..1], &mut out), Err(Error::InvalidLength(_)));
+        assert_matches!(
+            codec::decode_packet(&buf[..size], &mut out[..1]),
+            Err(Error::InvalidLength(_))
+        );
+        buf[0] = VERSION + 1;
+        assert_matches!(
+            codec::decode_packet(&buf[..size], &mut out),
+            Err(Error::UnknownVersion(_))
+        );
+    }
+    #[test]
+    fn balances_packet_test() {
+        let mut msgs = vec![data::Message::default(); data::MAX_PACKET_MSGS];
+        msgs[0].pld.kind = Kind::GetBalances;
+        msgs[0].pld.fee = 3;
+        for (i, m) in msgs[1..].iter_mut().enumerate() {
+            m.pld.kind = Kind::BalanceKey;
+            m.pld.data = data::MessageData {
+                bal: data::GetBalance {
+                    key: [i as u8; 32],
+                    amount: i as u64,
+                },
+            };
+        }
+        let mut buf = [0u8; MAX_PACKET];
+        let (size, num) = codec::encode_packet(&msgs, &mut buf).expect("encode");
+        assert_eq!(num, data::MAX_PACKET_MSGS);
+        assert_eq!(size, 1 + MESSAGE_SIZE + 2 + data::MAX_BALANCES * BALANCE_SIZE);
+        let mut out = vec![data::Message::default(); data::MAX_PACKET_MSGS];
+        assert_eq!(codec::decode_packet(&buf[..size], &mut out).expect("packet"), num);
+        assert_eq!(out[0].pld.fee, 3);
+        for (i, m) in out[1..].iter().enumerate() {
+            assert_eq!(m.pld.kind, Kind::BalanceKey);
+            assert_eq!(m.pld.get_bal().key, [i as u8; 32]);
+            assert_eq!(m.pld.get_bal().amount, i as u64);
+        }
+        assert_matches!(
+            codec::decode_packet(&buf[..size - 1], &mut out),
+            Err(Error::InvalidLength(_))
+        );
+        assert_matches!(
+            codec::decode_packet(&buf[..size], &mut out[..num - 1]),
+            Err(Error::InvalidLength(_))
+        );
+
+        //a head that doesn't fit with its keys starts the next packet
+        let mut with_tx = vec![tx()];
+        with_tx.extend_from_slice(&msgs[..data::MAX_BALANCES]);
+        let (size, num) = codec::encode_packet(&with_tx, &mut buf).expect("encode");
+        assert_eq!((size, num), (1 + MESSAGE_SIZE, 1));
+        let (_, num) = codec::encode_packet(&with_tx[1..], &mut buf).expect("encode");
+        assert_eq!(num, data::MAX_BALANCES);
+
+        //more keys than a packet holds aren't cut
+        let mut over = msgs.clone();
+        over.push(msgs[1]);
+        assert_matches!(codec::encode_packet(&over, &mut buf), Err(Error::ToLarge));
+    }
+}
@@ -1,72 +1,229 @@
 use state;
+use shard;
 use data;
+use net;
 use serde_json;
+use wallet;
 
 use std::sync::{Arc, Mutex};
 use std::io::Read;
 use result::Result;
 use reader::Reader;
+use tcp::Tcp;
+use std::net::SocketAddr;
 use std::fs::File;
-use std::mem::transmute;
 use getopts::Options;
 use std::string::String;
 use otp::{Port, OTP};
+use std::cmp::max;
+use data_encoding::BASE32HEX;
 
 fn print_usage(program: &str, opts: Options) {
     let brief = format!("Usage: {} FILE [options]", program);
     print!("{}", opts.usage(&brief));
 }
 
-fn loomd(testnet: Option<String>, port: u16) -> Result<OTP> {
-    let state = match testnet {
-        Some(f) => state_from_file(&f).and_then(|x| Ok(Arc::new(Mutex::new(x))))?,
-        None => Arc::new(Mutex::new(state::State::new(1024))),
+fn loomd(
+    testnet: Option<String>,
+    mut reader: Reader,
+    mapped: Option<String>,
+    audit: bool,
+    shards: usize,
+    faucet: Option<[u8; 32]>,
+    tcp: Option<SocketAddr>,
+) -> Result<OTP> {
+    let list = match testnet {
+        Some(f) => Some(accounts_from_file(&f)?),
+        None => None,
     };
-    let reader = Reader::new(port).and_then(|x| Ok(Arc::new(x)))?;
-    let sender = reader.sender()?;
-    let mut o = OTP::new();
+    let mut states = Vec::new();
+    if shards == 0 {
+        let mut s = open_state(list, mapped, audit)?;
+        s.set_shard(0, 1)?;
+        if let Some(k) = faucet {
+            s.set_faucet(k)?;
+        }
+        states.push(s);
+    } else {
+        //`run` refuses a faucet with shards
+        for i in 0..shards {
+            let part = list.as_ref().map(|l| {
+                l.iter()
+                    .filter(|a| shard::shard_of(&a.from, shards) == i)
+                    .cloned()
+                    .collect()
+            });
+            let path = mapped.as_ref().map(|m| format!("{}.{}", m, i));
+            //the keys of a state split for another number of shards are elsewhere
+            let mut s = open_state(part, path, audit)?;
+            s.set_shard(i, shards)?;
+            states.push(s);
+        }
+    }
+    //the next batch continues where the state stopped
+    reader.set_height(states.iter().map(|s| s.height()).max().unwrap_or(0));
+    let reader = Arc::new(reader);
+    let mut sender = reader.sender()?;
+    let mut o = OTP::with_shards(shards);
+    if let Some(addr) = tcp {
+        let t = Arc::new(Tcp::new(addr, reader.clone())?);
+        sender.set_tcp(t.clone());
+        o.source(Port::Tcp, move |p| t.run(p))?;
+    }
     let a_reader = reader.clone();
-    o.source(Port::Reader, move |p| a_reader.run(p))?;
+    o.sources(Port::Reader, reader.num_sockets(), move |i, p| {
+        a_reader.run_socket(i, p)
+    })?;
+    if reader.has_leader() {
+        let c_reader = reader.clone();
+        o.source(Port::Forward, move |p| c_reader.run_leader(p))?;
+    }
     let b_reader = reader.clone();
-    o.listen(Port::Recycle, move |_p, d| {
+    o.listen(Port::Recycle, move |p, d| {
+        b_reader.acknowledge(p, &d)?;
         b_reader.recycle(d);
         Ok(())
     })?;
-    o.listen(Port::Sender, move |_p, d| sender.run(d))?;
-    let a_state = state.clone();
-    o.listen(Port::State, move |p, d| a_state.lock().unwrap().run(p, d))?;
+    o.listen_idle(Port::Sender, move |_p, d| sender.run(d))?;
+    if shards == 0 {
+        let state = Mutex::new(states.remove(0));
+        o.listen(Port::State, move |p, d| state.lock().unwrap().run(p, d))?;
+        return Ok(o);
+    }
+    for (i, s) in states.into_iter().enumerate() {
+        let shard = Mutex::new(shard::Shard::new(i, shards, s));
+        o.listen(Port::Shard(i), move |p, d| shard.lock().unwrap().run(p, d))?;
+    }
+    let router = shard::Router::new(shards);
+    o.listen(Port::State, move |p, d| router.run(p, d))?;
     return Ok(o);
 }
 
+fn open_state(
+    list: Option<Vec<data::Account>>,
+    mapped: Option<String>,
+    audit: bool,
+) -> Result<state::State> {
+    let mut s = match (list, mapped) {
+        (l, Some(m)) => {
+            let list = l.unwrap_or_default();
+            let size = max(list.len() * 2, 1024);
+            state::State::open(&m, size, &list)?
+        }
+        (Some(l), None) => state::State::from_list(&l)?,
+        (None, None) => state::State::new(1024),
+    };
+    if audit {
+        s.audit()?;
+        s.set_audit(true);
+    }
+    Ok(s)
+}
+
 #[derive(Deserialize)]
 struct TestAccount {
     pub pubkey: [u64; 4],
     pub balance: u32,
 }
 
-fn state_from_file(f: &str) -> Result<state::State> {
+fn accounts_from_file(f: &str) -> Result<Vec<data::Account>> {
     let mut file = File::open(f)?;
     let mut e = Vec::new();
     let _sz = file.read_to_end(&mut e)?;
     let v: Vec<TestAccount> = serde_json::from_slice(&e)?;
     let acc: Vec<data::Account> = v.iter()
         .map(|a| {
-            let pk = unsafe { transmute::<[u64; 4], [u8; 32]>(a.pubkey) };
+            let pk = wallet::to32b(a.pubkey);
             data::Account {
                 from: pk,
                 balance: a.balance as u64,
             }
         })
         .collect();
-    state::State::from_list(&acc)
+    Ok(acc)
 }
 
 pub fn run(args: Vec<String>) -> Option<OTP> {
     let program = args[0].clone();
     let mut opts = Options::new();
     opts.optflag("h", "help", "print this help menu");
-    opts.optopt("l", "", "Run as a Loom with a listen port", "PORT");
+    opts.optopt(
+        "l",
+        "",
+        "Run as a Loom with a listen port on every IPv6 and IPv4 address, or on \
+         ADDRESS:PORT such as [::1]:12345",
+        "PORT",
+    );
     opts.optopt("t", "", "testnet accounts", "FILE");
+    opts.optopt(
+        "m",
+        "",
+        "keep the state in memory-mapped files at FILE and FILE.*, created from the testnet accounts if missing, \
+         every batch is synced to disk as it ends",
+        "FILE",
+    );
+    opts.optflag(
+        "a",
+        "audit",
+        "check that the supply is conserved after every batch and log a mismatch, always on in \
+         debug builds",
+    );
+    opts.optopt(
+        "s",
+        "",
+        "split the accounts into NUM shards with a thread each, for transfers only: single \
+         transfers and balance queries are executed, other requests are answered unexecuted \
+         and -F can't be used. a state opened with -m keeps shard i in FILE.i and can't be \
+         opened with another NUM",
+        "NUM",
+    );
+    opts.optopt(
+        "F",
+        "",
+        "answer airdrop requests with test tokens from the account at ADDRESS, the \
+         faucet is kept with a state opened with -m and can't be changed",
+        "ADDRESS",
+    );
+    opts.optopt(
+        "n",
+        "",
+        "read the listen port with NUM sockets and a thread each",
+        "NUM",
+    );
+    opts.optopt(
+        "f",
+        "",
+        "drop messages that pay less than FEE, the highest fees are executed first under load, \
+         a fee that nobody signed counts as none",
+        "FEE",
+    );
+    opts.optopt(
+        "r",
+        "",
+        "drop messages from an address, or signed by a key, that sends more than RATE a second",
+        "RATE",
+    );
+    opts.optopt(
+        "R",
+        "",
+        "send the replies from PORT or ADDRESS:PORT instead of the listen port, clients \
+         that connect their sockets don't get them",
+        "PORT",
+    );
+    opts.optopt(
+        "L",
+        "",
+        "follow the leader at HOST:PORT, requests are forwarded to it instead of \
+         executed",
+        "HOST:PORT",
+    );
+    opts.optopt(
+        "T",
+        "",
+        "also accept length-prefixed messages over TCP on PORT or ADDRESS:PORT, the replies \
+         are written to the connection",
+        "PORT",
+    );
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -76,9 +233,63 @@ pub fn run(args: Vec<String>) -> Option<OTP> {
         }
     };
     if matches.opt_str("l").is_some() {
+        //checked before any socket is bound
+        let faucet = match matches.opt_str("F").map(|a| BASE32HEX.decode(a.as_bytes())) {
+            Some(Ok(ref k)) if k.len() == 32 => {
+                let mut key = [0u8; 32];
+                key.copy_from_slice(k);
+                Some(key)
+            }
+            Some(_) => {
+                println!("expecting the base32hex address of an account for the faucet");
+                print_usage(&program, opts);
+                return None;
+            }
+            None => None,
+        };
+        let shards = matches
+            .opt_str("s")
+            .map(|s| s.parse().expect("expecting a number of shards"))
+            .unwrap_or(0);
+        if shards > 0 && faucet.is_some() {
+            println!("the faucet isn't available with shards");
+            print_usage(&program, opts);
+            return None;
+        }
         let ports = matches.opt_str("l").expect("missing loom port");
-        let port = ports.parse().expect("expecting u16 number for port");
-        let daemon = loomd(matches.opt_str("t"), port).expect("loomd");
+        let addr = net::listen_addr(&ports).expect("expecting a port or an address and a port");
+        let readers = matches
+            .opt_str("n")
+            .map(|n| n.parse().expect("expecting a number of readers"))
+            .unwrap_or(1);
+        let mut reader = Reader::with_sockets(addr, max(readers, 1)).expect("reader");
+        if let Some(f) = matches.opt_str("f") {
+            reader.set_min_fee(f.parse().expect("expecting a number for the fee"));
+        }
+        if let Some(r) = matches.opt_str("r") {
+            match r.parse().expect("expecting a number for the rate") {
+                0 => (),
+                r => reader.set_rate(r),
+            }
+        }
+        if let Some(r) = matches.opt_str("R") {
+            let addr = net::listen_addr(&r).expect("expecting a port or an address and a port");
+            reader.set_reply(addr).expect("reply socket");
+        }
+        if let Some(l) = matches.opt_str("L") {
+            reader.set_leader(&l).expect("leader");
+        }
+        let daemon = loomd(
+            matches.opt_str("t"),
+            reader,
+            matches.opt_str("m"),
+            matches.opt_present("a"),
+            shards,
+            faucet,
+            matches.opt_str("T").map(|t| {
+                net::listen_addr(&t).expect("expecting a port or an address and a port")
+            }),
+        ).expect("loomd");
         return Some(daemon);
     } else {
         print_usage(&program, opts);
@@ -94,11 +305,22 @@ mod tests {
     use wallet;
     use result::Result;
     use std::net::UdpSocket;
-    use std::mem::transmute;
+    use std::time::Duration;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::process;
 
     fn check_balance(s: &UdpSocket, w: &wallet::Wallet, to: [u8; 32]) -> Result<u64> {
+        check_balance_at(s, w, to, "127.0.0.1:24569")
+    }
+    fn check_balance_at(
+        s: &UdpSocket,
+        w: &wallet::Wallet,
+        to: [u8; 32],
+        host: &str,
+    ) -> Result<u64> {
         let mut num = 0;
-        let addr = "127.0.0.1:24569".parse().expect("parse");
+        let addr = host.parse().expect("parse");
         while num < 1 {
             let msg = w.check_balance(0, to, 1);
             net::send_to(&s, &[msg], &mut num, addr)?;
@@ -111,8 +333,20 @@ mod tests {
         assert_eq!(rmsgs.data[0].0, 1);
         Ok(rmsgs.msgs[0].pld.get_bal().amount)
     }
+    /// send `msg` and wait for the acknowledgement of the transfer
+    fn transfer_at(s: &UdpSocket, msg: data::Message, host: &str) -> Result<data::State> {
+        let mut num = 0;
+        let addr = host.parse().expect("parse");
+        while num < 1 {
+            net::send_to(&s, &[msg], &mut num, addr)?;
+        }
+        let mut rmsgs = data::Messages::new();
+        rmsgs.with_mut(|m, d| net::read_from(&s, m, d))?;
+        assert_eq!(rmsgs.msgs[0].sig[..], msg.sig[..]);
+        Ok(rmsgs.msgs[0].pld.state)
+    }
     fn from_pk(d: [u64; 4]) -> [u8; 32] {
-        unsafe { transmute::<[u64; 4], [u8; 32]>(d) }
+        wallet::to32b(d)
     }
     #[test]
     fn help_test() {
@@ -135,12 +369,9 @@ mod tests {
         let kp = wallet::Wallet::new_keypair();
         let to = from_pk(kp.1);
         let s = net::socket().expect("socket");
-        let addr = "127.0.0.1:24569".parse().expect("parse");
-        let mut num = 0;
-        while num < 1 {
-            let msg = w.tx(0, to, 1000, 1);
-            net::send_to(&s, &[msg], &mut num, addr).expect("write message");
-        }
+        let msg = w.tx(0, to, 1000, 1);
+        let st = transfer_at(&s, msg, "127.0.0.1:24569").expect("transfer");
+        assert_eq!(st, data::State::Deposited);
         let bto = check_balance(&s, &w, to).expect("check bal to");
         assert_eq!(bto, 1000);
         let bfrom = check_balance(&s, &w, from).expect("check bal from");
@@ -148,8 +379,172 @@ mod tests {
         t.shutdown().expect("success");
     }
     #[test]
+    fn mapped_test() {
+        let dir = temp_dir().join(format!("TESTLOOMD-{}", process::id()));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).expect("create dir");
+        let path = dir.join("accounts").to_str().unwrap().to_string();
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "24570".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+            "-m".into(),
+            path.clone(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let ew = wallet::EncryptedWallet::from_file("testdata/loom.wallet").expect("test wallet");
+        let w = ew.decrypt("foobar".as_bytes()).expect("decrypt");
+        let to = from_pk(wallet::Wallet::new_keypair().1);
+        let s = net::socket().expect("socket");
+        let msg = w.tx(0, to, 1000, 1);
+        let st = transfer_at(&s, msg, "127.0.0.1:24570").expect("transfer");
+        assert_eq!(st, data::State::Deposited);
+        let bto = check_balance_at(&s, &w, to, "127.0.0.1:24570").expect("check bal to");
+        assert_eq!(bto, 1000);
+        t.shutdown().expect("success");
+
+        //restart without the testnet accounts, the table is still on disk
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "24571".into(),
+            "-m".into(),
+            path.clone(),
+        ];
+        let mut t = daemon::run(args).expect("daemon reload");
+        let bto = check_balance_at(&s, &w, to, "127.0.0.1:24571").expect("check bal to");
+        assert_eq!(bto, 1000);
+        t.shutdown().expect("success");
+        remove_dir_all(&dir).expect("remove");
+    }
+    #[test]
+    fn sharded_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "24572".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+            "-s".into(),
+            "4".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let ew = wallet::EncryptedWallet::from_file("testdata/loom.wallet").expect("test wallet");
+        let w = ew.decrypt("foobar".as_bytes()).expect("decrypt");
+        let from = from_pk(w.pubkeys[0]);
+        let to = from_pk(wallet::Wallet::new_keypair().1);
+        let s = net::socket().expect("socket");
+        let msg = w.tx(0, to, 1000, 1);
+        let st = transfer_at(&s, msg, "127.0.0.1:24572").expect("transfer");
+        assert_eq!(st, data::State::Deposited);
+        let bto = check_balance_at(&s, &w, to, "127.0.0.1:24572").expect("check bal to");
+        assert_eq!(bto, 1000);
+        let bfrom = check_balance_at(&s, &w, from, "127.0.0.1:24572").expect("check bal from");
+        assert_eq!(bfrom, 1000000000 - 1003);
+        t.shutdown().expect("success");
+    }
+    #[test]
+    fn reply_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "24574".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let ew = wallet::EncryptedWallet::from_file("testdata/loom.wallet").expect("test wallet");
+        let w = ew.decrypt("foobar".as_bytes()).expect("decrypt");
+        let from = from_pk(w.pubkeys[0]);
+        //the reply comes from the address the client connected to, not the
+        //one the kernel would pick
+        let s = net::connect("127.0.0.2:24574").expect("connect");
+        s.set_read_timeout(Some(Duration::new(5, 0))).expect("timeout");
+        let mut num = 0;
+        net::write(&s, &[w.check_balance(0, from, 1)], &mut num).expect("write");
+        let mut rmsgs = data::Messages::new();
+        rmsgs
+            .with_mut(|m, d| net::read_from(&s, m, d))
+            .expect("read rmsgs");
+        assert_eq!(rmsgs.msgs[0].pld.get_bal().amount, 1000000000 - 1);
+        t.shutdown().expect("success");
+
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "24575".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+            "-R".into(),
+            "24576".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let s = net::socket().expect("socket");
+        let addr = "127.0.0.1:24575".parse().expect("parse");
+        let mut num = 0;
+        net::send_to(&s, &[w.check_balance(0, from, 1)], &mut num, addr).expect("write");
+        let mut buf = [0u8; data::MAX_PACKET];
+        let (_, src) = s.recv_from(&mut buf).expect("recv");
+        assert_eq!(src.port(), 24576);
+        t.shutdown().expect("success");
+    }
+    #[test]
+    fn faucet_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "24573".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+            "-F".into(),
+            "GKH79L674J8L8E8MRM1FKUHQ2EPC3LSTSUCVLPQJS4ADINVHQNIG====".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let ew = wallet::EncryptedWallet::from_file("testdata/loom.wallet").expect("test wallet");
+        let w = ew.decrypt("foobar".as_bytes()).expect("decrypt");
+        let to = from_pk(wallet::Wallet::new_keypair().1);
+        let s = net::socket().expect("socket");
+        let addr = "127.0.0.1:24573".parse().expect("parse");
+        let mut num = 0;
+        while num < 1 {
+            let msg = wallet::Wallet::airdrop(to, 100);
+            net::send_to(&s, &[msg], &mut num, addr).expect("write message");
+        }
+        let bto = check_balance_at(&s, &w, to, "127.0.0.1:24573").expect("check bal to");
+        assert_eq!(bto, 100);
+        t.shutdown().expect("success");
+    }
+    #[test]
+    fn faucet_address_test() {
+        //a bad address is rejected before the listen port is bound
+        let _busy = net::bindall(24577).expect("bind");
+        for bad in ["not base32", "UFC5KNCK"].iter() {
+            let args = vec![
+                "loomd".into(),
+                "-l".into(),
+                "24577".into(),
+                "-F".into(),
+                bad.to_string(),
+            ];
+            assert!(daemon::run(args).is_none());
+        }
+        //so is a faucet with shards
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "24577".into(),
+            "-s".into(),
+            "2".into(),
+            "-F".into(),
+            "GKH79L674J8L8E8MRM1FKUHQ2EPC3LSTSUCVLPQJS4ADINVHQNIG====".into(),
+        ];
+        assert!(daemon::run(args).is_none());
+    }
+    #[test]
     fn realnet_test() {
-        let args = vec!["loomd".into(), "-l".into(), "24568".into()];
+        let args = vec!["loomd".into(), "-l".into(), "24568".into(), "-a".into()];
         let mut t = daemon::run(args).expect("daemon load");
         t.shutdown().expect("success");
     }
@@ -1,8 +1,13 @@
 //! data structures for the protocol, data types must have little endian C99 layout, no gaps, and same layout on LP64 and LLP64 and other variants.
 //!
-//! TBD a lightweight serialization format.
+//! on the wire they are written field by field, see `codec`.
+//!
+//! messages that don't fit in a single `Message` are sent as a group, the head
+//! message followed by part messages (see `Kind::is_part`) in the same packet.
+//! every signature in a group is over the payloads of the whole group.
 
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::mem::size_of;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::sync::{Arc, RwLock};
 use hasht::{HashT, Key, Val};
 use result::Result;
@@ -20,11 +25,108 @@ pub struct GetBalance {
     pub amount: u64,
 }
 
+/// max number of signers of a multisig account
+pub const MAX_SIGNERS: usize = 8;
+
+/// create a multisig account at `key`, followed by `num` `Signature` parts
+/// that list the signers. `key` must be `wallet::multisig_key` of the signers.
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct MultiSig {
+    pub key: [u8; 32],
+    pub threshold: u32,
+    pub num: u32,
+}
+
+/// part of an `Escrow`, the payment can be claimed by `to` once the PoH
+/// `height` and unix `time` are reached, zero means no condition.
+/// until then `from` can cancel it.
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct Condition {
+    pub height: u64,
+    pub time: u64,
+}
+
+/// `Claim` or `Cancel` the escrow with `id`, see `wallet::escrow_id`
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct Redeem {
+    pub id: [u8; 32],
+}
+
+/// ask for the transfers of `key`, newest first, skipping the `start` newest.
+/// answered with up to `num` `Record` parts, `num` is set to how many were found.
+/// only the newest are kept, see `history`
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct GetHistory {
+    pub key: [u8; 32],
+    pub start: u32,
+    pub num: u32,
+}
+
+/// part of the answer to a `GetHistory`, the other account is in `from`.
+/// the transfer is message `index` of the batch at `height`
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct Record {
+    pub height: u64,
+    pub amount: u64,
+    pub index: u32,
+    /// 1 if the funds went to the queried key
+    pub incoming: u32,
+}
+
+/// ask for the rate limit state of `key` and of the address the query came
+/// from, answered by the reader with the fields filled in. the token counts
+/// saturate at `u16::MAX`, which is also the answer without rate limits
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct Stats {
+    pub key: [u8; 32],
+    pub key_tokens: u16,
+    pub addr_tokens: u16,
+    /// messages dropped by the rate limits since the daemon started
+    pub dropped: u32,
+}
+
+/// ask for the state hash after the batch at `height`, the last one that was
+/// executed, see `State::hash`. `from` pays the fee
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct GetHash {
+    pub hash: [u8; 32],
+    pub height: u64,
+}
+
+/// ask the daemon to check that its balances, escrows and fees add up to its
+/// supply, answered with what it found. `from` pays the fee
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct Audit {
+    pub supply: u64,
+    pub fees: u64,
+    /// audits that failed since the daemon started
+    pub failures: u64,
+    /// 1 if the supply added up
+    pub ok: u32,
+    pub unused: u32,
+}
+
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub union MessageData {
     pub tx: Transaction,
     pub bal: GetBalance,
+    pub msig: MultiSig,
+    pub cond: Condition,
+    pub redeem: Redeem,
+    pub hist: GetHistory,
+    pub rec: Record,
+    pub stats: Stats,
+    pub audit: Audit,
+    pub hash: GetHash,
 }
 
 impl Default for MessageData {
@@ -41,6 +143,77 @@ pub enum Kind {
     Invalid,
     Transaction,
     GetBalance,
+    MultiSig,
+    /// part with a cosigner key in `from` and its signature of the group in `sig`
+    Signature,
+    /// `Transaction` that is held until its `Condition` part is met, a multisig
+    /// account adds its `Signature` parts after the `Condition`
+    Escrow,
+    Condition,
+    Claim,
+    Cancel,
+    /// `Transaction` to many recipients, the head and each `Leg` part pay one of them
+    Batch,
+    Leg,
+    /// `Transaction` traded for the one in its `Counter` part, both settle or neither
+    Swap,
+    /// the counterparty's half of a `Swap`, signed by its `from`
+    Counter,
+    /// `GetBalance` for the key of each `BalanceKey` part, answered with the
+    /// whole group in one packet
+    GetBalances,
+    BalanceKey,
+    GetHistory,
+    Record,
+    /// unsigned request for test tokens to `to`, paid by the faucet account
+    /// of the daemon which also fills in `from`
+    Airdrop,
+    /// unsigned and free, see `Stats`
+    GetStats,
+    /// see `Audit`
+    GetAudit,
+    GetHash,
+}
+
+impl Kind {
+    pub fn from_u8(b: u8) -> Option<Kind> {
+        let k = match b {
+            0 => Kind::Invalid,
+            1 => Kind::Transaction,
+            2 => Kind::GetBalance,
+            3 => Kind::MultiSig,
+            4 => Kind::Signature,
+            5 => Kind::Escrow,
+            6 => Kind::Condition,
+            7 => Kind::Claim,
+            8 => Kind::Cancel,
+            9 => Kind::Batch,
+            10 => Kind::Leg,
+            11 => Kind::Swap,
+            12 => Kind::Counter,
+            13 => Kind::GetBalances,
+            14 => Kind::BalanceKey,
+            15 => Kind::GetHistory,
+            16 => Kind::Record,
+            17 => Kind::Airdrop,
+            18 => Kind::GetStats,
+            19 => Kind::GetAudit,
+            20 => Kind::GetHash,
+            _ => return None,
+        };
+        Some(k)
+    }
+    /// parts belong to the closest preceding message that isn't a part
+    pub fn is_part(&self) -> bool {
+        *self == Kind::Signature || *self == Kind::Condition || *self == Kind::Leg
+            || *self == Kind::Counter || *self == Kind::BalanceKey || *self == Kind::Record
+    }
+    /// kinds that carry a `Transaction`
+    pub fn is_transfer(&self) -> bool {
+        *self == Kind::Transaction || *self == Kind::Escrow || *self == Kind::Batch
+            || *self == Kind::Leg || *self == Kind::Swap || *self == Kind::Counter
+            || *self == Kind::Airdrop
+    }
 }
 
 impl Default for Kind {
@@ -65,6 +238,17 @@ pub enum State {
 }
 impl Copy for State {}
 
+impl State {
+    pub fn from_u8(b: u8) -> Option<State> {
+        match b {
+            0 => Some(State::Unknown),
+            1 => Some(State::Withdrawn),
+            2 => Some(State::Deposited),
+            _ => None,
+        }
+    }
+}
+
 impl Clone for State {
     fn clone(&self) -> State {
         *self
@@ -77,13 +261,22 @@ impl Default for State {
     }
 }
 pub const MAX_PACKET: usize = 1024 * 4;
+/// `Payload::version` of the messages that are executed
+pub const VERSION: u32 = 0;
+/// max number of recipients of a `Batch`, the whole group has to fit in one packet
+pub const MAX_BATCH: usize = (MAX_PACKET - 1) / size_of::<Message>();
+/// max number of keys in a `GetBalances`, they are sent as a key and an amount
+/// after the head, see `codec`
+pub const MAX_BALANCES: usize = (MAX_PACKET - 1 - size_of::<Message>() - 2) / (32 + 8);
+/// max number of messages in a packet, a `GetBalances` with all its keys
+pub const MAX_PACKET_MSGS: usize = MAX_BALANCES + 1;
 
 #[derive(Default, Copy, Clone)]
 #[repr(C)]
 pub struct Payload {
     pub from: [u8; 32],
     pub lvh: [u8; 32],
-    pub lvh_count: u64,
+    pub lvh_count: u64, //nonce of a `Transaction`, see `wallet::tx_nonce`
     pub fee: u64,
     pub data: MessageData,
     pub version: u32,
@@ -94,21 +287,85 @@ pub struct Payload {
 
 impl Payload {
     pub fn get_tx(&self) -> &Transaction {
-        assert_eq!(self.kind, Kind::Transaction);
+        assert!(self.kind.is_transfer(), "{:?}", self.kind);
         unsafe { &self.data.tx }
     }
     pub fn get_tx_mut(&mut self) -> &mut Transaction {
-        assert_eq!(self.kind, Kind::Transaction);
+        assert!(self.kind.is_transfer(), "{:?}", self.kind);
         unsafe { &mut self.data.tx }
     }
     pub fn get_bal(&self) -> &GetBalance {
-        assert_eq!(self.kind, Kind::GetBalance);
+        assert!(self.kind == Kind::GetBalance || self.kind == Kind::BalanceKey);
         unsafe { &self.data.bal }
     }
     pub fn get_bal_mut(&mut self) -> &mut GetBalance {
-        assert_eq!(self.kind, Kind::GetBalance);
+        assert!(self.kind == Kind::GetBalance || self.kind == Kind::BalanceKey);
         unsafe { &mut self.data.bal }
     }
+    pub fn get_msig(&self) -> &MultiSig {
+        assert_eq!(self.kind, Kind::MultiSig);
+        unsafe { &self.data.msig }
+    }
+    pub fn get_msig_mut(&mut self) -> &mut MultiSig {
+        assert_eq!(self.kind, Kind::MultiSig);
+        unsafe { &mut self.data.msig }
+    }
+    pub fn get_cond(&self) -> &Condition {
+        assert_eq!(self.kind, Kind::Condition);
+        unsafe { &self.data.cond }
+    }
+    pub fn get_cond_mut(&mut self) -> &mut Condition {
+        assert_eq!(self.kind, Kind::Condition);
+        unsafe { &mut self.data.cond }
+    }
+    pub fn get_hist(&self) -> &GetHistory {
+        assert_eq!(self.kind, Kind::GetHistory);
+        unsafe { &self.data.hist }
+    }
+    pub fn get_hist_mut(&mut self) -> &mut GetHistory {
+        assert_eq!(self.kind, Kind::GetHistory);
+        unsafe { &mut self.data.hist }
+    }
+    pub fn get_rec(&self) -> &Record {
+        assert_eq!(self.kind, Kind::Record);
+        unsafe { &self.data.rec }
+    }
+    pub fn get_rec_mut(&mut self) -> &mut Record {
+        assert_eq!(self.kind, Kind::Record);
+        unsafe { &mut self.data.rec }
+    }
+    pub fn get_stats(&self) -> &Stats {
+        assert_eq!(self.kind, Kind::GetStats);
+        unsafe { &self.data.stats }
+    }
+    pub fn get_stats_mut(&mut self) -> &mut Stats {
+        assert_eq!(self.kind, Kind::GetStats);
+        unsafe { &mut self.data.stats }
+    }
+    pub fn get_audit(&self) -> &Audit {
+        assert_eq!(self.kind, Kind::GetAudit);
+        unsafe { &self.data.audit }
+    }
+    pub fn get_audit_mut(&mut self) -> &mut Audit {
+        assert_eq!(self.kind, Kind::GetAudit);
+        unsafe { &mut self.data.audit }
+    }
+    pub fn get_hash(&self) -> &GetHash {
+        assert_eq!(self.kind, Kind::GetHash);
+        unsafe { &self.data.hash }
+    }
+    pub fn get_hash_mut(&mut self) -> &mut GetHash {
+        assert_eq!(self.kind, Kind::GetHash);
+        unsafe { &mut self.data.hash }
+    }
+    pub fn get_redeem(&self) -> &Redeem {
+        assert!(self.kind == Kind::Claim || self.kind == Kind::Cancel);
+        unsafe { &self.data.redeem }
+    }
+    pub fn get_redeem_mut(&mut self) -> &mut Redeem {
+        assert!(self.kind == Kind::Claim || self.kind == Kind::Cancel);
+        unsafe { &mut self.data.redeem }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -127,6 +384,14 @@ impl Default for Message {
     }
 }
 
+/// number of messages in the group that starts at `msgs[0]`
+pub fn group_len(msgs: &[Message]) -> usize {
+    1 + msgs.iter()
+        .skip(1)
+        .take_while(|m| m.pld.kind.is_part())
+        .count()
+}
+
 #[derive(Default, Copy, Clone)]
 #[repr(C)]
 pub struct Account {
@@ -159,9 +424,56 @@ impl Val<[u8; 32]> for Account {
 }
 pub type AccountT = HashT<[u8; 32], Account>;
 
+#[derive(Default, Copy, Clone)]
+#[repr(C)]
+pub struct MultiSigAccount {
+    pub key: [u8; 32],
+    pub threshold: u32,
+    pub num: u32,
+    pub signers: [[u8; 32]; MAX_SIGNERS],
+}
+
+impl MultiSigAccount {
+    pub fn signers(&self) -> &[[u8; 32]] {
+        &self.signers[..self.num as usize]
+    }
+}
+
+impl Val<[u8; 32]> for MultiSigAccount {
+    fn key(&self) -> &[u8; 32] {
+        &self.key
+    }
+}
+pub type MultiSigT = HashT<[u8; 32], MultiSigAccount>;
+
+/// funds held by an `Escrow` message until they are claimed or cancelled
+#[derive(Default, Copy, Clone)]
+#[repr(C)]
+pub struct Escrow {
+    pub id: [u8; 32],
+    pub from: [u8; 32],
+    pub to: [u8; 32],
+    pub amount: u64,
+    pub height: u64,
+    pub time: u64,
+}
+
+impl Val<[u8; 32]> for Escrow {
+    fn key(&self) -> &[u8; 32] {
+        &self.id
+    }
+}
+pub type EscrowT = HashT<[u8; 32], Escrow>;
+
 pub struct Messages {
     pub msgs: Vec<Message>,
     pub data: Vec<(usize, SocketAddr)>,
+    /// state hash after this batch was executed, see `State::hash`
+    pub hash: [u8; 32],
+    /// position of the batch in the PoH, stamped by the reader that sent it
+    pub height: u64,
+    /// unix time of the batch, the same for every node that executes it
+    pub time: u64,
 }
 
 impl Messages {
@@ -169,12 +481,13 @@ impl Messages {
         Messages {
             msgs: vec![Message::default(); 8 * 1024],
             data: vec![Self::def_data(); 8 * 1024],
+            hash: [0u8; 32],
+            height: 0,
+            time: 0,
         }
     }
     pub fn def_data() -> (usize, SocketAddr) {
-        let ipv4 = Ipv4Addr::new(0, 0, 0, 0);
-        let addr = SocketAddr::new(IpAddr::V4(ipv4), 0);
-        (0, addr)
+        (0, SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0))
     }
     pub fn with<F, A>(&mut self, f: F) -> Result<A>
     where
@@ -199,9 +512,29 @@ mod tests {
     fn data_test() {
         let _ = data::Transaction::default().clone();
         let _ = data::GetBalance::default().clone();
+        let _ = data::MultiSig::default().clone();
+        let _ = data::MultiSigAccount::default().clone();
+        let _ = data::Condition::default().clone();
+        let _ = data::Redeem::default().clone();
+        let _ = data::Escrow::default().clone();
+        let _ = data::GetHistory::default().clone();
+        let _ = data::Record::default().clone();
+        let _ = data::Audit::default().clone();
+        let _ = data::GetHash::default().clone();
         let _ = data::MessageData::default().clone();
         let _ = data::Kind::default().clone();
         let _ = data::State::default().clone();
         let _ = data::Messages::new();
     }
+    #[test]
+    fn group_len_test() {
+        let mut msgs = [data::Message::default(); 4];
+        msgs[0].pld.kind = data::Kind::MultiSig;
+        msgs[1].pld.kind = data::Kind::Signature;
+        msgs[2].pld.kind = data::Kind::Signature;
+        msgs[3].pld.kind = data::Kind::Transaction;
+        assert_eq!(data::group_len(&msgs), 3);
+        assert_eq!(data::group_len(&msgs[1..]), 2);
+        assert_eq!(data::group_len(&msgs[3..]), 1);
+    }
 }
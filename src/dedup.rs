@@ -0,0 +1,98 @@
+//! transactions that are retransmitted by a client that lost the reply are
+//! executed once, the `Reader` answers the copies with the result of the
+//! first one. see `client::request`
+
+use std::collections::{HashMap, VecDeque};
+use data::Message;
+
+/// signatures remembered before the oldest are forgotten
+const MAX_SIGS: usize = 64 * 1024;
+
+/// what is known about the signature of a message
+pub enum Seen {
+    New,
+    /// an earlier copy is waiting to be executed
+    Pending,
+    /// an earlier copy was executed with this result
+    Done(Message),
+}
+
+#[derive(Default)]
+pub struct Dedup {
+    /// the insert sequence and the result of each signature
+    sigs: HashMap<[u8; 64], (u64, Option<Message>)>,
+    order: VecDeque<(u64, [u8; 64])>,
+    seq: u64,
+}
+
+impl Dedup {
+    pub fn new() -> Dedup {
+        Dedup::default()
+    }
+    /// what was known about the signature of `m`, which is remembered from now on
+    pub fn check(&mut self, m: &Message) -> Seen {
+        if let Some((_, r)) = self.sigs.get(&m.sig) {
+            return match *r {
+                Some(d) => Seen::Done(d),
+                None => Seen::Pending,
+            };
+        }
+        self.seq += 1;
+        self.sigs.insert(m.sig, (self.seq, None));
+        self.order.push_back((self.seq, m.sig));
+        while self.order.len() > MAX_SIGS {
+            let (seq, sig) = self.order.pop_front().expect("order");
+            if self.sigs.get(&sig).map(|e| e.0) == Some(seq) {
+                self.sigs.remove(&sig);
+            }
+        }
+        Seen::New
+    }
+    /// record the result `m` of an executed message, true the first time
+    pub fn done(&mut self, m: &Message) -> bool {
+        match self.sigs.get_mut(&m.sig) {
+            Some(e) if e.1.is_none() => {
+                e.1 = Some(*m);
+                true
+            }
+            _ => false,
+        }
+    }
+    /// forget a message that won't be executed, so a copy can be
+    pub fn forget(&mut self, m: &Message) {
+        if let Some(&(_, None)) = self.sigs.get(&m.sig) {
+            self.sigs.remove(&m.sig);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data;
+    use dedup::{Dedup, Seen};
+
+    fn seen(s: Seen) -> Option<data::State> {
+        match s {
+            Seen::New => None,
+            Seen::Pending => Some(data::State::Unknown),
+            Seen::Done(r) => Some(r.pld.state),
+        }
+    }
+
+    #[test]
+    fn dedup_test() {
+        let mut d = Dedup::new();
+        let mut m = data::Message::default();
+        m.sig[0] = 1;
+        assert_eq!(seen(d.check(&m)), None);
+        assert_eq!(seen(d.check(&m)), Some(data::State::Unknown));
+        d.forget(&m);
+        assert_eq!(seen(d.check(&m)), None);
+        m.pld.state = data::State::Deposited;
+        assert!(d.done(&m));
+        assert!(!d.done(&m));
+        //executed messages are kept
+        d.forget(&m);
+        assert_eq!(seen(d.check(&m)), Some(data::State::Deposited));
+    }
+}
@@ -0,0 +1,105 @@
+//! rate limits for the testnet faucet, see `data::Kind::Airdrop`. the faucet
+//! is part of the state and kept next to its tables when they are mapped, so
+//! the nodes that replay the same batches pay the same airdrops
+
+/// most that a single airdrop pays out
+pub const AMOUNT: u64 = 1000;
+/// seconds before the same address can get another airdrop
+pub const INTERVAL: u64 = 60;
+/// most airdrops paid in one second
+pub const PER_SEC: u64 = 10;
+/// airdrops that are remembered, older ones were paid at least `INTERVAL` ago
+const RECENT: usize = (PER_SEC * INTERVAL) as usize;
+
+#[derive(Default, Copy, Clone)]
+#[repr(C)]
+struct Paid {
+    to: [u8; 32],
+    time: u64,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct Faucet {
+    /// account that pays the airdrops, unused if there is no faucet
+    pub key: [u8; 32],
+    /// second of the current rate window and the airdrops paid in it
+    window: u64,
+    count: u64,
+    /// slot of `recent` that is overwritten next
+    next: u64,
+    /// the latest airdrops
+    recent: [Paid; RECENT],
+}
+
+impl Default for Faucet {
+    fn default() -> Faucet {
+        Faucet::new([0u8; 32])
+    }
+}
+
+impl Faucet {
+    pub fn new(key: [u8; 32]) -> Faucet {
+        Faucet {
+            key,
+            window: 0,
+            count: 0,
+            next: 0,
+            recent: [Paid::default(); RECENT],
+        }
+    }
+    /// true if `to` can get an airdrop at unix `time`
+    pub fn allow(&self, to: &[u8; 32], time: u64) -> bool {
+        if self.window == time && self.count >= PER_SEC {
+            return false;
+        }
+        !self.recent
+            .iter()
+            .any(|d| d.to == *to && time < d.time + INTERVAL)
+    }
+    /// remember an airdrop to `to` at unix `time`
+    pub fn paid(&mut self, to: &[u8; 32], time: u64) {
+        if self.window != time {
+            self.window = time;
+            self.count = 0;
+        }
+        self.count += 1;
+        //at most `PER_SEC` a second, so the one that is overwritten is too old to matter
+        self.recent[self.next as usize] = Paid { to: *to, time };
+        self.next = (self.next + 1) % RECENT as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use faucet::{Faucet, INTERVAL, PER_SEC, RECENT};
+
+    #[test]
+    fn faucet_test() {
+        let mut f = Faucet::new([1u8; 32]);
+        assert!(f.allow(&[2u8; 32], 100));
+        f.paid(&[2u8; 32], 100);
+        assert!(!f.allow(&[2u8; 32], 100 + INTERVAL - 1));
+        assert!(f.allow(&[2u8; 32], 100 + INTERVAL));
+        for i in 1..PER_SEC {
+            assert!(f.allow(&[i as u8 + 2; 32], 100));
+            f.paid(&[i as u8 + 2; 32], 100);
+        }
+        assert!(!f.allow(&[99u8; 32], 100));
+        assert!(f.allow(&[99u8; 32], 101));
+    }
+    #[test]
+    fn faucet_recent_test() {
+        let mut f = Faucet::new([1u8; 32]);
+        f.paid(&[2u8; 32], 100);
+        //the busiest the faucet can be until the first airdrop is overwritten
+        for i in 1..RECENT {
+            let mut to = [3u8; 32];
+            to[..8].copy_from_slice(&(i as u64).to_le_bytes());
+            f.paid(&to, 100 + i as u64 / PER_SEC);
+        }
+        assert!(!f.allow(&[2u8; 32], 100 + INTERVAL - 1));
+        f.paid(&[4u8; 32], 100 + INTERVAL);
+        assert!(f.allow(&[2u8; 32], 100 + INTERVAL));
+    }
+}
@@ -0,0 +1,144 @@
+//! a loomd that follows a leader forwards the requests it reads to the
+//! leader instead of executing them, except for the `GetStats` queries about
+//! its own rate limits. the leader answers the follower, which passes the
+//! answer on to the client that sent the request. the clients are found by
+//! the signature of the request, so of the unsigned ones that are in flight
+//! at once only the latest is answered, the others retransmit
+
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::time::Duration;
+use data;
+use net;
+use otp::{Data, Port, Ports, OTP};
+use reader::now;
+use result::{Error, Result};
+
+/// most clients remembered, the oldest are forgotten first
+const MAX_CLIENTS: usize = 64 * 1024;
+/// milliseconds a client waits for the answer of the leader
+const WAIT_MS: u64 = 10_000;
+
+#[derive(Default)]
+struct Clients {
+    /// the insert sequence and the client of each forwarded request
+    addrs: HashMap<[u8; 64], (u64, SocketAddr)>,
+    /// the insert sequence, the time and the signature of each, oldest first
+    order: VecDeque<(u64, u64, [u8; 64])>,
+    seq: u64,
+}
+
+impl Clients {
+    /// remember `client` for `sig` and forget the ones that waited too long at `now`
+    fn insert(&mut self, sig: [u8; 64], client: SocketAddr, now: u64) {
+        self.seq += 1;
+        self.addrs.insert(sig, (self.seq, client));
+        self.order.push_back((self.seq, now, sig));
+        while let Some(&(seq, time, sig)) = self.order.front() {
+            if time + WAIT_MS > now && self.order.len() <= MAX_CLIENTS {
+                break;
+            }
+            self.order.pop_front();
+            //a request that was forwarded again is remembered from its later time
+            if self.addrs.get(&sig).map(|e| e.0) == Some(seq) {
+                self.addrs.remove(&sig);
+            }
+        }
+    }
+    fn remove(&mut self, sig: &[u8; 64]) -> Option<SocketAddr> {
+        self.addrs.remove(sig).map(|e| e.1)
+    }
+}
+
+pub struct Forwarder {
+    /// connected to the leader
+    sock: UdpSocket,
+    clients: Mutex<Clients>,
+}
+
+impl Forwarder {
+    /// a forwarder to the leader at `host`
+    pub fn new(host: &str) -> Result<Forwarder> {
+        let sock = net::connect(host)?;
+        sock.set_read_timeout(Some(Duration::new(1, 0)))?;
+        Ok(Forwarder {
+            sock,
+            clients: Mutex::new(Clients::default()),
+        })
+    }
+    /// send `group` from `client` to the leader
+    pub fn forward(&self, group: &[data::Message], client: SocketAddr) -> Result<()> {
+        self.clients
+            .lock()
+            .unwrap()
+            .insert(group[0].sig, client, now());
+        let mut num = 0;
+        while num < group.len() {
+            net::write(&self.sock, group, &mut num)?;
+        }
+        Ok(())
+    }
+    /// pass the answers of the leader on to the clients
+    pub fn run(&self, ports: &Ports) -> Result<()> {
+        let mut v = data::Messages::new();
+        let n = match v.with_mut(|m, d| net::read_from(&self.sock, m, d)) {
+            Ok(n) => n,
+            Err(Error::IO(ref e))
+                if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+            {
+                return Ok(())
+            }
+            Err(e) => {
+                //the leader isn't up yet, the clients retransmit
+                debug!("leader read failed {:?}", e);
+                return Ok(());
+            }
+        };
+        //the leader sends its answers to the follower together
+        let mut ix = 0;
+        for &(z, _) in &v.data[..n] {
+            let end = ix + z;
+            while ix < end {
+                let num = data::group_len(&v.msgs[ix..end]);
+                let client = self.clients.lock().unwrap().remove(&v.msgs[ix].sig);
+                if let Some(a) = client {
+                    let msgs = v.msgs[ix..ix + num].to_vec();
+                    OTP::send(ports, Port::Sender, Data::SendMessages(msgs, a))?;
+                }
+                ix += num;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use forward::{Clients, MAX_CLIENTS, WAIT_MS};
+
+    #[test]
+    fn forward_clients_test() {
+        let a = "127.0.0.1:1".parse().unwrap();
+        let b = "127.0.0.1:2".parse().unwrap();
+        let mut c = Clients::default();
+        c.insert([1u8; 64], a, 0);
+        c.insert([2u8; 64], a, 1);
+        //forwarded again, so it is remembered from the later time
+        c.insert([1u8; 64], b, 2);
+        c.insert([3u8; 64], a, WAIT_MS + 1);
+        assert_eq!(c.remove(&[2u8; 64]), None);
+        assert_eq!(c.remove(&[1u8; 64]), Some(b));
+        assert_eq!(c.remove(&[1u8; 64]), None);
+        for i in 0..MAX_CLIENTS + 1 {
+            let mut sig = [4u8; 64];
+            sig[..8].copy_from_slice(&(i as u64).to_le_bytes());
+            c.insert(sig, a, WAIT_MS + 1);
+        }
+        assert_eq!(c.order.len(), MAX_CLIENTS);
+        assert_eq!(c.addrs.len(), MAX_CLIENTS);
+        assert_eq!(c.remove(&[3u8; 64]), None);
+    }
+}
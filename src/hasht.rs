@@ -1,5 +1,6 @@
 use result::{Error, Result};
 use core::marker::PhantomData;
+use std::cmp::min;
 
 pub trait Key: Eq {
     fn start(&self) -> usize;
@@ -24,7 +25,7 @@ where
         let num_elems = tbl.len();
         let st = key.start();
         for i in 0..num_elems {
-            let pos = st.wrapping_add(i) % num_elems;
+            let pos = (st % num_elems + i) % num_elems;
             let k = tbl.get(pos).unwrap().key();
             if k.unused() || k == key {
                 return Ok(pos);
@@ -43,6 +44,98 @@ where
         }
         Ok(())
     }
+    /// incremental version of `migrate`, moves the occupied slots in
+    /// `src[start..start + num]` into `dst` and returns the next start position.
+    /// keys that are already in `dst` are newer than their `src` copy and are skipped.
+    pub fn migrate_some(src: &[V], dst: &mut [V], start: usize, num: usize) -> Result<usize> {
+        let end = min(start.saturating_add(num), src.len());
+        for i in &src[start..end] {
+            if i.key().unused() {
+                continue;
+            }
+            let p = Self::find(dst, i.key())?;
+            if dst[p].key().unused() {
+                dst[p] = (*i).clone();
+            }
+        }
+        Ok(end)
+    }
+    /// `find` while `src` is being migrated into `dst` with `migrate_some`.
+    /// `dst` is authoritative, a key that is only in `src` is copied into `dst`
+    /// on first touch so all the updates land in the new table.
+    pub fn find_migrating(src: &[V], dst: &mut [V], key: &K) -> Result<usize> {
+        let p = Self::find(dst, key)?;
+        if !dst[p].key().unused() {
+            return Ok(p);
+        }
+        match Self::find(src, key) {
+            Ok(sp) if src[sp].key() == key => {
+                dst[p] = src[sp].clone();
+            }
+            Ok(_) | Err(Error::NoSpace) => (),
+            Err(e) => return Err(e),
+        }
+        Ok(p)
+    }
+    /// remove `key` from the table using backward shift deletion, so probe
+    /// chains stay intact without tombstones.
+    /// returns false if the key was not in the table
+    pub fn remove(tbl: &mut [V], key: &K) -> Result<bool>
+    where
+        V: Default,
+    {
+        Self::remove_with(tbl, key, |_| Ok(()))
+    }
+    /// `remove` while `src` is being migrated into `dst`, the key is removed
+    /// from both tables. entries shifted in `src` could land behind the
+    /// migration cursor so they are pulled into `dst` right away.
+    pub fn remove_migrating(src: &mut [V], dst: &mut [V], key: &K) -> Result<bool>
+    where
+        V: Default,
+    {
+        let removed = Self::remove(dst, key)?;
+        Self::remove_with(src, key, |v| {
+            let p = Self::find(dst, v.key())?;
+            if dst[p].key().unused() {
+                dst[p] = v.clone();
+            }
+            Ok(())
+        })?;
+        Ok(removed)
+    }
+    fn remove_with<F>(tbl: &mut [V], key: &K, mut shifted: F) -> Result<bool>
+    where
+        V: Default,
+        F: FnMut(&V) -> Result<()>,
+    {
+        let num_elems = tbl.len();
+        let mut hole = match Self::find(tbl, key) {
+            Ok(p) => p,
+            Err(Error::NoSpace) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if tbl[hole].key().unused() {
+            return Ok(false);
+        }
+        tbl[hole] = V::default();
+        let mut pos = hole;
+        for _ in 1..num_elems {
+            pos = (pos + 1) % num_elems;
+            if tbl[pos].key().unused() {
+                break;
+            }
+            let home = tbl[pos].key().start() % num_elems;
+            let dist = (pos + num_elems - home) % num_elems;
+            let hole_dist = (hole + num_elems - home) % num_elems;
+            if hole_dist < dist {
+                shifted(&tbl[pos])?;
+                tbl[hole] = tbl[pos].clone();
+                tbl[pos] = V::default();
+                hole = pos;
+            }
+        }
+        Ok(true)
+    }
 }
 
 #[cfg(test)]
@@ -120,5 +213,83 @@ mod test {
         m[mc] = 3;
         assert_eq!(UsizeT::find(&m, &3usize).unwrap(), mc);
     }
+    #[test]
+    fn hash_migrate_some_test() {
+        let mut v = vec![0usize; 4];
+        for k in [1usize, 2, 3].iter() {
+            let p = UsizeT::find(&v, k).expect("find");
+            v[p] = *k;
+        }
+        let mut m = vec![0usize; 8];
+        let next = UsizeT::migrate_some(&v, &mut m, 0, 2).expect("migrate");
+        assert_eq!(next, 2);
+        assert_eq!(m[UsizeT::find(&m, &1usize).unwrap()], 1);
+        assert!(m[UsizeT::find(&m, &3usize).unwrap()] == 0);
+        //3 has not been moved yet, touching it pulls it over
+        let p3 = UsizeT::find_migrating(&v, &mut m, &3usize).expect("find 3");
+        assert_eq!(m[p3], 3);
+        let p9 = UsizeT::find_migrating(&v, &mut m, &9usize).expect("find 9");
+        assert_eq!(m[p9], 0);
+        m[p9] = 9;
+        let next = UsizeT::migrate_some(&v, &mut m, next, 2).expect("migrate");
+        assert_eq!(next, 4);
+        assert_eq!(UsizeT::migrate_some(&v, &mut m, next, 2).expect("done"), 4);
+        let mut found: Vec<usize> = m.iter().cloned().filter(|k| *k != 0).collect();
+        found.sort();
+        assert_eq!(found, [1, 2, 3, 9]);
+        let e = vec![0usize; 0];
+        let p = UsizeT::find_migrating(&e, &mut m, &2usize).expect("find 2");
+        assert_eq!(m[p], 2);
+    }
+    #[test]
+    fn hash_remove_test() {
+        let mut v = vec![0usize; 4];
+        for k in [4usize, 8, 12, 1].iter() {
+            let p = UsizeT::find(&v, k).expect("find");
+            v[p] = *k;
+        }
+        //4, 8 and 12 all start at 0 and probe into 1, which pushes 1 to 3
+        assert_eq!(v, [4, 8, 12, 1]);
+        assert!(UsizeT::remove(&mut v, &8usize).expect("remove 8"));
+        assert!(!UsizeT::remove(&mut v, &8usize).expect("remove 8 again"));
+        assert_eq!(v, [4, 12, 1, 0]);
+        for k in [4usize, 12, 1].iter() {
+            let p = UsizeT::find(&v, k).expect("find");
+            assert_eq!(v[p], *k);
+        }
+        assert!(UsizeT::remove(&mut v, &4usize).expect("remove 4"));
+        assert_eq!(v, [12, 1, 0, 0]);
+        assert!(!UsizeT::remove(&mut v, &3usize).expect("remove 3"));
+    }
+    #[test]
+    fn hash_remove_migrating_test() {
+        let mut v = vec![0usize; 4];
+        for k in [4usize, 8, 12, 1].iter() {
+            let p = UsizeT::find(&v, k).expect("find");
+            v[p] = *k;
+        }
+        let mut m = vec![0usize; 8];
+        //only slot 0 was migrated, removing 4 shifts 8, 12 and 1 into the migrated range
+        let next = UsizeT::migrate_some(&v, &mut m, 0, 1).expect("migrate");
+        assert!(UsizeT::remove_migrating(&mut v, &mut m, &4usize).expect("remove"));
+        assert_eq!(v, [8, 12, 1, 0]);
+        UsizeT::migrate_some(&v, &mut m, next, 4).expect("migrate");
+        let mut found: Vec<usize> = m.iter().cloned().filter(|k| *k != 0).collect();
+        found.sort();
+        assert_eq!(found, [1, 8, 12]);
+    }
+    #[test]
+    fn hash_remove_wrap_test() {
+        let mut v = vec![0usize; 4];
+        for k in [3usize, 7, 11].iter() {
+            let p = UsizeT::find(&v, k).expect("find");
+            v[p] = *k;
+        }
+        assert_eq!(v, [7, 11, 0, 3]);
+        assert!(UsizeT::remove(&mut v, &3usize).expect("remove 3"));
+        assert_eq!(v, [11, 0, 0, 7]);
+        let mut full = vec![1usize, 2];
+        assert!(!UsizeT::remove(&mut full, &3usize).expect("remove 3"));
+    }
 
 }
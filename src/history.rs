@@ -0,0 +1,164 @@
+//! index from account key to the transfers that touched it, built as the
+//! batches are executed and kept in memory only. the oldest records of a key
+//! are forgotten after `MAX_KEY_RECORDS`, and the oldest of all after `MAX_RECORDS`
+
+use data;
+use std::collections::{HashMap, VecDeque};
+
+/// records kept for one key
+const MAX_KEY_RECORDS: usize = 4 * 1024;
+/// records kept for all the keys
+const MAX_RECORDS: usize = 1024 * 1024;
+
+#[derive(Copy, Clone)]
+struct Entry {
+    seq: u64,
+    other: [u8; 32],
+    rec: data::Record,
+}
+
+#[derive(Default)]
+pub struct History {
+    index: HashMap<[u8; 32], VecDeque<Entry>>,
+    /// the insert sequence and the key of every record, oldest first
+    order: VecDeque<(u64, [u8; 32])>,
+    seq: u64,
+}
+
+impl History {
+    pub fn new() -> History {
+        History::default()
+    }
+    /// index a transfer of `amount` from `from` to `to`, message `index`
+    /// of the batch at `height`
+    pub fn record(&mut self, height: u64, index: usize, from: &[u8; 32], to: &[u8; 32], amount: u64) {
+        let rec = |incoming| data::Record {
+            height,
+            amount,
+            index: index as u32,
+            incoming,
+        };
+        self.push(from, *to, rec(0));
+        if from != to {
+            self.push(to, *from, rec(1));
+        }
+    }
+    /// index only the side of `key`, funds that `other` sent to it if
+    /// `incoming`, like an escrow that is paid out later
+    pub fn record_one(
+        &mut self,
+        height: u64,
+        index: usize,
+        key: &[u8; 32],
+        other: &[u8; 32],
+        amount: u64,
+        incoming: bool,
+    ) {
+        let rec = data::Record {
+            height,
+            amount,
+            index: index as u32,
+            incoming: incoming as u32,
+        };
+        self.push(key, *other, rec);
+    }
+    fn push(&mut self, key: &[u8; 32], other: [u8; 32], rec: data::Record) {
+        self.seq += 1;
+        let seq = self.seq;
+        {
+            let v = self.index.entry(*key).or_default();
+            v.push_back(Entry { seq, other, rec });
+            if v.len() > MAX_KEY_RECORDS {
+                v.pop_front();
+            }
+        }
+        self.order.push_back((seq, *key));
+        while self.order.len() > MAX_RECORDS {
+            let (seq, key) = self.order.pop_front().expect("order");
+            self.forget(seq, &key);
+        }
+    }
+    /// drop the record `seq` of `key` if it wasn't dropped already
+    fn forget(&mut self, seq: u64, key: &[u8; 32]) {
+        let empty = match self.index.get_mut(key) {
+            Some(v) => {
+                if v.front().map(|e| e.seq) == Some(seq) {
+                    v.pop_front();
+                }
+                v.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            self.index.remove(key);
+        }
+    }
+    /// up to `num` transfers of `key` with the other account, newest first,
+    /// skipping the `start` newest
+    pub fn page(&self, key: &[u8; 32], start: usize, num: usize) -> Vec<([u8; 32], data::Record)> {
+        match self.index.get(key) {
+            Some(v) => v.iter()
+                .rev()
+                .skip(start)
+                .take(num)
+                .map(|e| (e.other, e.rec))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use history::{History, MAX_KEY_RECORDS, MAX_RECORDS};
+
+    #[test]
+    fn history_test() {
+        let (a, b, c) = ([1u8; 32], [2u8; 32], [3u8; 32]);
+        let mut h = History::new();
+        h.record(0, 0, &a, &b, 10);
+        h.record(0, 1, &b, &c, 5);
+        h.record(1, 0, &c, &b, 1);
+        h.record(2, 0, &a, &a, 7);
+        let p = h.page(&b, 0, 10);
+        assert_eq!(p.len(), 3);
+        assert_eq!(p[0].0, c);
+        assert_eq!(p[0].1.incoming, 1);
+        assert_eq!(p[0].1.height, 1);
+        assert_eq!(p[1].0, c);
+        assert_eq!(p[1].1.incoming, 0);
+        assert_eq!(p[2].0, a);
+        assert_eq!(p[2].1.amount, 10);
+        let p = h.page(&b, 1, 1);
+        assert_eq!(p.len(), 1);
+        assert_eq!(p[0].1.index, 1);
+        assert_eq!(h.page(&b, 3, 10).len(), 0);
+        assert_eq!(h.page(&a, 0, 10).len(), 2);
+        assert_eq!(h.page(&[9u8; 32], 0, 10).len(), 0);
+        h.record_one(3, 0, &c, &a, 4, true);
+        let p = h.page(&c, 0, 10);
+        assert_eq!(p.len(), 3);
+        assert_eq!((p[0].0, p[0].1.incoming, p[0].1.amount), (a, 1, 4));
+        assert_eq!(h.page(&a, 0, 10).len(), 2);
+    }
+    #[test]
+    fn history_limit_test() {
+        let (a, b) = ([1u8; 32], [2u8; 32]);
+        let mut h = History::new();
+        for i in 0..MAX_KEY_RECORDS + 1 {
+            h.record_one(i as u64, 0, &a, &b, 1, false);
+        }
+        assert_eq!(h.page(&a, 0, MAX_RECORDS).len(), MAX_KEY_RECORDS);
+        assert_eq!(h.page(&a, MAX_KEY_RECORDS - 1, 1)[0].1.height, 1);
+        //the records of the other keys push out the oldest of `a`
+        for i in 0..MAX_RECORDS - MAX_KEY_RECORDS + 1 {
+            let mut k = [3u8; 32];
+            k[..8].copy_from_slice(&(i as u64).to_le_bytes());
+            h.record_one(0, 0, &k, &b, 1, true);
+        }
+        assert_eq!(h.page(&a, 0, MAX_RECORDS).len(), MAX_KEY_RECORDS - 1);
+        assert_eq!(h.page(&a, MAX_KEY_RECORDS - 2, 1)[0].1.height, 2);
+        assert!(h.index.len() <= MAX_RECORDS);
+        assert_eq!(h.order.len(), MAX_RECORDS);
+    }
+}
@@ -15,16 +15,27 @@ extern crate serde_derive;
 extern crate serde_json;
 
 pub mod net;
+#[cfg(target_os = "linux")]
+pub mod mmsg;
 pub mod data;
+pub mod codec;
 pub mod otp;
 pub mod hasht;
+pub mod table;
 pub mod result;
 pub mod wallet;
 pub mod reader;
+pub mod limit;
+pub mod dedup;
+pub mod forward;
 pub mod state;
+pub mod shard;
+pub mod history;
+pub mod faucet;
 pub mod aes;
 pub mod daemon;
 pub mod sender;
+pub mod tcp;
 pub mod client;
 
 #[cfg(test)]
@@ -0,0 +1,157 @@
+//! token bucket rate limits per sender key and per source address, checked by
+//! the `Reader` before the messages get to the state, see `data::Kind::GetStats`.
+//! a key is only limited for groups that it signed, so another sender can't
+//! use up its tokens
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// a token in thousandths, so slow rates still refill every millisecond
+const TOKEN: u64 = 1000;
+/// buckets kept before the full ones are forgotten
+const MAX_BUCKETS: usize = 64 * 1024;
+
+#[derive(Clone, Copy)]
+struct Bucket {
+    tokens: u64,
+    /// unix time in milliseconds of the last refill
+    last: u64,
+}
+
+impl Bucket {
+    /// the tokens at unix `now` in milliseconds, up to `full`
+    fn at(&self, rate: u64, full: u64, now: u64) -> u64 {
+        let ms = now.saturating_sub(self.last);
+        self.tokens.saturating_add(ms.saturating_mul(rate)).min(full)
+    }
+    fn refill(&mut self, rate: u64, full: u64, now: u64) {
+        self.tokens = self.at(rate, full, now);
+        self.last = now;
+    }
+}
+
+pub struct Limiter {
+    /// messages per second, and the most that can be sent at once
+    rate: u64,
+    /// tokens of a full bucket
+    full: u64,
+    keys: HashMap<[u8; 32], Bucket>,
+    addrs: HashMap<SocketAddr, Bucket>,
+    /// messages dropped so far
+    dropped: u64,
+}
+
+impl Limiter {
+    pub fn new(rate: u64) -> Limiter {
+        Limiter {
+            rate,
+            full: rate.saturating_mul(TOKEN),
+            keys: HashMap::new(),
+            addrs: HashMap::new(),
+            dropped: 0,
+        }
+    }
+    fn bucket<K>(map: &mut HashMap<K, Bucket>, k: K, rate: u64, full: u64, now: u64) -> &mut Bucket
+    where
+        K: ::std::hash::Hash + Eq,
+    {
+        let b = map.entry(k).or_insert(Bucket {
+            tokens: full,
+            last: now,
+        });
+        b.refill(rate, full, now);
+        b
+    }
+    /// the tokens of `k` without adding a bucket for it
+    fn peek<K>(map: &HashMap<K, Bucket>, k: &K, rate: u64, full: u64, now: u64) -> u64
+    where
+        K: ::std::hash::Hash + Eq,
+    {
+        map.get(k).map(|b| b.at(rate, full, now)).unwrap_or(full)
+    }
+    /// take `num` tokens from the buckets of `key` and `addr` at unix `now` in
+    /// milliseconds, false if either of them is short and the messages should be
+    /// dropped. without a key only the address is limited, the `Reader` only
+    /// passes the key of a head whose signature it checked
+    pub fn allow(
+        &mut self,
+        key: Option<&[u8; 32]>,
+        addr: SocketAddr,
+        num: usize,
+        now: u64,
+    ) -> bool {
+        let cost = (num as u64).saturating_mul(TOKEN);
+        let (rate, full) = (self.rate, self.full);
+        let k = match key {
+            Some(k) => Self::bucket(&mut self.keys, *k, rate, full, now).tokens,
+            None => cost,
+        };
+        let a = Self::bucket(&mut self.addrs, addr, rate, full, now).tokens;
+        if k < cost || a < cost {
+            self.dropped += num as u64;
+            return false;
+        }
+        if let Some(k) = key {
+            self.keys.get_mut(k).expect("key").tokens -= cost;
+        }
+        self.addrs.get_mut(&addr).expect("addr").tokens -= cost;
+        if self.keys.len() + self.addrs.len() > MAX_BUCKETS {
+            self.prune(now);
+        }
+        true
+    }
+    /// whole tokens left for `key` and `addr` at unix `now` in milliseconds
+    pub fn tokens(&self, key: &[u8; 32], addr: SocketAddr, now: u64) -> (u64, u64) {
+        let (rate, full) = (self.rate, self.full);
+        let k = Self::peek(&self.keys, key, rate, full, now);
+        let a = Self::peek(&self.addrs, &addr, rate, full, now);
+        (k / TOKEN, a / TOKEN)
+    }
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+    /// forget the buckets that would be full by now
+    fn prune(&mut self, now: u64) {
+        let (rate, full) = (self.rate, self.full);
+        self.keys.retain(|_, b| b.at(rate, full, now) < full);
+        self.addrs.retain(|_, b| b.at(rate, full, now) < full);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use limit::Limiter;
+
+    #[test]
+    fn limit_test() {
+        let mut l = Limiter::new(10);
+        let (a, b) = ("127.0.0.1:1".parse().unwrap(), "127.0.0.1:2".parse().unwrap());
+        assert!(l.allow(Some(&[1u8; 32]), a, 8, 1000));
+        assert!(!l.allow(Some(&[1u8; 32]), a, 3, 1000));
+        assert_eq!(l.tokens(&[1u8; 32], a, 1000), (2, 2));
+        //same key from another address
+        assert!(!l.allow(Some(&[1u8; 32]), b, 3, 1000));
+        //another key from the same address
+        assert!(!l.allow(Some(&[2u8; 32]), a, 3, 1000));
+        assert!(l.allow(Some(&[2u8; 32]), b, 10, 1000));
+        assert!(!l.allow(None, b, 1, 1000));
+        assert_eq!(l.dropped(), 10);
+        //one token every 100ms, up to the rate
+        assert!(l.allow(Some(&[1u8; 32]), a, 3, 1100));
+        assert_eq!(l.tokens(&[1u8; 32], a, 100_000), (10, 10));
+        //asking doesn't add buckets
+        let keys = l.keys.len();
+        assert_eq!(l.tokens(&[9u8; 32], b, 100_000), (10, 10));
+        assert_eq!(l.keys.len(), keys);
+    }
+    #[test]
+    fn limit_overflow_test() {
+        let mut l = Limiter::new(u64::MAX);
+        let a = "127.0.0.1:1".parse().unwrap();
+        assert!(l.allow(Some(&[1u8; 32]), a, 1000, 1000));
+        let all = u64::MAX / 1000;
+        assert_eq!(l.tokens(&[1u8; 32], a, u64::MAX), (all, all));
+        assert!(l.allow(Some(&[1u8; 32]), a, usize::MAX, u64::MAX));
+        assert_eq!(l.tokens(&[1u8; 32], a, u64::MAX), (0, 0));
+    }
+}
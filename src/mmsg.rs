@@ -0,0 +1,311 @@
+//! batched socket io for linux, `recvmmsg` reads and `sendmmsg` writes many
+//! datagrams with a single system call
+
+use std::cell::RefCell;
+use std::io;
+use std::mem::{size_of, size_of_val, zeroed};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6,
+               UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::ptr::null_mut;
+use nix::libc;
+use codec;
+use data::{Message, MAX_PACKET, MAX_PACKET_MSGS};
+use result::{Error, Result};
+use result::Error::IO;
+
+/// most datagrams read or written in one call
+pub const MAX_MMSG: usize = 64;
+
+/// control bytes of a datagram, room for one `in6_pktinfo` or `in_pktinfo`.
+/// the buffers are words so that the `cmsghdr` in them is aligned
+const CTRL: usize = 64;
+const CTRL_WORDS: usize = CTRL / 8;
+
+thread_local! {
+    static BUFS: RefCell<Vec<u8>> = RefCell::new(vec![0u8; MAX_MMSG * MAX_PACKET]);
+    static CTRLS: RefCell<Vec<u64>> = RefCell::new(vec![0u64; MAX_MMSG * CTRL_WORDS]);
+}
+
+fn to_std(sa: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match i32::from(sa.ss_family) {
+        libc::AF_INET => {
+            let a = unsafe { &*(sa as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(a.sin_addr.s_addr));
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(a.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let a = unsafe { &*(sa as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(a.sin6_addr.s6_addr);
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                ip,
+                u16::from_be(a.sin6_port),
+                a.sin6_flowinfo,
+                a.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+fn from_std(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut sa: libc::sockaddr_storage = unsafe { zeroed() };
+    let len = match *addr {
+        SocketAddr::V4(ref a) => {
+            let s = unsafe { &mut *(&mut sa as *mut _ as *mut libc::sockaddr_in) };
+            s.sin_family = libc::AF_INET as libc::sa_family_t;
+            s.sin_port = a.port().to_be();
+            s.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+            size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(ref a) => {
+            let s = unsafe { &mut *(&mut sa as *mut _ as *mut libc::sockaddr_in6) };
+            s.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            s.sin6_port = a.port().to_be();
+            s.sin6_addr.s6_addr = a.ip().octets();
+            s.sin6_flowinfo = a.flowinfo();
+            s.sin6_scope_id = a.scope_id();
+            size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (sa, len as libc::socklen_t)
+}
+
+/// the local address a datagram was sent to, from the `IP_PKTINFO` or
+/// `IPV6_PKTINFO` control message that `net::bind` asks for
+fn local_addr(h: &libc::msghdr) -> Option<IpAddr> {
+    unsafe {
+        let mut c = libc::CMSG_FIRSTHDR(h);
+        while !c.is_null() {
+            let (level, kind) = ((*c).cmsg_level, (*c).cmsg_type);
+            if level == libc::IPPROTO_IPV6 && kind == libc::IPV6_PKTINFO {
+                let i = &*(libc::CMSG_DATA(c) as *const libc::in6_pktinfo);
+                return Some(IpAddr::V6(Ipv6Addr::from(i.ipi6_addr.s6_addr)));
+            }
+            if level == libc::IPPROTO_IP && kind == libc::IP_PKTINFO {
+                let i = &*(libc::CMSG_DATA(c) as *const libc::in_pktinfo);
+                return Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(i.ipi_addr.s_addr))));
+            }
+            c = libc::CMSG_NXTHDR(h, c);
+        }
+    }
+    None
+}
+
+/// write the control message that sends a datagram to `to` from the local
+/// address `from` into `ctrl`, returns its length or 0 if it can't be done
+fn set_local_addr(ctrl: &mut [u64], to: &SocketAddr, from: IpAddr) -> usize {
+    let mut h: libc::msghdr = unsafe { zeroed() };
+    h.msg_control = ctrl.as_mut_ptr() as *mut libc::c_void;
+    h.msg_controllen = size_of_val(ctrl) as _;
+    unsafe {
+        let c = libc::CMSG_FIRSTHDR(&h);
+        match (*to, from) {
+            (SocketAddr::V6(_), from) => {
+                let ip = match from {
+                    IpAddr::V4(a) => a.to_ipv6_mapped(),
+                    IpAddr::V6(a) => a,
+                };
+                let len = size_of::<libc::in6_pktinfo>() as libc::c_uint;
+                (*c).cmsg_level = libc::IPPROTO_IPV6;
+                (*c).cmsg_type = libc::IPV6_PKTINFO;
+                (*c).cmsg_len = libc::CMSG_LEN(len) as _;
+                let i = &mut *(libc::CMSG_DATA(c) as *mut libc::in6_pktinfo);
+                i.ipi6_addr.s6_addr = ip.octets();
+                i.ipi6_ifindex = 0;
+                libc::CMSG_SPACE(len) as usize
+            }
+            (SocketAddr::V4(_), IpAddr::V4(ip)) => {
+                let len = size_of::<libc::in_pktinfo>() as libc::c_uint;
+                (*c).cmsg_level = libc::IPPROTO_IP;
+                (*c).cmsg_type = libc::IP_PKTINFO;
+                (*c).cmsg_len = libc::CMSG_LEN(len) as _;
+                let i = &mut *(libc::CMSG_DATA(c) as *mut libc::in_pktinfo);
+                i.ipi_ifindex = 0;
+                i.ipi_spec_dst.s_addr = u32::from(ip).to_be();
+                i.ipi_addr.s_addr = 0;
+                libc::CMSG_SPACE(len) as usize
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// `net::read_from_with` with one `recvmmsg` call, blocks until a datagram
+/// arrives and then reads the ones that are already queued
+pub fn recv_mmsg<F>(
+    socket: &UdpSocket,
+    messages: &mut [Message],
+    mdata: &mut [(usize, SocketAddr)],
+    local: &mut [Option<IpAddr>],
+    invalid: F,
+) -> Result<usize>
+where
+    F: Fn(SocketAddr, Error),
+{
+    let num = MAX_MMSG.min(mdata.len()).min(messages.len() / MAX_PACKET_MSGS);
+    if num == 0 {
+        return Ok(0);
+    }
+    BUFS.with(|b| CTRLS.with(|c| {
+        let mut bufs = b.borrow_mut();
+        let mut ctrls = c.borrow_mut();
+        let mut iovs: Vec<libc::iovec> = bufs.chunks_mut(MAX_PACKET)
+            .take(num)
+            .map(|c| libc::iovec {
+                iov_base: c.as_mut_ptr() as *mut libc::c_void,
+                iov_len: c.len(),
+            })
+            .collect();
+        let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { zeroed() }; num];
+        let mut hdrs: Vec<libc::mmsghdr> = vec![unsafe { zeroed() }; num];
+        loop {
+            let hs = hdrs.iter_mut()
+                .zip(iovs.iter_mut())
+                .zip(addrs.iter_mut())
+                .zip(ctrls.chunks_mut(CTRL_WORDS));
+            for (((h, v), a), c) in hs {
+                h.msg_hdr.msg_name = a as *mut _ as *mut libc::c_void;
+                h.msg_hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                h.msg_hdr.msg_iov = v;
+                h.msg_hdr.msg_iovlen = 1;
+                h.msg_hdr.msg_control = c.as_mut_ptr() as *mut libc::c_void;
+                h.msg_hdr.msg_controllen = CTRL as _;
+                h.msg_len = 0;
+            }
+            let rv = unsafe {
+                libc::recvmmsg(
+                    socket.as_raw_fd(),
+                    hdrs.as_mut_ptr(),
+                    num as libc::c_uint,
+                    libc::MSG_WAITFORONE,
+                    null_mut(),
+                )
+            };
+            if rv < 0 {
+                return Err(IO(io::Error::last_os_error()));
+            }
+            let mut total = 0;
+            let mut ix = 0;
+            for i in 0..rv as usize {
+                let from = match to_std(&addrs[i]) {
+                    Some(a) => a,
+                    None => continue,
+                };
+                let size = hdrs[i].msg_len as usize;
+                let pkt = &bufs[i * MAX_PACKET..i * MAX_PACKET + size];
+                match codec::decode_packet(pkt, &mut messages[total..]) {
+                    Ok(n) => {
+                        total += n;
+                        mdata[ix] = (n, from);
+                        if let Some(l) = local.get_mut(ix) {
+                            *l = local_addr(&hdrs[i].msg_hdr);
+                        }
+                        ix += 1;
+                    }
+                    Err(e) => invalid(from, e),
+                }
+            }
+            //like `recv_from`, keep waiting until something valid shows up
+            if ix > 0 {
+                return Ok(ix);
+            }
+        }
+    }))
+}
+
+/// `net::send_from` with one `sendmmsg` call for up to `MAX_MMSG` packets
+pub fn send_mmsg(
+    socket: &UdpSocket,
+    msgs: &[Message],
+    num: &mut usize,
+    addr: SocketAddr,
+    from: Option<IpAddr>,
+) -> Result<()> {
+    let (mut sa, len) = from_std(&addr);
+    let mut ctrl = [0u64; CTRL_WORDS];
+    let clen = from.map(|f| set_local_addr(&mut ctrl, &addr, f)).unwrap_or(0);
+    BUFS.with(|b| {
+        let mut bufs = b.borrow_mut();
+        while *num < msgs.len() {
+            let mut iovs = Vec::new();
+            let mut counts = Vec::new();
+            let mut at = *num;
+            for c in bufs.chunks_mut(MAX_PACKET) {
+                if at >= msgs.len() {
+                    break;
+                }
+                let mut pkt = [0u8; MAX_PACKET];
+                let (size, n) = codec::encode_packet(&msgs[at..], &mut pkt)?;
+                c[..size].copy_from_slice(&pkt[..size]);
+                iovs.push(libc::iovec {
+                    iov_base: c.as_mut_ptr() as *mut libc::c_void,
+                    iov_len: size,
+                });
+                counts.push(n);
+                at += n;
+            }
+            let mut hdrs: Vec<libc::mmsghdr> = vec![unsafe { zeroed() }; iovs.len()];
+            for (h, v) in hdrs.iter_mut().zip(iovs.iter_mut()) {
+                h.msg_hdr.msg_name = &mut sa as *mut _ as *mut libc::c_void;
+                h.msg_hdr.msg_namelen = len;
+                h.msg_hdr.msg_iov = v;
+                h.msg_hdr.msg_iovlen = 1;
+                if clen > 0 {
+                    //the kernel only reads the control message
+                    h.msg_hdr.msg_control = ctrl.as_mut_ptr() as *mut libc::c_void;
+                    h.msg_hdr.msg_controllen = clen as _;
+                }
+            }
+            let rv = unsafe {
+                libc::sendmmsg(
+                    socket.as_raw_fd(),
+                    hdrs.as_mut_ptr(),
+                    hdrs.len() as libc::c_uint,
+                    0,
+                )
+            };
+            if rv < 0 {
+                return Err(IO(io::Error::last_os_error()));
+            }
+            *num += counts[..rv as usize].iter().sum::<usize>();
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use data;
+    use mmsg;
+    use net;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn mmsg_test() {
+        let srv = net::bindall(12006).expect("bind");
+        let cli = net::socket().expect("socket");
+        let addr = "127.0.0.1:12006".parse().expect("addr");
+        let mut msgs = vec![data::Message::default(); data::MAX_BATCH * 3 + 1];
+        for (i, m) in msgs.iter_mut().enumerate() {
+            m.pld.fee = i as u64;
+        }
+        let mut num = 0;
+        mmsg::send_mmsg(&cli, &msgs, &mut num, addr, None).expect("send");
+        assert_eq!(num, msgs.len());
+        let mut v = data::Messages::new();
+        let mut got = Vec::new();
+        while got.len() < 4 {
+            let n = v.with_mut(|m, d| {
+                mmsg::recv_mmsg(&srv, m, d, &mut [], |_: SocketAddr, _| panic!("invalid"))
+            }).expect("recv");
+            let mut total = 0;
+            for &(z, _) in &v.data[..n] {
+                got.push((z, v.msgs[total].pld.fee));
+                total += z;
+            }
+        }
+        let m = data::MAX_BATCH;
+        assert_eq!(got, [(m, 0), (m, m as u64), (m, 2 * m as u64), (1, 3 * m as u64)]);
+    }
+}
@@ -1,24 +1,93 @@
-//! network code, assuming all endpoints are reading and writing little endian C99 LP64 layout.
+//! network code, packets are encoded and decoded with `codec`.
 
-#![allow(mutable_transmutes)]
-
-use std::cmp::min;
 use std::net::UdpSocket;
-use std::mem::transmute;
-use std::mem::size_of;
-use std::slice::from_raw_parts;
-use std::net::SocketAddr;
-use std::net::Ipv4Addr;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::net::IpAddr;
-use data::{Message, MAX_PACKET};
-use result::Result;
+use std::mem::size_of;
+use std::os::unix::io::FromRawFd;
+use nix::libc;
+use nix::errno::Errno;
+use nix::sys::socket::{self, setsockopt, sockopt, AddressFamily, InetAddr, SockAddr, SockFlag,
+                       SockType};
+use codec;
+#[cfg(target_os = "linux")]
+use mmsg;
+use data::{Message, MAX_PACKET, MAX_PACKET_MSGS};
+use result::{Error, Result};
 use result::Error::IO;
 
+/// every IPv6 and IPv4 address of the host at `port`
+pub fn any(port: u16) -> SocketAddr {
+    SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port)
+}
+
+/// a listen address given as a port or as an address and a port
+pub fn listen_addr(s: &str) -> Result<SocketAddr> {
+    match s.parse() {
+        Ok(port) => Ok(any(port)),
+        Err(_) => Ok(s.parse()?),
+    }
+}
+
 pub fn bindall(port: u16) -> Result<UdpSocket> {
-    let ipv4 = Ipv4Addr::new(0, 0, 0, 0);
-    let addr = SocketAddr::new(IpAddr::V4(ipv4), port);
-    let rv = UdpSocket::bind(&addr)?;
-    return Ok(rv);
+    bind(&any(port), false)
+}
+
+/// a socket bound to `addr`, with `SO_REUSEPORT` if `reuse` is set so that
+/// more sockets can bind the same address and share its datagrams. the
+/// unspecified IPv6 address is dual-stack and gets the IPv4 datagrams too,
+/// on hosts without IPv6 the unspecified IPv4 address is used instead
+pub fn bind(addr: &SocketAddr, reuse: bool) -> Result<UdpSocket> {
+    let family = match *addr {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let fd = match socket::socket(family, SockType::Datagram, SockFlag::empty(), None) {
+        Err(::nix::Error::Sys(Errno::EAFNOSUPPORT)) if *addr == any(addr.port()) => {
+            let ipv4 = Ipv4Addr::new(0, 0, 0, 0);
+            return bind(&SocketAddr::new(IpAddr::V4(ipv4), addr.port()), reuse);
+        }
+        r => r?,
+    };
+    let s = unsafe { UdpSocket::from_raw_fd(fd) };
+    if reuse {
+        setsockopt(fd, sockopt::ReusePort, &true)?;
+    }
+    if addr.ip().is_unspecified() && family == AddressFamily::Inet6 {
+        let off: libc::c_int = 0;
+        let rv = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_V6ONLY,
+                &off as *const _ as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        Errno::result(rv)?;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        //report the local address of every datagram, see `read_from_with`
+        let (level, name) = match family {
+            AddressFamily::Inet6 => (libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO),
+            _ => (libc::IPPROTO_IP, libc::IP_PKTINFO),
+        };
+        let on: libc::c_int = 1;
+        let rv = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &on as *const _ as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        Errno::result(rv)?;
+    }
+    socket::bind(fd, &SockAddr::new_inet(InetAddr::from_std(addr)))?;
+    Ok(s)
 }
 
 pub fn socket() -> Result<UdpSocket> {
@@ -26,25 +95,92 @@ pub fn socket() -> Result<UdpSocket> {
     Ok(ret)
 }
 
+/// a socket that can send to `addr`
+pub fn socket_for(addr: &SocketAddr) -> Result<UdpSocket> {
+    let ret = match *addr {
+        SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0")?,
+        SocketAddr::V6(_) => UdpSocket::bind("[::]:0")?,
+    };
+    Ok(ret)
+}
+
+/// a socket connected to `host`, a name or an IPv4 or IPv6 address with a port
+pub fn connect(host: &str) -> Result<UdpSocket> {
+    let addr = host.to_socket_addrs()?.next().ok_or(Error::NoneError)?;
+    let s = socket_for(&addr)?;
+    s.connect(addr)?;
+    Ok(s)
+}
+
+/// read packets into `messages` until the socket would block, packets that
+/// don't decode are dropped
 pub fn read_from(
     socket: &UdpSocket,
     messages: &mut [Message],
     mdata: &mut [(usize, SocketAddr)],
 ) -> Result<usize> {
-    let sz = size_of::<Message>();
+    read_from_with(socket, messages, mdata, &mut [], |from, e| {
+        debug!("invalid packet from {:?} {:?}", from, e);
+    })
+}
+
+/// `read_from` that calls `invalid` for every packet that doesn't decode and
+/// stores the local address each packet was sent to in `local`, as far as it
+/// goes. see `send_from`
+#[cfg(target_os = "linux")]
+pub fn read_from_with<F>(
+    socket: &UdpSocket,
+    messages: &mut [Message],
+    mdata: &mut [(usize, SocketAddr)],
+    local: &mut [Option<IpAddr>],
+    invalid: F,
+) -> Result<usize>
+where
+    F: Fn(SocketAddr, Error),
+{
+    mmsg::recv_mmsg(socket, messages, mdata, local, invalid)
+}
+
+/// `read_from` that calls `invalid` for every packet that doesn't decode,
+/// the local addresses aren't known
+#[cfg(not(target_os = "linux"))]
+pub fn read_from_with<F>(
+    socket: &UdpSocket,
+    messages: &mut [Message],
+    mdata: &mut [(usize, SocketAddr)],
+    local: &mut [Option<IpAddr>],
+    invalid: F,
+) -> Result<usize>
+where
+    F: Fn(SocketAddr, Error),
+{
+    for l in local.iter_mut() {
+        *l = None;
+    }
+    recv_each(socket, messages, mdata, invalid)
+}
+
+/// `read_from_with` with a `recv_from` call for each datagram
+pub fn recv_each<F>(
+    socket: &UdpSocket,
+    messages: &mut [Message],
+    mdata: &mut [(usize, SocketAddr)],
+    invalid: F,
+) -> Result<usize>
+where
+    F: Fn(SocketAddr, Error),
+{
     let max = messages.len();
     let mut total = 0usize;
     let mut ix = 0usize;
+    let mut buf = [0u8; MAX_PACKET];
     socket.set_nonblocking(false)?;
     while total < max {
-        let p = &mut messages[total] as *mut Message;
-        if (max - total) * sz < MAX_PACKET {
+        if max - total < MAX_PACKET_MSGS {
             return Ok(ix);
         }
-        assert!(cfg!(target_endian = "little"));
-        let buf = unsafe { transmute(from_raw_parts(p as *mut u8, MAX_PACKET)) };
         trace!("recv_from");
-        match socket.recv_from(buf) {
+        match socket.recv_from(&mut buf) {
             Err(_) if ix > 0 => {
                 socket.set_nonblocking(false)?;
                 break;
@@ -55,9 +191,16 @@ pub fn read_from(
             }
             Ok((nrecv, from)) => {
                 trace!("got recv_from {:?}", nrecv);
-                total += nrecv / sz;
+                let num = match codec::decode_packet(&buf[..nrecv], &mut messages[total..]) {
+                    Ok(num) => num,
+                    Err(e) => {
+                        invalid(from, e);
+                        continue;
+                    }
+                };
+                total += num;
                 trace!("total recv_from {:?}", total);
-                *mdata.get_mut(ix).unwrap() = (nrecv / sz, from);
+                *mdata.get_mut(ix).unwrap() = (num, from);
                 ix += 1;
                 socket.set_nonblocking(true)?;
             }
@@ -68,31 +211,25 @@ pub fn read_from(
 }
 
 pub fn read(socket: &UdpSocket, messages: &mut [Message], num: &mut usize) -> Result<()> {
-    let sz = size_of::<Message>();
     let max = messages.len();
+    let mut buf = [0u8; MAX_PACKET];
     while *num < max {
-        let p = &mut messages[*num] as *mut Message;
-        if (max - *num) * sz < MAX_PACKET {
+        if max - *num < MAX_PACKET_MSGS {
             break;
         }
-        assert!(cfg!(target_endian = "little"));
-        let buf = unsafe { transmute(from_raw_parts(p as *mut u8, MAX_PACKET)) };
-        let (nrecv, _from) = socket.recv_from(buf)?;
-        *num = *num + nrecv / sz;
+        let (nrecv, _from) = socket.recv_from(&mut buf)?;
+        *num += codec::decode_packet(&buf[..nrecv], &mut messages[*num..])?;
     }
     Ok(())
 }
 
 pub fn write(socket: &UdpSocket, messages: &[Message], num: &mut usize) -> Result<()> {
-    let sz = size_of::<Message>();
     let max = messages.len();
+    let mut buf = [0u8; MAX_PACKET];
     while *num < max {
-        let p = &messages[*num] as *const Message;
-        let bz = min(MAX_PACKET / sz, max - *num) * sz;
-        assert!(cfg!(target_endian = "little"));
-        let buf = unsafe { transmute(from_raw_parts(p as *const u8, bz)) };
-        let sent_size = socket.send(buf)?;
-        *num += sent_size / sz;
+        let (bz, n) = codec::encode_packet(&messages[*num..], &mut buf)?;
+        socket.send(&buf[..bz])?;
+        *num += n;
     }
     Ok(())
 }
@@ -103,27 +240,49 @@ pub fn send_to(
     num: &mut usize,
     addr: SocketAddr,
 ) -> Result<()> {
-    let sz = size_of::<Message>();
+    send_from(socket, msgs, num, addr, None)
+}
+
+/// `send_to` from the local address `from` of a socket bound to the
+/// unspecified address, so that a client that sent to `from` gets the reply
+/// from the address it expects
+#[cfg(target_os = "linux")]
+pub fn send_from(
+    socket: &UdpSocket,
+    msgs: &[Message],
+    num: &mut usize,
+    addr: SocketAddr,
+    from: Option<IpAddr>,
+) -> Result<()> {
+    mmsg::send_mmsg(socket, msgs, num, addr, from)
+}
+
+/// `send_to`, the local address is picked by the kernel
+#[cfg(not(target_os = "linux"))]
+pub fn send_from(
+    socket: &UdpSocket,
+    msgs: &[Message],
+    num: &mut usize,
+    addr: SocketAddr,
+    _from: Option<IpAddr>,
+) -> Result<()> {
     let max = msgs.len();
+    let mut buf = [0u8; MAX_PACKET];
     while *num < max {
-        let p = &msgs[*num] as *const Message;
-        let bz = min(MAX_PACKET / sz, max - *num) * sz;
-        assert!(cfg!(target_endian = "little"));
-        let buf = unsafe { transmute(from_raw_parts(p as *const u8, bz)) };
-        let sent_size = socket.send_to(buf, &addr)?;
-        *num = *num + sent_size / sz;
+        let (bz, n) = codec::encode_packet(&msgs[*num..], &mut buf)?;
+        socket.send_to(&buf[..bz], &addr)?;
+        *num += n;
     }
     Ok(())
 }
 
 #[test]
 fn read_write_test() {
-    let sz = size_of::<Message>();
     let srv = bindall(12345).expect("couldn't create a server");
     let cli = socket().expect("socket create");
     cli.connect("127.0.0.1:12345").expect("client");
-    let max = MAX_PACKET / sz;
-    let mut m = [Message::default(); 26];
+    let max = ::data::MAX_BATCH;
+    let mut m = [Message::default(); MAX_PACKET_MSGS + 1];
     let mut num = 0;
     write(&cli, &m[0..max], &mut num).expect("write");
     assert!(num == max);
@@ -132,3 +291,23 @@ fn read_write_test() {
     assert!(num == max);
     assert!(num > 0);
 }
+
+#[test]
+fn dual_stack_test() {
+    assert_eq!(listen_addr("12").expect("port"), any(12));
+    assert_eq!(
+        listen_addr("127.0.0.1:12").expect("addr"),
+        "127.0.0.1:12".parse().expect("parse")
+    );
+    assert!(listen_addr("localhost").is_err());
+    let srv = bindall(12009).expect("bind");
+    let mut m = [Message::default(); MAX_PACKET_MSGS];
+    for host in ["127.0.0.1:12009", "[::1]:12009"].iter() {
+        let cli = connect(host).expect("connect");
+        let mut num = 0;
+        write(&cli, &m[..1], &mut num).expect("write");
+        num = 0;
+        read(&srv, &mut m, &mut num).expect("read");
+        assert_eq!(num, 1);
+    }
+}
@@ -7,6 +7,7 @@ use std::thread::{spawn, JoinHandle};
 use std::time::Duration;
 use std::net::SocketAddr;
 use data;
+use shard;
 use result::Result;
 use result::Error;
 
@@ -16,8 +17,17 @@ pub enum Port {
     State,
     Recycle,
     Sender,
+    /// accepts the tcp connections, see `tcp`
+    Tcp,
+    /// reads the answers of the leader, see `forward`
+    Forward,
+    /// worker that owns one of the account shards, see `OTP::with_shards`
+    Shard(usize),
 }
 
+/// number of ports that aren't shards
+const NUM_PORTS: usize = 7;
+
 impl Port {
     fn to_usize(self) -> usize {
         match self {
@@ -26,6 +36,9 @@ impl Port {
             Port::State => 2,
             Port::Recycle => 3,
             Port::Sender => 4,
+            Port::Tcp => 5,
+            Port::Forward => 6,
+            Port::Shard(i) => NUM_PORTS + i,
         }
     }
 }
@@ -35,12 +48,21 @@ pub enum Data {
     Signal,
     SharedMessages(data::SharedMessages),
     SendMessage(data::Message, SocketAddr),
+    /// messages that are sent together in one packet
+    SendMessages(Vec<data::Message>, SocketAddr),
+    /// first phase of a sharded batch, see `shard`
+    Debit(Arc<shard::Batch>),
+    /// second phase of a sharded batch
+    Credit(Arc<shard::Batch>),
+    /// last phase of a sharded batch
+    Settle(Arc<shard::Batch>),
 }
 
 struct Locked {
     ports: Vec<Sender<Data>>,
     readers: Vec<Arc<Mutex<Receiver<Data>>>>,
-    threads: Vec<Arc<Option<JoinHandle<Result<()>>>>>,
+    /// the threads that serve each port
+    threads: Vec<Vec<JoinHandle<Result<()>>>>,
 }
 
 pub struct OTP {
@@ -52,27 +74,23 @@ pub type Ports = Vec<Sender<Data>>;
 
 impl OTP {
     pub fn new() -> OTP {
-        let (s1, r1) = channel();
-        let (s2, r2) = channel();
-        let (s3, r3) = channel();
-        let (s4, r4) = channel();
-        let (s5, r5) = channel();
+        Self::with_shards(0)
+    }
+    /// an OTP with `Port::Shard(0)` up to `Port::Shard(num - 1)`
+    pub fn with_shards(num: usize) -> OTP {
+        let mut ports = Vec::new();
+        let mut readers = Vec::new();
+        let mut threads = Vec::new();
+        for _ in 0..NUM_PORTS + num {
+            let (s, r) = channel();
+            ports.push(s);
+            readers.push(Arc::new(Mutex::new(r)));
+            threads.push(Vec::new());
+        }
         let locked = Locked {
-            ports: [s1, s2, s3, s4, s5].to_vec(),
-            readers: [
-                Arc::new(Mutex::new(r1)),
-                Arc::new(Mutex::new(r2)),
-                Arc::new(Mutex::new(r3)),
-                Arc::new(Mutex::new(r4)),
-                Arc::new(Mutex::new(r5)),
-            ].to_vec(),
-            threads: [
-                Arc::new(None),
-                Arc::new(None),
-                Arc::new(None),
-                Arc::new(None),
-                Arc::new(None),
-            ].to_vec(),
+            ports,
+            readers,
+            threads,
         };
         let exit = Arc::new(Mutex::new(false));
         OTP {
@@ -86,7 +104,7 @@ impl OTP {
     {
         let mut w = self.lock.write().unwrap();
         let pz = port.to_usize();
-        if w.threads[pz].is_some() {
+        if !w.threads[pz].is_empty() {
             return Err(Error::OTPError);
         }
         let c_ports = w.ports.clone();
@@ -100,16 +118,58 @@ impl OTP {
                 return Ok(());
             }
         });
-        w.threads[pz] = Arc::new(Some(j));
+        w.threads[pz].push(j);
         return Ok(());
     }
+    /// `source` with `num` threads, each calls `func` with its index
+    pub fn sources<F>(&self, port: Port, num: usize, func: F) -> Result<()>
+    where
+        F: Send + Sync + 'static + Fn(usize, &Ports) -> Result<()>,
+    {
+        let mut w = self.lock.write().unwrap();
+        let pz = port.to_usize();
+        if !w.threads[pz].is_empty() {
+            return Err(Error::OTPError);
+        }
+        let func = Arc::new(func);
+        for i in 0..num {
+            let c_func = func.clone();
+            let c_ports = w.ports.clone();
+            let c_exit = self.exit.clone();
+            let j = spawn(move || loop {
+                match c_func(i, &c_ports) {
+                    Ok(()) => (),
+                    e => return e,
+                }
+                if *c_exit.lock().unwrap() {
+                    return Ok(());
+                }
+            });
+            w.threads[pz].push(j);
+        }
+        Ok(())
+    }
     pub fn listen<F>(&mut self, port: Port, func: F) -> Result<()>
+    where
+        F: Send + 'static + Fn(&Ports, Data) -> Result<()>,
+    {
+        self.listen_with(port, false, func)
+    }
+    /// `listen` that also calls `func` with `Data::Signal` whenever nothing
+    /// arrives for a moment, for listeners that act on a deadline
+    pub fn listen_idle<F>(&mut self, port: Port, func: F) -> Result<()>
+    where
+        F: Send + 'static + Fn(&Ports, Data) -> Result<()>,
+    {
+        self.listen_with(port, true, func)
+    }
+    fn listen_with<F>(&mut self, port: Port, idle: bool, func: F) -> Result<()>
     where
         F: Send + 'static + Fn(&Ports, Data) -> Result<()>,
     {
         let mut w = self.lock.write().unwrap();
         let pz = port.to_usize();
-        if w.threads[pz].is_some() {
+        if !w.threads[pz].is_empty() {
             return Err(Error::OTPError);
         }
         let recv_lock = w.readers[pz].clone();
@@ -120,13 +180,14 @@ impl OTP {
             let timer = Duration::new(0, 500000);
             match recv.recv_timeout(timer) {
                 Ok(val) => func(&c_ports, val).expect("otp listen"),
+                Err(_) if idle => func(&c_ports, Data::Signal).expect("otp listen"),
                 _ => (),
             }
             if *c_exit.lock().unwrap() == true {
                 return Ok(());
             }
         });
-        w.threads[pz] = Arc::new(Some(j));
+        w.threads[pz].push(j);
         return Ok(());
     }
     pub fn send(ports: &Ports, to: Port, m: Data) -> Result<()> {
@@ -146,12 +207,11 @@ impl OTP {
             *self.exit.lock().unwrap() = true;
         }
         {
-            let r = self.lock.read().unwrap();
-            for t in r.threads.iter() {
-                match Arc::try_unwrap((*t).clone()) {
-                    Ok(Some(j)) => j.join()??,
-                    _ => (),
-                };
+            let mut w = self.lock.write().unwrap();
+            for t in w.threads.iter_mut() {
+                for j in t.drain(..) {
+                    j.join()??;
+                }
             }
         }
         return Ok(());
@@ -161,7 +221,7 @@ impl OTP {
 #[cfg(test)]
 mod test {
     use otp::OTP;
-    use otp::Port::{Main, Reader, State};
+    use otp::Port::{Main, Reader, Shard, State};
     use otp::Data::Signal;
     use std::sync::{Arc, Mutex};
 
@@ -171,6 +231,40 @@ mod test {
         assert_matches!(o.shutdown(), Ok(()));
     }
     #[test]
+    fn test_shards() {
+        let mut o = OTP::with_shards(2);
+        assert_matches!(
+            o.source(Reader, move |ports| OTP::send(ports, Shard(1), Signal)),
+            Ok(())
+        );
+        assert_matches!(
+            o.listen(Shard(1), move |ports, _data| OTP::send(ports, Main, Signal)),
+            Ok(())
+        );
+        assert!(o.listen(Shard(1), move |_ports, _data| Ok(())).is_err());
+        assert_matches!(o.join(), Ok(()));
+    }
+    #[test]
+    fn test_sources() {
+        let mut o = OTP::new();
+        let seen = Arc::new(Mutex::new(vec![false; 3]));
+        let c_seen = seen.clone();
+        assert_matches!(
+            o.sources(Reader, 3, move |i, ports| {
+                let mut s = c_seen.lock().unwrap();
+                s[i] = true;
+                if s.iter().all(|s| *s) {
+                    OTP::send(ports, Main, Signal)?;
+                }
+                Ok(())
+            }),
+            Ok(())
+        );
+        assert!(o.source(Reader, move |_ports| Ok(())).is_err());
+        assert_matches!(o.join(), Ok(()));
+        assert!(seen.lock().unwrap().iter().all(|s| *s));
+    }
+    #[test]
     fn test_join() {
         let mut o = OTP::new();
         assert_matches!(
@@ -180,6 +274,18 @@ mod test {
         assert_matches!(o.join(), Ok(()));
     }
     #[test]
+    fn test_listen_idle() {
+        let mut o = OTP::new();
+        assert_matches!(
+            o.listen_idle(State, move |ports, data| match data {
+                Signal => OTP::send(ports, Main, Signal),
+                _ => Ok(()),
+            }),
+            Ok(())
+        );
+        assert_matches!(o.join(), Ok(()));
+    }
+    #[test]
     fn test_source() {
         let mut o = OTP::new();
         assert_matches!(
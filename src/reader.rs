@@ -1,120 +1,625 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
 use std::sync::{Arc, Mutex, RwLock};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
-use result::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::net::{SocketAddr, UdpSocket};
+use result::{Error, Result};
 use result::Error::IO;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use data;
+use dedup::{Dedup, Seen};
+use forward::Forwarder;
+use limit::Limiter;
 use net;
 use otp::{Data, Port, Ports, OTP};
-use sender::Sender;
+use sender::{Routes, Sender};
+use wallet;
 use std::os::unix::io::FromRawFd;
 use std::os::unix::io::AsRawFd;
 use nix::unistd::dup;
 
+/// batches handed to `Port::State` and not recycled yet before the reader
+/// starts to buffer and reorder by fee
+const MAX_IN_FLIGHT: usize = 2;
+/// most messages buffered, the lowest fees are dropped first
+const MAX_PENDING: usize = 64 * 1024;
+/// most messages in a batch
+const SIZE: usize = 1024;
+
+/// a group waiting for admission with the address it came from
+struct Entry {
+    msgs: Vec<data::Message>,
+    addr: SocketAddr,
+}
+
+/// groups waiting while the state is busy, highest fee and then oldest first
+#[derive(Default)]
+struct Pending {
+    groups: BTreeMap<(u64, Reverse<u64>), Entry>,
+    seq: u64,
+    size: usize,
+}
+
+/// why a datagram was dropped before it got to the state
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reject {
+    /// unknown wire format or `Payload::version`
+    Version,
+    /// size that isn't a whole number of messages
+    Length,
+    /// kind that isn't known or can't be sent to the daemon
+    Kind,
+    /// state that isn't `State::Unknown`
+    State,
+    /// `unused` field that isn't zero
+    Unused,
+    /// part that doesn't follow a head message
+    Orphan,
+}
+const NUM_REJECTS: usize = 6;
+
+impl Reject {
+    fn from_error(e: &Error) -> Reject {
+        match *e {
+            Error::UnknownVersion(_) => Reject::Version,
+            Error::InvalidKind(_) => Reject::Kind,
+            Error::InvalidState(_) => Reject::State,
+            _ => Reject::Length,
+        }
+    }
+    /// the first problem with the messages of a datagram
+    fn check(msgs: &[data::Message]) -> Option<Reject> {
+        if !msgs.is_empty() && msgs[0].pld.kind.is_part() {
+            return Some(Reject::Orphan);
+        }
+        for m in msgs {
+            if m.pld.version != data::VERSION {
+                return Some(Reject::Version);
+            }
+            if m.pld.kind == data::Kind::Invalid || m.pld.kind == data::Kind::Record {
+                return Some(Reject::Kind);
+            }
+            if m.pld.state != data::State::Unknown {
+                return Some(Reject::State);
+            }
+            if m.pld.unused != 0 {
+                return Some(Reject::Unused);
+            }
+        }
+        None
+    }
+}
+
 pub struct Reader {
     lock: Mutex<Vec<data::SharedMessages>>,
-    sock: UdpSocket,
+    /// PoH height of the last batch sent to the state, see `Reader::set_height`
+    height: Mutex<u64>,
+    /// sockets that share the port, see `Reader::with_sockets`
+    socks: Vec<UdpSocket>,
+    pending: Mutex<Pending>,
+    in_flight: AtomicUsize,
+    /// the batches that hold the `in_flight` slots, by address
+    issued: Mutex<HashSet<usize>>,
+    min_fee: u64,
+    limit: Option<Mutex<Limiter>>,
+    rejects: [AtomicUsize; NUM_REJECTS],
+    /// signatures of the transactions that were admitted
+    dedup: Mutex<Dedup>,
+    /// the leader that executes the requests, see `Reader::set_leader`
+    leader: Option<Forwarder>,
+    /// the local address of each client, see `sender::Routes`
+    routes: Arc<Routes>,
+    /// socket of the replies if it isn't the listen port
+    reply: Option<UdpSocket>,
 }
 impl Reader {
+    /// the sender of the replies. they go out from the listen port, which
+    /// is where connected sockets and NATs expect them, unless
+    /// `Reader::set_reply` picked another one
     pub fn sender(&self) -> Result<Sender> {
-        //TODO(anatoly): we need to dup this so we can properly respond to
-        //connected udp sockets.  need to find a crate that has win32 and
-        //unix dup
         let sock = unsafe {
-            let fd = self.sock.as_raw_fd();
+            let fd = self.reply.as_ref().unwrap_or(&self.socks[0]).as_raw_fd();
             let nfd = dup(fd)?;
             UdpSocket::from_raw_fd(nfd)
         };
-        return Ok(Sender::new(sock));
+        let mut s = Sender::new(sock);
+        s.set_routes(self.routes.clone())?;
+        Ok(s)
+    }
+    /// send the replies from a socket bound to `addr` instead of the listen
+    /// port, only clients that don't connect their sockets get them
+    pub fn set_reply(&mut self, addr: SocketAddr) -> Result<()> {
+        self.reply = Some(net::bind(&addr, false)?);
+        Ok(())
     }
+    /// a reader on `port` of every IPv6 and IPv4 address
     pub fn new(port: u16) -> Result<Reader> {
-        let ipv4 = Ipv4Addr::new(0, 0, 0, 0);
-        let addr = SocketAddr::new(IpAddr::V4(ipv4), port);
-        let srv = UdpSocket::bind(&addr)?;
-        let timer = Duration::new(1, 0);
-        srv.set_read_timeout(Some(timer))?;
+        Self::with_sockets(net::any(port), 1)
+    }
+    /// a reader with `num` sockets bound to `addr` with `SO_REUSEPORT`, the
+    /// kernel spreads the senders over them and each is read by its own
+    /// thread, see `Reader::run_socket`
+    pub fn with_sockets(addr: SocketAddr, num: usize) -> Result<Reader> {
+        let mut socks = Vec::new();
+        for _ in 0..num {
+            let srv = net::bind(&addr, num > 1)?;
+            let timer = Duration::new(1, 0);
+            srv.set_read_timeout(Some(timer))?;
+            socks.push(srv);
+        }
         let rv = Reader {
             lock: Mutex::new(Vec::new()),
-            sock: srv,
+            height: Mutex::new(0),
+            socks,
+            pending: Mutex::new(Pending::default()),
+            in_flight: AtomicUsize::new(0),
+            issued: Mutex::new(HashSet::new()),
+            min_fee: 0,
+            limit: None,
+            rejects: Default::default(),
+            dedup: Mutex::new(Dedup::new()),
+            leader: None,
+            routes: Arc::new(Routes::default()),
+            reply: None,
         };
         return Ok(rv);
     }
+    /// drop the messages that pay less than `fee`, airdrops are exempt
+    pub fn set_min_fee(&mut self, fee: u64) {
+        self.min_fee = fee;
+    }
+    /// drop the messages of a key or an address that sends more than `rate` a second
+    pub fn set_rate(&mut self, rate: u64) {
+        self.limit = Some(Mutex::new(Limiter::new(rate)));
+    }
+    /// forward the requests to the leader at `host` instead of executing
+    /// them, the answers are read by `Reader::run_leader`
+    pub fn set_leader(&mut self, host: &str) -> Result<()> {
+        self.leader = Some(Forwarder::new(host)?);
+        Ok(())
+    }
+    pub fn has_leader(&self) -> bool {
+        self.leader.is_some()
+    }
+    /// pass an answer of the leader on to its client
+    pub fn run_leader(&self, ports: &Ports) -> Result<()> {
+        match self.leader {
+            Some(ref l) => l.run(ports),
+            None => Ok(()),
+        }
+    }
+    /// datagrams dropped so far for `r`
+    pub fn rejected(&self, r: Reject) -> usize {
+        self.rejects[r as usize].load(Ordering::Relaxed)
+    }
+    /// count a packet from `from` that didn't decode
+    pub fn invalid(&self, from: SocketAddr, e: &Error) {
+        self.reject(Reject::from_error(e), from);
+    }
+    fn reject(&self, r: Reject, from: SocketAddr) {
+        debug!("rejected datagram from {:?} {:?}", from, r);
+        self.rejects[r as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    /// drop the datagrams of a batch that was just read that aren't well
+    /// formed, returns the number of messages left
+    fn validate(&self, v: &mut data::Messages) -> usize {
+        let data::Messages {
+            ref mut msgs,
+            ref mut data,
+            ..
+        } = *v;
+        let mut out = 0;
+        let mut ix = 0;
+        data.retain(|&(z, from)| {
+            let end = ix + z;
+            let r = Reject::check(&msgs[ix..end]);
+            if let Some(r) = r {
+                self.reject(r, from);
+            } else {
+                msgs.copy_within(ix..end, out);
+                out += z;
+            }
+            ix = end;
+            r.is_none()
+        });
+        msgs.truncate(out);
+        out
+    }
     pub fn recycle(&self, d: Data) {
         match d {
             Data::SharedMessages(m) => {
-                let mut gc = self.lock.lock().expect("lock");
-                gc.push(m);
+                //only the batches that this reader sent hold one of its slots
+                if self.issued.lock().unwrap().remove(&batch_id(&m)) {
+                    self.release();
+                }
+                self.lock.lock().expect("lock").push(m);
             }
             _ => (),
         }
     }
+    /// answer the transactions of a batch that was executed with their
+    /// result, before it is recycled
+    pub fn acknowledge(&self, ports: &Ports, d: &Data) -> Result<()> {
+        if let Data::SharedMessages(ref m) = *d {
+            let v = m.read().unwrap();
+            let mut ix = 0;
+            for &(z, addr) in v.data.iter() {
+                let end = ix + z;
+                while ix < end {
+                    let h = v.msgs[ix];
+                    let num = data::group_len(&v.msgs[ix..end]);
+                    //a forged copy can't take the result of the one it copied
+                    let signed = h.pld.kind == data::Kind::Transaction
+                        && wallet::verify(&v.msgs[ix..ix + num], 0);
+                    ix += num;
+                    if signed && self.dedup.lock().unwrap().done(&h) {
+                        OTP::send(ports, Port::Sender, Data::SendMessage(h, addr))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    /// true if `fee` is enough for `m`, the unsigned kinds are free
+    fn admitted(&self, m: &data::Message, fee: u64) -> bool {
+        fee >= self.min_fee || m.pld.kind == data::Kind::Airdrop
+            || m.pld.kind == data::Kind::GetStats
+    }
+    /// true if the head of `group` is signed by its `from`. only checked for
+    /// the transactions, which are deduplicated by signature, the groups that
+    /// pay a fee and the groups that are rate limited by key
+    fn signed(&self, group: &[data::Message]) -> bool {
+        let kind = group[0].pld.kind;
+        //anyone can ask for the stats of a key, even one that is throttled
+        if kind == data::Kind::GetStats || kind == data::Kind::Airdrop {
+            return false;
+        }
+        let needed =
+            kind == data::Kind::Transaction || group[0].pld.fee > 0 || self.limit.is_some();
+        needed && wallet::verify(group, 0)
+    }
+    /// the fee that `group` is ordered and admitted by. a claimed fee only
+    /// counts if someone signed it, the head or for a multisig account its
+    /// first `Signature` part, the state checks that it is paid
+    fn fee(&self, group: &[data::Message], signed: bool) -> u64 {
+        let fee = group[0].pld.fee;
+        if signed || fee == 0 {
+            return fee;
+        }
+        match group.iter().position(|p| p.pld.kind == data::Kind::Signature) {
+            Some(ix) if wallet::verify(group, ix) => fee,
+            _ => 0,
+        }
+    }
+    /// a key is only charged for the groups that it `signed`
+    fn limited(&self, group: &[data::Message], signed: bool, addr: SocketAddr, now: u64) -> bool {
+        match self.limit {
+            Some(ref l) => {
+                let key = if signed { Some(&group[0].pld.from) } else { None };
+                !l.lock().unwrap().allow(key, addr, group.len(), now)
+            }
+            None => false,
+        }
+    }
+    /// the answer to a `GetStats`
+    fn stats(&self, m: &data::Message, addr: SocketAddr, now: u64) -> data::Message {
+        let mut r = *m;
+        let max = u64::from(u16::MAX);
+        let (k, a, d) = match self.limit {
+            Some(ref l) => {
+                let l = l.lock().unwrap();
+                let (k, a) = l.tokens(&m.pld.get_stats().key, addr, now);
+                (k, a, l.dropped())
+            }
+            None => (max, max, 0),
+        };
+        let st = r.pld.get_stats_mut();
+        st.key_tokens = k.min(max) as u16;
+        st.addr_tokens = a.min(max) as u16;
+        st.dropped = d.min(u64::from(u32::MAX)) as u32;
+        r
+    }
+    /// drop the groups below the minimum fee or over the rate limits and the
+    /// copies of admitted transactions, or forward the requests to the
+    /// leader, from a batch that was just read at unix
+    /// `now` in milliseconds. the `GetStats` queries and the copies of executed
+    /// transactions are taken out with their answers in `replies`, the `fee`
+    /// of each group that is left is pushed to `fees`. returns the number of
+    /// messages left
+    fn filter(
+        &self,
+        v: &mut data::Messages,
+        now: u64,
+        replies: &mut Vec<(data::Message, SocketAddr)>,
+        fees: &mut Vec<u64>,
+    ) -> usize {
+        let data::Messages {
+            ref mut msgs,
+            ref mut data,
+            ..
+        } = *v;
+        let mut out = 0;
+        let mut ix = 0;
+        for d in data.iter_mut() {
+            let end = ix + d.0;
+            let mut kept = 0;
+            while ix < end {
+                let num = data::group_len(&msgs[ix..end]);
+                let m = msgs[ix];
+                //the claimed fee is checked first, it's cheaper than the signature
+                if !self.admitted(&m, m.pld.fee) {
+                    ix += num;
+                    continue;
+                }
+                let signed = self.signed(&msgs[ix..ix + num]);
+                let fee = self.fee(&msgs[ix..ix + num], signed);
+                if !self.admitted(&m, fee) {
+                    ix += num;
+                    continue;
+                }
+                if self.limited(&msgs[ix..ix + num], signed, d.1, now) {
+                    ix += num;
+                    continue;
+                }
+                if let Some(ref l) = self.leader {
+                    //the stats are about the limits of this node
+                    if m.pld.kind != data::Kind::GetStats {
+                        if let Err(e) = l.forward(&msgs[ix..ix + num], d.1) {
+                            warn!("forward to the leader failed {:?}", e);
+                        }
+                        ix += num;
+                        continue;
+                    }
+                }
+                //only a signature that belongs to the group stands for it
+                let seen = if m.pld.kind == data::Kind::Transaction && signed {
+                    self.dedup.lock().unwrap().check(&m)
+                } else {
+                    Seen::New
+                };
+                if m.pld.kind == data::Kind::GetStats {
+                    replies.push((self.stats(&m, d.1, now), d.1));
+                } else if let Seen::Done(r) = seen {
+                    replies.push((r, d.1));
+                } else if let Seen::New = seen {
+                    msgs.copy_within(ix..ix + num, out);
+                    fees.push(fee);
+                    out += num;
+                    kept += num;
+                }
+                ix += num;
+            }
+            d.0 = kept;
+        }
+        msgs.truncate(out);
+        out
+    }
+    /// buffer every group of `v` until the state can take more, ordered by
+    /// the `fees` that `filter` found for them
+    fn buffer(&self, v: &data::Messages, fees: &[u64]) {
+        let mut p = self.pending.lock().unwrap();
+        let mut ix = 0;
+        let mut fees = fees.iter();
+        for &(z, addr) in v.data.iter() {
+            let end = ix + z;
+            while ix < end {
+                let num = data::group_len(&v.msgs[ix..end]);
+                let e = Entry {
+                    msgs: v.msgs[ix..ix + num].to_vec(),
+                    addr,
+                };
+                let fee = *fees.next().expect("fee");
+                let key = (fee, Reverse(p.seq));
+                p.seq += 1;
+                p.size += num;
+                p.groups.insert(key, e);
+                ix += num;
+            }
+        }
+        while p.size > MAX_PENDING {
+            let low = *p.groups.keys().next().expect("pending");
+            let e = p.groups.remove(&low).expect("pending");
+            p.size -= e.msgs.len();
+            self.dedup.lock().unwrap().forget(&e.msgs[0]);
+        }
+    }
+    /// fill `v` with the buffered groups that pay the most, returns the number of messages
+    fn fill(&self, v: &mut data::Messages) -> usize {
+        let mut p = self.pending.lock().unwrap();
+        v.msgs.clear();
+        v.data.clear();
+        while let Some(&key) = p.groups.keys().next_back() {
+            if v.msgs.len() + p.groups[&key].msgs.len() > SIZE {
+                break;
+            }
+            let e = p.groups.remove(&key).expect("pending");
+            p.size -= e.msgs.len();
+            v.data.push((e.msgs.len(), e.addr));
+            v.msgs.extend_from_slice(&e.msgs);
+        }
+        v.msgs.len()
+    }
+    /// take one of the `MAX_IN_FLIGHT` batch slots of the state, false if
+    /// they are all taken. the reading threads race for them
+    fn reserve(&self) -> bool {
+        self.in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                if n < MAX_IN_FLIGHT {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+    /// give back a slot taken by `reserve`
+    fn release(&self) {
+        let _ = self.in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+    }
+    /// hand a batch of buffered groups to the state if it isn't busy
+    fn admit(&self, ports: &Ports) -> Result<bool> {
+        if self.pending.lock().unwrap().groups.is_empty() || !self.reserve() {
+            return Ok(false);
+        }
+        let m = self.allocate();
+        let num = self.fill(&mut m.write().unwrap());
+        if num == 0 {
+            //another thread took the buffered groups first
+            self.release();
+            self.lock.lock().expect("lock").push(m);
+            return Ok(false);
+        }
+        self.send(ports, m)?;
+        Ok(true)
+    }
+    /// stamp the batch with the next height and the time, it's sent while
+    /// the height is held so the state gets the batches in height order.
+    /// the caller has a slot from `reserve`
+    fn send(&self, ports: &Ports, m: data::SharedMessages) -> Result<()> {
+        let mut height = self.height.lock().unwrap();
+        *height += 1;
+        {
+            let mut v = m.write().unwrap();
+            v.height = *height;
+            v.time = now() / 1000;
+        }
+        self.issued.lock().unwrap().insert(batch_id(&m));
+        OTP::send(ports, Port::State, Data::SharedMessages(m))
+    }
+    /// continue the heights after `height`, the height of the restored state
+    pub fn set_height(&mut self, height: u64) {
+        *self.height.lock().unwrap() = height;
+    }
 
-    fn read(&self, m: data::SharedMessages) -> Result<usize> {
+    pub fn num_sockets(&self) -> usize {
+        self.socks.len()
+    }
+    fn read(&self, ix: usize, m: data::SharedMessages) -> Result<usize> {
         let mut v = m.write().unwrap();
-        const SIZE: usize = 1024;
         v.msgs.resize(SIZE, data::Message::default());
         v.data.resize(SIZE, data::Messages::def_data());
-        v.with(move |ms, ds| net::read_from(&self.sock, ms, ds))
+        let mut local = [None; SIZE];
+        let n = v.with_mut(|ms, ds| {
+            net::read_from_with(&self.socks[ix], ms, ds, &mut local, |from, e| {
+                self.invalid(from, &e)
+            })
+        })?;
+        self.routes.add(&v.data[..n], &local[..n]);
+        Ok(n)
     }
 
     pub fn run(&self, ports: &Ports) -> Result<()> {
+        self.run_socket(0, ports)
+    }
+    /// read a batch from socket `ix` and pass it on to `Port::State`
+    pub fn run_socket(&self, ix: usize, ports: &Ports) -> Result<()> {
+        if self.admit(ports)? {
+            return Ok(());
+        }
         let m = self.allocate();
-        let mut total = 0usize;
-        {
-            trace!("reading");
-            let r = self.read(m.clone());
-            trace!("reading done");
-            match r {
-                Err(IO(e)) => {
-                    debug!("failed with IO error {:?}", e);
-                }
-                Err(e) => {
-                    debug!("read failed error {:?}", e);
-                }
-                Ok(0) => {
-                    trace!("read returned 0");
-                }
-                Ok(num) => {
+        trace!("reading");
+        let r = self.read(ix, m.clone());
+        trace!("reading done");
+        match r {
+            Err(IO(e)) => {
+                debug!("failed with IO error {:?}", e);
+            }
+            Err(e) => {
+                debug!("read failed error {:?}", e);
+            }
+            Ok(0) => {
+                trace!("read returned 0");
+            }
+            Ok(num) => {
+                {
                     let mut v = m.write().unwrap();
-                    let s: usize = v.data.iter_mut().map(|v| v.0).sum();
-                    total += s;
-                    v.msgs.resize(s, data::Message::default());
                     v.data.resize(num, data::Messages::def_data());
+                    let s: usize = v.data.iter().map(|v| v.0).sum();
+                    v.msgs.resize(s, data::Message::default());
                 }
+                return self.submit(ports, m);
             }
         }
+        self.lock.lock().expect("lock").push(m);
+        self.admit(ports)?;
+        Ok(())
+    }
+    /// check and admit a batch that wasn't read from the sockets of the
+    /// reader, see `tcp`
+    pub fn submit(&self, ports: &Ports, m: data::SharedMessages) -> Result<()> {
+        let mut total = 0usize;
+        let mut replies = Vec::new();
+        let mut fees = Vec::new();
+        {
+            let mut v = m.write().unwrap();
+            if self.validate(&mut v) > 0 {
+                total = self.filter(&mut v, now(), &mut replies, &mut fees);
+            }
+        }
+        for (r, a) in replies {
+            OTP::send(ports, Port::Sender, Data::SendMessage(r, a))?;
+        }
+        //the buffered groups go first, they pay more or waited longer
+        let queued = !self.pending.lock().unwrap().groups.is_empty();
+        if total > 0 && !queued && self.reserve() {
+            return self.send(ports, m);
+        }
         if total > 0 {
-            OTP::send(ports, Port::State, Data::SharedMessages(m))?;
-            return Ok(());
-        } else {
-            let mut gc = self.lock.lock().expect("lock");
-            gc.push(m);
-            return Ok(());
+            //the state is behind, wait in line by fee
+            self.buffer(&m.read().unwrap(), &fees);
         }
+        self.lock.lock().expect("lock").push(m);
+        self.admit(ports)?;
+        Ok(())
     }
-    fn allocate(&self) -> data::SharedMessages {
+    /// a batch buffer, recycled ones are reused
+    pub fn allocate(&self) -> data::SharedMessages {
         let mut gc = self.lock.lock().expect("lock");
         gc.pop()
             .unwrap_or_else(|| Arc::new(RwLock::new(data::Messages::new())))
     }
 }
 
+/// the address of a batch, which stays the same while it is in flight
+fn batch_id(m: &data::SharedMessages) -> usize {
+    &**m as *const RwLock<data::Messages> as usize
+}
+
+/// unix time in milliseconds
+pub fn now() -> u64 {
+    let d = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    d.as_secs() * 1000 + u64::from(d.subsec_nanos()) / 1_000_000
+}
+
 #[cfg(test)]
 mod test {
     use std::thread::sleep;
     use otp::{Data, Port, OTP};
-    use std::sync::{Arc, Mutex};
-    use std::net::UdpSocket;
-    use reader::Reader;
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::sync::mpsc::channel;
+    use std::net::{SocketAddr, UdpSocket};
+    use reader::{Reader, Reject, MAX_IN_FLIGHT};
+    use result::Result;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    #[cfg(target_os = "linux")]
+    use mmsg;
+    use codec;
     use std::time::Duration;
     use std::time::SystemTime;
     use std::thread::spawn;
     use net;
     use data;
+    use wallet::{to32b, Wallet};
 
     #[test]
     fn reader_test() {
-        let reader = Arc::new(Reader::new(12001).expect("reader"));
+        let mut reader = Reader::new(12001).expect("reader");
+        //a restored state at height 10
+        reader.set_height(10);
+        let reader = Arc::new(reader);
         let mut o = OTP::new();
         let a_reader = reader.clone();
         assert_matches!(
@@ -132,11 +637,14 @@ mod test {
 
         let rvs = Arc::new(Mutex::new(0usize));
         let a_rvs = rvs.clone();
+        let heights = Arc::new(Mutex::new(Vec::new()));
+        let a_heights = heights.clone();
         assert_matches!(
             o.listen(Port::State, move |ports, data| match data {
                 Data::SharedMessages(msgs) => {
                     let mut v = a_rvs.lock().unwrap();
                     *v += msgs.read().unwrap().data.len();
+                    a_heights.lock().unwrap().push(msgs.read().unwrap().height);
                     OTP::send(ports, Port::Recycle, Data::SharedMessages(msgs))?;
                     Ok(())
                 }
@@ -149,7 +657,11 @@ mod test {
         cli.connect("127.0.0.1:12001").expect("client");
         let timer = Duration::new(1, 0);
         cli.set_write_timeout(Some(timer)).expect("write timer");
-        let m = [data::Message::default(); 64];
+        let mut m = [data::Message::default(); 64];
+        for (i, m) in m.iter_mut().enumerate() {
+            m.pld.kind = data::Kind::Transaction;
+            m.sig[0] = i as u8;
+        }
         let mut num = 0;
         let mut tries = 0;
         while num < 64 && tries < 100 {
@@ -163,13 +675,309 @@ mod test {
         sleep(Duration::new(1, 0));
         assert!(o.shutdown().is_ok());
         assert_eq!(*rvs.lock().unwrap(), 64);
+        //every batch gets the next height
+        let h = heights.lock().unwrap();
+        assert_eq!(*h, (11..11 + h.len() as u64).collect::<Vec<_>>());
     }
-    fn send_msgs(b: Arc<Mutex<bool>>) {
-        let addr = "127.0.0.1:12002".parse().unwrap();
-        let m = data::Message::default();
+    #[test]
+    fn reader_sockets_test() {
+        let reader = Arc::new(Reader::with_sockets(net::any(12008), 2).expect("reader"));
+        assert_eq!(reader.num_sockets(), 2);
+        let mut o = OTP::new();
+        let a_reader = reader.clone();
+        assert_matches!(
+            o.sources(Port::Reader, 2, move |i, ports| a_reader.run_socket(i, ports)),
+            Ok(())
+        );
+        let b_reader = reader.clone();
+        assert_matches!(
+            o.listen(Port::Recycle, move |_ports, data| {
+                b_reader.recycle(data);
+                Ok(())
+            }),
+            Ok(())
+        );
+        let rvs = Arc::new(Mutex::new(0usize));
+        let a_rvs = rvs.clone();
+        assert_matches!(
+            o.listen(Port::State, move |ports, data| match data {
+                Data::SharedMessages(msgs) => {
+                    *a_rvs.lock().unwrap() += msgs.read().unwrap().msgs.len();
+                    OTP::send(ports, Port::Recycle, Data::SharedMessages(msgs))
+                }
+                _ => Ok(()),
+            }),
+            Ok(())
+        );
+        let mut m = data::Message::default();
+        m.pld.kind = data::Kind::Transaction;
+        //the kernel picks the socket by the address of the sender
+        for i in 0..8 {
+            m.sig[0] = i;
+            let cli = net::socket().expect("socket");
+            cli.connect("127.0.0.1:12008").expect("connect");
+            let mut num = 0;
+            net::write(&cli, &[m], &mut num).expect("write");
+        }
+        sleep(Duration::new(1, 0));
+        assert!(o.shutdown().is_ok());
+        assert_eq!(*rvs.lock().unwrap(), 8);
+    }
+    #[test]
+    fn reader_fee_test() {
+        let mut reader = Reader::new(12003).expect("reader");
+        reader.set_min_fee(2);
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let kp = Wallet::new_keypair();
+        let mut w = Wallet::new();
+        w.add_keypair(kp);
+        let mut v = data::Messages::new();
+        v.msgs.clear();
+        v.data.clear();
+        v.msgs.push(w.tx(0, [9u8; 32], 1, 1));
+        //a multisig head is unsigned, the fee counts if its signer signed it
+        let mut group = [data::Message::default(); 2];
+        group[0].pld.kind = data::Kind::MultiSig;
+        group[0].pld.fee = 3;
+        group[1].pld.kind = data::Kind::Signature;
+        group[1].pld.from = to32b(kp.1);
+        Wallet::sign_group(kp, &mut group, 1);
+        v.msgs.extend_from_slice(&group);
+        v.msgs.push(w.tx(0, [9u8; 32], 1, 2));
+        v.msgs.push(Wallet::airdrop([9u8; 32], 1));
+        v.msgs.push(w.tx(0, [9u8; 32], 1, 5));
+        //nobody signed this fee
+        let mut forged = w.tx(0, [9u8; 32], 1, 9);
+        forged.pld.from = [8u8; 32];
+        v.msgs.push(forged);
+        v.data.push((3, addr));
+        v.data.push((4, addr));
+        let mut stats = Vec::new();
+        let mut fees = Vec::new();
+        assert_eq!(reader.filter(&mut v, 0, &mut stats, &mut fees), 5);
+        assert!(stats.is_empty());
+        assert_eq!(fees, [3, 2, 0, 5]);
+        assert_eq!(v.data[0].0, 2);
+        assert_eq!(v.data[1].0, 3);
+        reader.buffer(&v, &fees);
+        let mut out = data::Messages::new();
+        assert_eq!(reader.fill(&mut out), 5);
+        let fees: Vec<u64> = out.msgs.iter().map(|m| m.pld.fee).collect();
+        assert_eq!(fees, [5, 3, 0, 2, 0]);
+        let lens: Vec<usize> = out.data.iter().map(|d| d.0).collect();
+        assert_eq!(lens, [1, 2, 1, 1]);
+        assert_eq!(reader.fill(&mut out), 0);
+        //without a minimum the forged fee is still taken for none
+        let reader = Reader::new(12015).expect("reader");
+        let mut v = data::Messages::new();
+        v.msgs.clear();
+        v.data.clear();
+        v.msgs.push(forged);
+        v.data.push((1, addr));
+        let mut fees = Vec::new();
+        assert_eq!(reader.filter(&mut v, 0, &mut stats, &mut fees), 1);
+        assert_eq!(fees, [0]);
+    }
+    #[test]
+    fn reader_rate_test() {
+        let mut reader = Reader::new(12004).expect("reader");
+        reader.set_rate(3);
+        let (a, b) = ("127.0.0.1:1".parse().unwrap(), "127.0.0.1:2".parse().unwrap());
+        let c = "127.0.0.1:3".parse().unwrap();
+        let mut w = Wallet::new();
+        w.add_keypair(Wallet::new_keypair());
+        w.add_keypair(Wallet::new_keypair());
+        let k0 = to32b(w.pubkeys[0]);
+        let mut v = data::Messages::new();
+        v.msgs.clear();
+        v.data.clear();
+        for i in 0..4 {
+            v.msgs.push(w.tx(i % 2, [9u8; 32], i as u64 + 1, 0));
+        }
+        //copies of the key that it didn't sign don't use up its tokens
+        for i in 0..2 {
+            let mut m = data::Message::default();
+            m.pld.kind = data::Kind::Transaction;
+            m.pld.from = k0;
+            m.sig[0] = i;
+            v.msgs.push(m);
+        }
+        for i in 4..6 {
+            v.msgs.push(w.tx(i % 2, [9u8; 32], i as u64 + 1, 0));
+        }
+        let q = Wallet::get_stats(k0);
+        v.msgs.push(q);
+        v.data.push((4, a));
+        v.data.push((2, c));
+        v.data.push((3, b));
+        let mut stats = Vec::new();
+        //a sends 4 but only has 3 tokens
+        assert_eq!(reader.filter(&mut v, 0, &mut stats, &mut Vec::new()), 7);
+        assert_eq!(v.data[0].0, 3);
+        assert_eq!(v.data[1].0, 2);
+        assert_eq!(v.data[2].0, 2);
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].1, b);
+        let st = *stats[0].0.pld.get_stats();
+        assert_eq!((st.key_tokens, st.addr_tokens, st.dropped), (0, 0, 1));
+        //the address is out of tokens
+        v.msgs.clear();
+        v.data.clear();
+        v.msgs.push(q);
+        v.data.push((1, b));
+        assert_eq!(reader.filter(&mut v, 0, &mut stats, &mut Vec::new()), 0);
+        assert_eq!(stats.len(), 1);
+        v.msgs.push(q);
+        v.data[0].0 = 1;
+        assert_eq!(reader.filter(&mut v, 1000, &mut stats, &mut Vec::new()), 0);
+        assert_eq!(v.data[0].0, 0);
+        let st = *stats[1].0.pld.get_stats();
+        assert_eq!((st.key_tokens, st.addr_tokens, st.dropped), (3, 2, 2));
+    }
+    #[test]
+    fn reader_in_flight_test() {
+        let reader = Arc::new(Reader::new(12013).expect("reader"));
+        let taken = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let (reader, taken) = (reader.clone(), taken.clone());
+                spawn(move || {
+                    for _ in 0..100 {
+                        if reader.reserve() {
+                            taken.fetch_add(1, Ordering::SeqCst);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("join");
+        }
+        assert_eq!(taken.load(Ordering::SeqCst), MAX_IN_FLIGHT);
+        reader.release();
+        assert!(reader.reserve());
+        assert!(!reader.reserve());
+        //a batch of another reader doesn't free a slot, one that was sent does
+        let other = Reader::new(12014).expect("other");
+        reader.recycle(Data::SharedMessages(other.allocate()));
+        assert!(!reader.reserve());
+        let (p, r) = channel();
+        let ports = vec![p; 8];
+        reader.send(&ports, reader.allocate()).expect("send");
+        match r.try_recv() {
+            Ok(d) => reader.recycle(d),
+            _ => panic!("no batch"),
+        }
+        assert!(reader.reserve());
+    }
+    #[test]
+    fn reader_dedup_test() {
+        let reader = Reader::new(12011).expect("reader");
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let mut w = Wallet::new();
+        w.add_keypair(Wallet::new_keypair());
+        let mut m = w.tx(0, [9u8; 32], 1, 0);
+        //a message that has the signature of another isn't taken for a copy
+        let mut forged = m;
+        forged.pld.get_tx_mut().amount = 2;
+        let mut v = data::Messages::new();
+        v.msgs.clear();
+        v.data.clear();
+        v.msgs.extend_from_slice(&[forged, forged, m, m]);
+        for _ in 0..4 {
+            v.data.push((1, addr));
+        }
+        let mut replies = Vec::new();
+        //the copy of a pending transaction is dropped
+        assert_eq!(reader.filter(&mut v, 0, &mut replies, &mut Vec::new()), 3);
+        assert!(replies.is_empty());
+        assert_eq!(v.msgs[2].sig[..], m.sig[..]);
+        v.data.truncate(3);
+        m.pld.state = data::State::Deposited;
+        //the forged copies come first and still don't get the result
+        v.msgs[2] = m;
+        //every port goes to `r`
+        let (p, r) = channel();
+        let ports = vec![p; 8];
+        let d = Data::SharedMessages(Arc::new(RwLock::new(v)));
+        assert_matches!(reader.acknowledge(&ports, &d), Ok(()));
+        assert_matches!(reader.acknowledge(&ports, &d), Ok(()));
+        match r.try_recv() {
+            Ok(Data::SendMessage(a, b)) => assert_eq!((a.pld.state, b), (m.pld.state, addr)),
+            _ => panic!("no acknowledgement"),
+        }
+        assert!(r.try_recv().is_err());
+        //a copy of an executed one is answered with its result
+        let mut v = data::Messages::new();
+        v.msgs.clear();
+        v.data.clear();
+        m.pld.state = data::State::Unknown;
+        v.msgs.push(m);
+        v.data.push((1, addr));
+        assert_eq!(reader.filter(&mut v, 0, &mut replies, &mut Vec::new()), 0);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].0.pld.state, data::State::Deposited);
+    }
+    #[test]
+    fn reader_validate_test() {
+        let reader = Reader::new(12005).expect("reader");
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let mut v = data::Messages::new();
+        v.msgs.clear();
+        v.data.clear();
+        let mut m = data::Message::default();
+        m.pld.kind = data::Kind::Transaction;
+        let mut bad = vec![m; 6];
+        bad[1].pld.kind = data::Kind::Signature;
+        bad[2].pld.version = 1;
+        bad[3].pld.unused = 1;
+        bad[4].pld.state = data::State::Deposited;
+        bad[5].pld.kind = data::Kind::Invalid;
+        //a part can't start a datagram
+        v.msgs.push(bad[1]);
+        v.data.push((1, addr));
+        for b in bad {
+            v.msgs.push(m);
+            v.msgs.push(b);
+            v.data.push((2, addr));
+        }
+        assert_eq!(reader.validate(&mut v), 4);
+        assert_eq!(v.data.len(), 2);
+        for r in [Reject::Version, Reject::Unused, Reject::State, Reject::Kind].iter() {
+            assert_eq!(reader.rejected(*r), 1);
+        }
+        assert_eq!(reader.rejected(Reject::Orphan), 1);
+
+        //datagrams that don't decode
+        let cli = net::socket().expect("socket");
+        cli.connect("127.0.0.1:12005").expect("connect");
+        let mut buf = [0u8; data::MAX_PACKET];
+        let (size, _) = codec::encode_packet(&[m], &mut buf).expect("encode");
+        cli.send(&buf[..size - 1]).expect("short");
+        buf[0] = codec::VERSION + 1;
+        cli.send(&buf[..size]).expect("version");
+        buf[0] = codec::VERSION;
+        buf[1 + codec::PAYLOAD_SIZE - 4] = 0xff;
+        cli.send(&buf[..size]).expect("kind");
+        let mut num = 0;
+        net::write(&cli, &[m], &mut num).expect("write");
+        let b = Arc::new(RwLock::new(data::Messages::new()));
+        assert_eq!(reader.read(0, b.clone()).expect("read"), 1);
+        assert_eq!(reader.rejected(Reject::Length), 1);
+        assert_eq!(reader.rejected(Reject::Version), 2);
+        assert_eq!(reader.rejected(Reject::Kind), 2);
+    }
+    fn send_msgs(b: Arc<Mutex<bool>>, port: u16) {
+        let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), port);
+        let mut m = data::Message::default();
+        m.pld.kind = data::Kind::Transaction;
         let s = net::socket().unwrap();
+        let mut seq = 0u64;
         loop {
             for _ in [0..10].iter() {
+                //copies of a transaction are dropped
+                seq += 1;
+                m.sig[..8].copy_from_slice(&seq.to_le_bytes());
                 let ms = &[m];
                 let mut num = 0;
                 while num < 1 {
@@ -222,7 +1030,7 @@ mod test {
         let mut threads = vec![Arc::new(None); NUM_THREADS];
         for t in threads.iter_mut() {
             let c_exit = exit.clone();
-            let j = spawn(move || send_msgs(c_exit));
+            let j = spawn(move || send_msgs(c_exit, 12002));
             *t = Arc::new(Some(j));
         }
         let start = SystemTime::now();
@@ -242,4 +1050,52 @@ mod test {
             };
         }
     }
+    /// messages per second read with `f` for `secs` seconds while senders
+    /// flood `port`
+    fn read_rate<F>(sock: &UdpSocket, port: u16, secs: u64, f: F) -> f64
+    where
+        F: Fn(&UdpSocket, &mut Vec<data::Message>, &mut Vec<(usize, SocketAddr)>)
+            -> Result<usize>,
+    {
+        const NUM_THREADS: usize = 2;
+        let exit = Arc::new(Mutex::new(false));
+        let threads: Vec<_> = (0..NUM_THREADS)
+            .map(|_| {
+                let c_exit = exit.clone();
+                spawn(move || send_msgs(c_exit, port))
+            })
+            .collect();
+        let mut v = data::Messages::new();
+        let mut count = 0;
+        let start = SystemTime::now();
+        while start.elapsed().unwrap() < Duration::new(secs, 0) {
+            v.msgs.resize(1024, data::Message::default());
+            v.data.resize(1024, data::Messages::def_data());
+            if let Ok(n) = v.with(|m, d| f(sock, m, d)) {
+                count += v.data[..n].iter().map(|d| d.0).sum::<usize>();
+            }
+        }
+        let elapsed = start.elapsed().unwrap();
+        *exit.lock().unwrap() = true;
+        for j in threads {
+            j.join().unwrap();
+        }
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        count as f64 / secs
+    }
+    /// run with `cargo test mmsg_bench -- --ignored --nocapture`
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore]
+    fn mmsg_bench() {
+        let sock = net::bindall(12007).expect("bind");
+        sock.set_read_timeout(Some(Duration::new(1, 0))).expect("timeout");
+        let each = read_rate(&sock, 12007, 2, |s, m, d| {
+            net::recv_each(s, m, d, |_, _| ())
+        });
+        let mmsg = read_rate(&sock, 12007, 2, |s, m, d| {
+            mmsg::recv_mmsg(s, m, d, &mut [], |_, _| ())
+        });
+        println!("recv_from: {:?} recvmmsg: {:?}", each, mmsg);
+    }
 }
@@ -21,6 +21,30 @@ pub enum Error {
     NoSpace,
     ToLarge,
     PubKeyNotFound,
+    InvalidGroup,
+    SupplyMismatch,
+    /// packet with a wire format version that isn't `codec::VERSION`
+    UnknownVersion(u8),
+    /// packet or message with a size that doesn't match the wire format
+    InvalidLength(usize),
+    InvalidKind(u8),
+    InvalidState(u8),
+    /// mapped table file with a header that doesn't match, see `table::VERSION`
+    InvalidTable,
+    /// mapped state that stopped in the middle of the batch after this height,
+    /// its tables can be ahead of its counters, see `State::begin_batch`
+    UnfinishedBatch(u64),
+    /// a faucet that isn't the one the state already has, see `State::set_faucet`
+    FaucetMismatch,
+    /// state of shard `.0` out of `.1`, opened as another one, see `State::set_shard`
+    ShardMismatch(u64, u64),
+    /// wallet key made from a 64 byte seed, which can't sign, see `Wallet::new_keypair`
+    OldKeypair(usize),
+    /// no reply after every retransmission, see `client::request`
+    Timeout,
+    /// the transaction was executed without a transfer, the balance was short
+    /// or an account didn't match
+    Rejected,
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
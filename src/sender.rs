@@ -1,27 +1,204 @@
 use result::Result;
-use std::net::UdpSocket;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use net;
+use data::{Message, MAX_BATCH};
 use otp::Data;
+use tcp::Tcp;
 
+/// clients remembered before the table starts over
+const MAX_ROUTES: usize = 64 * 1024;
+/// milliseconds a reply waits for more replies to the same client
+const DEADLINE_MS: u64 = 1;
+
+/// the local address that each client sent its last datagram to. a socket
+/// bound to the unspecified address would reply from the address the kernel
+/// picks, which a connected socket or a NAT drops if it isn't the one the
+/// client sent to
+#[derive(Default)]
+pub struct Routes {
+    map: Mutex<HashMap<SocketAddr, IpAddr>>,
+}
+
+impl Routes {
+    /// remember the local address of each datagram in `data`
+    pub fn add(&self, data: &[(usize, SocketAddr)], local: &[Option<IpAddr>]) {
+        let mut map = self.map.lock().unwrap();
+        for (d, l) in data.iter().zip(local.iter()) {
+            if let Some(l) = *l {
+                map.insert(d.1, l);
+            }
+        }
+        if map.len() > MAX_ROUTES {
+            //the next datagram of each client adds it back
+            map.clear();
+        }
+    }
+    pub fn get(&self, client: &SocketAddr) -> Option<IpAddr> {
+        self.map.lock().unwrap().get(client).cloned()
+    }
+}
+
+/// replies to the same client that are queued together
+struct Queued {
+    msgs: Vec<Message>,
+    /// the insert sequence of the queue, see `Queues::order`
+    seq: u64,
+}
+
+#[derive(Default)]
+struct Queues {
+    map: HashMap<SocketAddr, Queued>,
+    /// when each queue was started, with its sequence and client, oldest first.
+    /// the queues that were sent because they filled up are skipped
+    order: VecDeque<(Instant, u64, SocketAddr)>,
+    seq: u64,
+}
+
+/// replies are sent together in as few datagrams as possible, from a queue for
+/// each client that is flushed once it fills a packet or after `DEADLINE_MS`
 pub struct Sender {
     s: UdpSocket,
+    tcp: Option<Arc<Tcp>>,
+    routes: Option<Arc<Routes>>,
+    queues: Mutex<Queues>,
 }
 impl Sender {
     pub fn new(sock: UdpSocket) -> Sender {
-        Sender { s: sock }
+        Sender {
+            s: sock,
+            tcp: None,
+            routes: None,
+            queues: Mutex::new(Queues::default()),
+        }
+    }
+    /// reply from the local address each client sent to, if the socket is
+    /// bound to the unspecified address
+    pub fn set_routes(&mut self, routes: Arc<Routes>) -> Result<()> {
+        if self.s.local_addr()?.ip().is_unspecified() {
+            self.routes = Some(routes);
+        }
+        Ok(())
+    }
+    /// reply over tcp to the addresses that are connected to `tcp`
+    pub fn set_tcp(&mut self, tcp: Arc<Tcp>) {
+        self.tcp = Some(tcp);
+    }
+    fn tcp(&self, msgs: &[Message], a: SocketAddr) -> Result<bool> {
+        match self.tcp {
+            Some(ref t) => t.send(msgs, a),
+            None => Ok(false),
+        }
+    }
+    fn send(&self, msgs: &[Message], a: SocketAddr) -> Result<()> {
+        let from = self.routes.as_ref().and_then(|r| r.get(&a));
+        let mut num = 0;
+        while num < msgs.len() {
+            net::send_from(&self.s, msgs, &mut num, a, from)?;
+        }
+        Ok(())
+    }
+    /// queue `msgs` for `a`, they are never split over two datagrams unless
+    /// they don't fit in one
+    fn queue(&self, msgs: &[Message], a: SocketAddr) -> Result<()> {
+        if self.tcp(msgs, a)? {
+            return Ok(());
+        }
+        let mut guard = self.queues.lock().unwrap();
+        let queues = &mut *guard;
+        let full = queues
+            .map
+            .get(&a)
+            .map(|q| q.msgs.len() + msgs.len() > MAX_BATCH)
+            .unwrap_or(false);
+        if full {
+            let q = queues.map.remove(&a).expect("queue");
+            self.send(&q.msgs, a)?;
+        }
+        if msgs.len() >= MAX_BATCH {
+            return self.send(msgs, a);
+        }
+        let (order, seq) = (&mut queues.order, &mut queues.seq);
+        let q = queues.map.entry(a).or_insert_with(|| {
+            *seq += 1;
+            order.push_back((Instant::now(), *seq, a));
+            Queued {
+                msgs: Vec::with_capacity(MAX_BATCH),
+                seq: *seq,
+            }
+        });
+        q.msgs.extend_from_slice(msgs);
+        if q.msgs.len() == MAX_BATCH {
+            let q = queues.map.remove(&a).expect("queue");
+            self.send(&q.msgs, a)?;
+        }
+        Ok(())
+    }
+    /// send the queues that waited for `DEADLINE_MS`
+    fn flush(&self) -> Result<()> {
+        let mut queues = self.queues.lock().unwrap();
+        let deadline = Duration::from_millis(DEADLINE_MS);
+        while let Some(&(since, seq, a)) = queues.order.front() {
+            if since.elapsed() < deadline {
+                break;
+            }
+            queues.order.pop_front();
+            if queues.map.get(&a).map(|q| q.seq) == Some(seq) {
+                let q = queues.map.remove(&a).expect("queue");
+                self.send(&q.msgs, a)?;
+            }
+        }
+        Ok(())
     }
 
+    /// queue the replies in `d`, a `Data::Signal` only flushes the queues,
+    /// see `OTP::listen_idle`
     pub fn run(&self, d: Data) -> Result<()> {
         match d {
-            Data::SendMessage(m, a) => {
-                let msgs = [m];
-                let mut num = 0;
-                while num < 1 {
-                    net::send_to(&self.s, &msgs, &mut num, a)?;
-                }
-            }
+            Data::SendMessage(m, a) => self.queue(&[m], a)?,
+            Data::SendMessages(msgs, a) => self.queue(&msgs, a)?,
             _ => (),
         }
-        Ok(())
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data;
+    use net;
+    use otp::Data;
+    use sender::Sender;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn sender_test() {
+        let srv = net::bindall(12012).expect("bind");
+        let addr = "127.0.0.1:12012".parse().expect("addr");
+        let s = Sender::new(net::socket().expect("socket"));
+        let mut m = data::Message::default();
+        m.pld.kind = data::Kind::GetBalance;
+        for _ in 0..data::MAX_BATCH + 2 {
+            s.run(Data::SendMessage(m, addr)).expect("send");
+        }
+        //a group isn't split between datagrams
+        let mut g = vec![m; 20];
+        for p in g[1..].iter_mut() {
+            p.pld.kind = data::Kind::BalanceKey;
+        }
+        s.run(Data::SendMessages(g, addr)).expect("send");
+        sleep(Duration::new(0, 10_000_000));
+        s.run(Data::Signal).expect("flush");
+        let mut got = Vec::new();
+        srv.set_read_timeout(Some(Duration::new(1, 0))).expect("timeout");
+        while got.len() < 3 {
+            let mut v = data::Messages::new();
+            let n = v.with_mut(|m, d| net::read_from(&srv, m, d)).expect("read");
+            got.extend(v.data[..n].iter().map(|d| d.0));
+        }
+        assert_eq!(got, [data::MAX_BATCH, 2, 20]);
     }
 }
@@ -0,0 +1,587 @@
+//! the accounts split into shards by key prefix, each shard is a `State`
+//! owned by its own OTP worker on `Port::Shard(i)`.
+//!
+//! the `Router` on `Port::State` runs every batch in two phases. in the debit
+//! phase each shard charges the senders it owns and queues a credit for every
+//! recipient, in the credit phase each shard pays the credits it was sent.
+//! a recipient in the same shard is paid in the credit phase too, so funds
+//! received in a batch can't be spent in it wherever the accounts are.
+//! in the settle phase each shard gives back what it took for a credit that
+//! failed and ends the batch. a phase starts once every shard is done with
+//! the previous one, so the shards see the batches in the same order.
+//!
+//! a shard that fails is logged and counted by the router, which keeps
+//! going. the groups the shard didn't get to are answered in
+//! `State::Unknown`, and so are the ones it couldn't pay, which are refunded.
+//!
+//! only single message `Transaction` and `GetBalance` groups are executed and
+//! no history is kept. the router answers every other group with its head in
+//! `State::Unknown` and counts it, those need an unsharded `State`.
+
+use data;
+use hasht::Key;
+use otp::{Data, Port, Ports, OTP};
+use result::Result;
+use state::State;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use data_encoding::HEXLOWER;
+use std::mem::replace;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+/// the shard out of `num` that owns `key`
+pub fn shard_of(key: &[u8; 32], num: usize) -> usize {
+    ((key.start() as u64 as u128 * num as u128) >> 64) as usize
+}
+
+/// work left for the shard of the recipient of message `ix`
+#[derive(Clone, Copy)]
+enum Credit {
+    /// pay the transaction of message `ix`
+    Deposit(usize),
+    /// answer the `GetBalance` of message `ix`
+    Balance(usize, SocketAddr),
+}
+
+impl Credit {
+    fn ix(&self) -> usize {
+        match *self {
+            Credit::Deposit(ix) | Credit::Balance(ix, _) => ix,
+        }
+    }
+}
+
+/// a batch that is being executed by the shards
+pub struct Batch {
+    msgs: data::SharedMessages,
+    /// credits for each shard from the debit phase
+    credits: Vec<Mutex<Vec<Credit>>>,
+    /// messages that each shard debited but that couldn't be paid
+    refunds: Vec<Mutex<Vec<usize>>>,
+    /// state hash of each shard after the batch
+    hashes: Mutex<Vec<[u8; 32]>>,
+    done: Mutex<Sender<Result<()>>>,
+}
+
+impl Batch {
+    fn ack(&self, r: Result<()>) {
+        let _ = self.done.lock().unwrap().send(r);
+    }
+}
+
+/// true if the shards execute `group`
+fn supported(group: &[data::Message]) -> bool {
+    let kind = group[0].pld.kind;
+    group.len() == 1 && (kind == data::Kind::Transaction || kind == data::Kind::GetBalance)
+}
+
+pub struct Router {
+    num: usize,
+    /// groups that were answered without being executed
+    unsupported: AtomicUsize,
+    /// phases that a shard failed
+    failures: AtomicUsize,
+}
+
+impl Router {
+    pub fn new(num: usize) -> Router {
+        Router {
+            num,
+            unsupported: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+        }
+    }
+    /// groups that were answered without being executed since the start
+    pub fn unsupported(&self) -> usize {
+        self.unsupported.load(Ordering::Relaxed)
+    }
+    /// phases that a shard failed since the start
+    pub fn failures(&self) -> usize {
+        self.failures.load(Ordering::Relaxed)
+    }
+    /// answer the groups of the batch that the shards can't execute with
+    /// their head in `State::Unknown`, so the clients don't retransmit them
+    fn reject(&self, p: &Ports, ms: &data::Messages) -> Result<()> {
+        let mut total = 0;
+        for &(z, a) in ms.data.iter() {
+            let mut ix = total;
+            while ix < total + z {
+                let num = data::group_len(&ms.msgs[ix..total + z]);
+                let group = &ms.msgs[ix..ix + num];
+                ix += num;
+                if supported(group) {
+                    continue;
+                }
+                self.unsupported.fetch_add(1, Ordering::Relaxed);
+                let mut head = group[0];
+                head.pld.state = data::State::Unknown;
+                OTP::send(p, Port::Sender, Data::SendMessage(head, a))?;
+            }
+            total += z;
+        }
+        Ok(())
+    }
+    pub fn run(&self, p: &Ports, d: Data) -> Result<()> {
+        if let Data::SharedMessages(m) = d {
+            self.reject(p, &m.read().unwrap())?;
+            let (s, r) = channel();
+            let b = Arc::new(Batch {
+                msgs: m.clone(),
+                credits: (0..self.num).map(|_| Mutex::new(Vec::new())).collect(),
+                refunds: (0..self.num).map(|_| Mutex::new(Vec::new())).collect(),
+                hashes: Mutex::new(vec![[0u8; 32]; self.num]),
+                done: Mutex::new(s),
+            });
+            self.phase(p, &r, &b, Data::Debit)?;
+            self.phase(p, &r, &b, Data::Credit)?;
+            self.phase(p, &r, &b, Data::Settle)?;
+            let mut h = Sha256::new();
+            for s in b.hashes.lock().unwrap().iter() {
+                h.input(s);
+            }
+            {
+                let mut v = m.write().unwrap();
+                h.result(&mut v.hash);
+                debug!("batch {} hash {}", v.height, HEXLOWER.encode(&v.hash));
+            }
+            OTP::send(p, Port::Recycle, Data::SharedMessages(m))?;
+        }
+        Ok(())
+    }
+    /// run one phase of `b` on every shard and wait for all of them
+    fn phase(
+        &self,
+        p: &Ports,
+        r: &Receiver<Result<()>>,
+        b: &Arc<Batch>,
+        phase: fn(Arc<Batch>) -> Data,
+    ) -> Result<()> {
+        for i in 0..self.num {
+            OTP::send(p, Port::Shard(i), phase(b.clone()))?;
+        }
+        for _ in 0..self.num {
+            if let Err(e) = r.recv()? {
+                error!("shard failed {:?}", e);
+                self.failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Shard {
+    id: usize,
+    num: usize,
+    state: State,
+    /// hash of the batch in flight
+    hash: Sha256,
+}
+
+impl Shard {
+    pub fn new(id: usize, num: usize, state: State) -> Shard {
+        Shard {
+            id,
+            num,
+            state,
+            hash: Sha256::new(),
+        }
+    }
+    pub fn run(&mut self, p: &Ports, d: Data) -> Result<()> {
+        match d {
+            Data::Debit(b) => {
+                let r = self.debit(&b);
+                b.ack(r);
+            }
+            Data::Credit(b) => {
+                let r = self.credit(p, &b);
+                b.ack(r);
+            }
+            Data::Settle(b) => {
+                let r = self.settle(&b);
+                b.ack(r);
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+    fn debit(&mut self, b: &Batch) -> Result<()> {
+        let mut out = Vec::new();
+        let mut failed = None;
+        {
+            let ms = b.msgs.read().unwrap();
+            match self.state.begin_batch(ms.height, ms.time) {
+                Ok(h) => self.hash = h,
+                Err(e) => failed = Some(e),
+            }
+            let mut total = 0;
+            for &(z, a) in ms.data.iter() {
+                let mut ix = total;
+                while ix < total + z {
+                    let num = data::group_len(&ms.msgs[ix..total + z]);
+                    let m = &ms.msgs[ix];
+                    ix += num;
+                    if shard_of(&m.pld.from, self.num) != self.id {
+                        continue;
+                    }
+                    //once the shard failed the rest of its groups aren't executed
+                    if failed.is_some() {
+                        out.push((ix - num, num, data::State::Unknown));
+                        continue;
+                    }
+                    let st = if supported(&ms.msgs[ix - num..ix]) {
+                        self.debit_msg(b, ix - num, m, a)
+                    } else {
+                        Ok(data::State::Unknown)
+                    };
+                    let st = st.unwrap_or_else(|e| {
+                        failed = Some(e);
+                        data::State::Unknown
+                    });
+                    out.push((ix - num, num, st));
+                    h_state(&mut self.hash, st);
+                    if let Err(e) = self.state.hash_key(&mut self.hash, &m.pld.from) {
+                        failed = Some(e);
+                    }
+                }
+                total += z;
+            }
+        }
+        let mut ms = b.msgs.write().unwrap();
+        for (ix, num, st) in out {
+            for m in &mut ms.msgs[ix..ix + num] {
+                m.pld.state = data::State::Unknown;
+            }
+            ms.msgs[ix].pld.state = st;
+        }
+        failed.map_or(Ok(()), Err)
+    }
+    fn debit_msg(
+        &mut self,
+        b: &Batch,
+        ix: usize,
+        m: &data::Message,
+        a: SocketAddr,
+    ) -> Result<data::State> {
+        let (to, credit, amount) = match m.pld.kind {
+            data::Kind::Transaction => {
+                let tx = m.pld.get_tx();
+                (tx.to, Credit::Deposit(ix), tx.amount)
+            }
+            data::Kind::GetBalance => (m.pld.get_bal().key, Credit::Balance(ix, a), 0),
+            _ => return Ok(data::State::Unknown),
+        };
+        if to.unused() || !self.state.debit(&m.pld.from, amount, m.pld.fee)? {
+            return Ok(data::State::Unknown);
+        }
+        b.credits[shard_of(&to, self.num)].lock().unwrap().push(credit);
+        Ok(data::State::Withdrawn)
+    }
+    fn credit(&mut self, p: &Ports, b: &Batch) -> Result<()> {
+        let mut credits: Vec<Credit> = b.credits[self.id].lock().unwrap().drain(..).collect();
+        //the shards queue them concurrently, keep the hash deterministic
+        credits.sort_by_key(|c| c.ix());
+        let mut out = Vec::new();
+        let mut failed = None;
+        {
+            let ms = b.msgs.read().unwrap();
+            for c in credits {
+                let ix = c.ix();
+                let m = &ms.msgs[ix];
+                let st = self.pay(p, m, c).unwrap_or_else(|e| {
+                    //the shard of the sender gives it back in the settle phase
+                    b.refunds[shard_of(&m.pld.from, self.num)]
+                        .lock()
+                        .unwrap()
+                        .push(ix);
+                    failed = Some(e);
+                    data::State::Unknown
+                });
+                h_state(&mut self.hash, st);
+                if st == data::State::Deposited {
+                    if let Err(e) = self.state.hash_key(&mut self.hash, &m.pld.get_tx().to) {
+                        failed = Some(e);
+                    }
+                }
+                out.push((ix, st));
+            }
+        }
+        let mut ms = b.msgs.write().unwrap();
+        for (ix, st) in out {
+            ms.msgs[ix].pld.state = st;
+        }
+        failed.map_or(Ok(()), Err)
+    }
+    /// refund the messages this shard debited that couldn't be paid, then
+    /// end the batch
+    fn settle(&mut self, b: &Batch) -> Result<()> {
+        let mut refunds: Vec<usize> = b.refunds[self.id].lock().unwrap().drain(..).collect();
+        refunds.sort();
+        let mut failed = None;
+        {
+            let ms = b.msgs.read().unwrap();
+            for ix in refunds {
+                let m = &ms.msgs[ix];
+                let amount = match m.pld.kind {
+                    data::Kind::Transaction => m.pld.get_tx().amount,
+                    _ => 0,
+                };
+                let r = self.state
+                    .refund(&m.pld.from, amount, m.pld.fee)
+                    .and_then(|_| self.state.hash_key(&mut self.hash, &m.pld.from));
+                if let Err(e) = r {
+                    error!("message {} can't be refunded {} {:?}", ix, amount, e);
+                    failed = Some(e);
+                }
+            }
+        }
+        let h = replace(&mut self.hash, Sha256::new());
+        let r = self.state.end_batch(h);
+        b.hashes.lock().unwrap()[self.id] = self.state.hash();
+        failed.map_or(r, Err)
+    }
+    /// the part of `m` that belongs to the shard of its recipient
+    fn pay(&mut self, p: &Ports, m: &data::Message, c: Credit) -> Result<data::State> {
+        match c {
+            Credit::Deposit(_) => {
+                let tx = m.pld.get_tx();
+                self.state.credit(&tx.to, tx.amount)?;
+                Ok(data::State::Deposited)
+            }
+            Credit::Balance(_, a) => {
+                let key = m.pld.get_bal().key;
+                if let Some(amount) = self.state.balance(&key)? {
+                    let mut r = *m;
+                    r.pld.state = data::State::Withdrawn;
+                    r.pld.get_bal_mut().amount = amount;
+                    OTP::send(p, Port::Sender, Data::SendMessage(r, a))?;
+                }
+                Ok(data::State::Withdrawn)
+            }
+        }
+    }
+}
+
+fn h_state(h: &mut Sha256, st: data::State) {
+    h.input(&[st as u8]);
+}
+
+#[cfg(test)]
+mod tests {
+    use data;
+    use otp::{Data, Port, OTP};
+    use shard::{shard_of, Router, Shard};
+    use state::State;
+    use std::sync::{Arc, Mutex, RwLock};
+
+    #[test]
+    fn shard_of_test() {
+        assert_eq!(shard_of(&[0u8; 32], 4), 0);
+        assert_eq!(shard_of(&[0x40u8; 32], 4), 1);
+        assert_eq!(shard_of(&[0xffu8; 32], 4), 3);
+        assert_eq!(shard_of(&[0xffu8; 32], 1), 0);
+    }
+    /// a shard for each of `lists`
+    fn shards(lists: &[&[data::Account]]) -> Vec<Arc<Mutex<Shard>>> {
+        lists
+            .iter()
+            .enumerate()
+            .map(|(i, l)| {
+                let s = State::from_list(l).expect("from list");
+                Arc::new(Mutex::new(Shard::new(i, lists.len(), s)))
+            })
+            .collect()
+    }
+    /// a batch with a transaction for each of `txs`
+    fn txs(txs: &[([u8; 32], [u8; 32], u64)]) -> data::Messages {
+        let mut msgs = data::Messages::new();
+        msgs.with_mut(|m, d| {
+            for (m, &(from, to, amount)) in m.iter_mut().zip(txs.iter()) {
+                m.pld.kind = data::Kind::Transaction;
+                m.pld.from = from;
+                m.pld.fee = 1;
+                m.pld.data = data::MessageData {
+                    tx: data::Transaction { to, amount },
+                };
+            }
+            d[0].0 = txs.len();
+            Ok(())
+        }).expect("init");
+        msgs
+    }
+    /// the states of the first `num` messages of `ms`
+    fn states(ms: &data::Messages, num: usize) -> Vec<data::State> {
+        ms.msgs[..num].iter().map(|m| m.pld.state).collect()
+    }
+    /// execute `batches` in order with a router for `shards`, returns the
+    /// router, the executed batches and the replies
+    fn execute(
+        shards: &[Arc<Mutex<Shard>>],
+        batches: Vec<data::Messages>,
+    ) -> (Arc<Router>, Vec<data::SharedMessages>, Vec<data::Message>) {
+        let mut o = OTP::with_shards(shards.len());
+        for (i, s) in shards.iter().enumerate() {
+            let s = s.clone();
+            assert_matches!(
+                o.listen(Port::Shard(i), move |p, d| s.lock().unwrap().run(p, d)),
+                Ok(())
+            );
+        }
+        let router = Arc::new(Router::new(shards.len()));
+        let a_router = router.clone();
+        assert_matches!(o.listen(Port::State, move |p, d| a_router.run(p, d)), Ok(()));
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let c_replies = replies.clone();
+        assert_matches!(
+            o.listen(Port::Sender, move |_p, d| {
+                if let Data::SendMessage(m, _) = d {
+                    c_replies.lock().unwrap().push(m);
+                }
+                Ok(())
+            }),
+            Ok(())
+        );
+        let num = batches.len();
+        let pending = Arc::new(Mutex::new(batches.into_iter().rev().collect::<Vec<_>>()));
+        let c_pending = pending.clone();
+        assert_matches!(
+            o.source(Port::Reader, move |p| match c_pending.lock().unwrap().pop() {
+                Some(m) => OTP::send(
+                    p,
+                    Port::State,
+                    Data::SharedMessages(Arc::new(RwLock::new(m)))
+                ),
+                None => Ok(()),
+            }),
+            Ok(())
+        );
+        let done = Arc::new(Mutex::new(Vec::new()));
+        let c_done = done.clone();
+        assert_matches!(
+            o.listen(Port::Recycle, move |p, d| {
+                let mut v = c_done.lock().unwrap();
+                if let Data::SharedMessages(m) = d {
+                    v.push(m);
+                }
+                if v.len() == num {
+                    OTP::send(p, Port::Main, Data::Signal)?;
+                }
+                Ok(())
+            }),
+            Ok(())
+        );
+        assert_matches!(o.join(), Ok(()));
+        let done = done.lock().unwrap().drain(..).collect();
+        let replies = replies.lock().unwrap().drain(..).collect();
+        (router, done, replies)
+    }
+    #[test]
+    fn shard_test() {
+        let (a, b) = ([0x10u8; 32], [0x90u8; 32]);
+        let shards = shards(&[
+            &[
+                data::Account {
+                    from: a,
+                    balance: 100,
+                },
+            ],
+            &[
+                data::Account {
+                    from: b,
+                    balance: 100,
+                },
+            ],
+        ]);
+        let mut msgs = txs(&[
+            (a, b, 10),
+            (b, a, 20),
+            (a, [0x11u8; 32], 5),
+            (b, a, 500),
+            //paid in the credit phase, it can't be spent before then
+            ([0x11u8; 32], a, 1),
+        ]);
+        msgs.with_mut(|m, d| {
+            m[5].pld.kind = data::Kind::GetBalance;
+            m[5].pld.from = a;
+            m[5].pld.fee = 1;
+            m[5].pld.data = data::MessageData {
+                bal: data::GetBalance { key: b, amount: 0 },
+            };
+            //the shards don't keep history
+            m[6].pld.kind = data::Kind::GetHistory;
+            m[6].pld.from = a;
+            m[6].pld.fee = 1;
+            d[0].0 = 7;
+            Ok(())
+        }).expect("init");
+        let (router, done, replies) = execute(&shards, vec![msgs]);
+
+        let ms = done[0].read().unwrap();
+        assert_eq!(
+            states(&ms, 7),
+            [
+                data::State::Deposited,
+                data::State::Deposited,
+                data::State::Deposited,
+                data::State::Unknown,
+                data::State::Unknown,
+                data::State::Withdrawn,
+                data::State::Unknown,
+            ]
+        );
+        assert!(ms.hash != [0u8; 32]);
+        assert_eq!(router.unsupported(), 1);
+        assert_eq!(router.failures(), 0);
+        let h = replies
+            .iter().find(|m| m.pld.kind == data::Kind::GetHistory).expect("reply");
+        assert_eq!(h.pld.state, data::State::Unknown);
+        let mut sa = shards[0].lock().unwrap();
+        let mut sb = shards[1].lock().unwrap();
+        assert_eq!(sa.state.balance(&a).expect("a"), Some(100 - 11 + 20 - 6 - 1));
+        assert_eq!(sa.state.balance(&[0x11u8; 32]).expect("c"), Some(5));
+        assert_eq!(sb.state.balance(&b).expect("b"), Some(100 - 21 + 10));
+        assert_eq!(sa.state.supply() + sb.state.supply(), 200);
+        assert_eq!(sa.state.fees() + sb.state.fees(), 4);
+        sa.state.audit().expect("audit a");
+        sb.state.audit().expect("audit b");
+    }
+    #[test]
+    fn shard_failure_test() {
+        let (a, b, c) = ([0x10u8; 32], [0x90u8; 32], [0x91u8; 32]);
+        //a deposit to b overflows its balance
+        let shards = shards(&[
+            &[
+                data::Account {
+                    from: a,
+                    balance: 100,
+                },
+            ],
+            &[
+                data::Account {
+                    from: b,
+                    balance: u64::MAX - 5,
+                },
+            ],
+        ]);
+        let batches = vec![txs(&[(a, b, 10), (a, c, 3)]), txs(&[(b, a, 20)])];
+        let (router, done, _) = execute(&shards, batches);
+
+        assert_eq!(router.failures(), 1);
+        let states: Vec<_> = done.iter().map(|m| states(&m.read().unwrap(), 2)).collect();
+        assert_eq!(states[0], [data::State::Unknown, data::State::Deposited]);
+        //the router kept going
+        assert_eq!(states[1][0], data::State::Deposited);
+        let mut sa = shards[0].lock().unwrap();
+        let mut sb = shards[1].lock().unwrap();
+        //the failed transfer was refunded with its fee
+        assert_eq!(sa.state.balance(&a).expect("a"), Some(100 - 4 + 20));
+        assert_eq!(sb.state.balance(&b).expect("b"), Some(u64::MAX - 5 - 21));
+        assert_eq!(sb.state.balance(&c).expect("c"), Some(3));
+        assert_eq!(sa.state.supply(), 100 - 3 + 20);
+        assert_eq!(sa.state.fees() + sb.state.fees(), 2);
+        sa.state.audit().expect("audit a");
+        sb.state.audit().expect("audit b");
+    }
+}
@@ -1,49 +1,361 @@
 //! state machine for transactions
 
 use data;
-use result::Result;
-use hasht::Key;
+use result::{Error, Result};
+use hasht::{HashT, Key, Val};
+use history::History;
+use faucet::{self, Faucet};
 use otp::{Data, Port, Ports, OTP};
+use table::{Mapped, Table};
+use wallet;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use data_encoding::HEXLOWER;
+use std::cmp::{max, min};
 use std::net::SocketAddr;
+use std::mem::replace;
+
+/// number of old table slots moved into the new table per message while resizing
+const MIGRATE_STEP: usize = 64;
+
+/// the counters of the state, kept next to the tables when they are mapped.
+/// they are written after the tables are synced at the end of every batch,
+/// so they are the commit record of the tables
+#[derive(Default, Copy, Clone)]
+#[repr(C)]
+struct Meta {
+    height: u64,
+    time: u64,
+    fees: u64,
+    hash: [u8; 32],
+    /// 1 while a batch is executed, the tables on disk can then be ahead of
+    /// the counters, see `State::begin_batch`
+    busy: u64,
+    /// index of the shard and number of shards, see `State::set_shard`
+    shard: u64,
+    shards: u64,
+}
 
 #[repr(C)]
 pub struct State {
-    accounts: Vec<data::Account>,
+    accounts: Table<data::Account>,
+    /// previous table while a resize is in flight, empty otherwise
+    old: Table<data::Account>,
+    /// next slot in `old` to migrate
+    moved: usize,
     used: usize,
+    min: usize,
+    /// multisig account records
+    multisig: Table<data::MultiSigAccount>,
+    multisig_used: usize,
+    /// funds held by pending escrows
+    escrows: Table<data::Escrow>,
+    escrows_used: usize,
+    /// where the counters are written by `flush`
+    meta: Table<Meta>,
+    /// PoH height of the current batch, see `data::Messages::height`
+    height: u64,
+    /// unix time of the current batch, see `data::Messages::time`
+    time: u64,
+    /// coins in existence, balances plus escrows plus `fees`
+    supply: u64,
+    /// fees collected since the state was created or opened
+    fees: u64,
+    /// run `audit` after every batch
+    audit: bool,
+    /// audits that failed since the state was created or opened
+    failures: u64,
+    /// rolling hash of the changes of every executed batch
+    hash: [u8; 32],
+    /// height of the batch that `hash` is from
+    hashed: u64,
+    /// transfers of each account, kept in memory only
+    history: History,
+    /// the one faucet, its key is unused if there is none
+    faucet: Table<Faucet>,
+    /// index of the shard and number of shards, zero until they are set
+    shard: u64,
+    shards: u64,
 }
 
 impl State {
     pub fn new(size: usize) -> State {
+        Self::with_tables(
+            Table::new(size),
+            Table::new(0),
+            Table::new(0),
+            Table::new(1),
+            Table::new(1),
+        )
+    }
+    fn with_tables(
+        accounts: Table<data::Account>,
+        multisig: Table<data::MultiSigAccount>,
+        escrows: Table<data::Escrow>,
+        meta: Table<Meta>,
+        faucet: Table<Faucet>,
+    ) -> State {
+        let size = accounts.len();
         State {
-            accounts: vec![data::Account::default(); size],
+            accounts,
+            old: Table::new(0),
+            moved: 0,
             used: 0,
+            min: size,
+            multisig,
+            multisig_used: 0,
+            escrows,
+            escrows_used: 0,
+            meta,
+            height: 0,
+            time: 0,
+            supply: 0,
+            fees: 0,
+            audit: cfg!(debug_assertions),
+            failures: 0,
+            hash: [0u8; 32],
+            hashed: 0,
+            history: History::new(),
+            faucet,
+            shard: 0,
+            shards: 0,
         }
     }
     pub fn from_list(v: &[data::Account]) -> Result<State> {
         let mut s = Self::new(v.len() * 2);
+        s.seed(v)?;
+        Ok(s)
+    }
+    /// open the memory-mapped account table at `path`, or create it with
+    /// `size` slots seeded from `list` if it doesn't exist yet. the multisig
+    /// records, the escrows, the counters and the faucet are kept in
+    /// `<path>.multisig`, `<path>.escrows`, `<path>.meta` and `<path>.faucet`.
+    /// a resize that was interrupted by a restart is finished before returning,
+    /// a state that stopped in the middle of a batch can't be opened.
+    pub fn open(path: &str, size: usize, list: &[data::Account]) -> Result<State> {
+        let (mp, ep, tp, fp) = Self::paths(path);
+        if !Table::<data::Account>::exists(path) {
+            //the accounts are created last, once they exist so do the others
+            let multisig = Table::Mapped(Mapped::create(&mp, 0)?);
+            let escrows = Table::Mapped(Mapped::create(&ep, 0)?);
+            let meta = Table::Mapped(Mapped::create(&tp, 1)?);
+            let faucet = Table::Mapped(Mapped::create(&fp, 1)?);
+            let accounts = Table::Mapped(Mapped::create(path, size)?);
+            let mut s = Self::with_tables(accounts, multisig, escrows, meta, faucet);
+            s.seed(list)?;
+            s.flush()?;
+            return Ok(s);
+        }
+        let mut s = Self::with_tables(
+            Table::Mapped(Mapped::open(path)?),
+            Table::Mapped(Mapped::open(&mp)?),
+            Table::Mapped(Mapped::open(&ep)?),
+            Table::Mapped(Mapped::open(&tp)?),
+            Table::Mapped(Mapped::open(&fp)?),
+        );
+        if s.meta.len() != 1 || s.faucet.len() != 1 {
+            return Err(Error::InvalidTable);
+        }
+        let meta = s.meta[0];
+        if meta.busy != 0 {
+            return Err(Error::UnfinishedBatch(meta.height));
+        }
+        s.height = meta.height;
+        s.time = meta.time;
+        s.fees = meta.fees;
+        s.hash = meta.hash;
+        s.hashed = meta.height;
+        s.shard = meta.shard;
+        s.shards = meta.shards;
+        let resize = Table::<data::Account>::resize_path(path);
+        if Table::<data::Account>::exists(&resize) {
+            let new = Table::Mapped(Mapped::open(&resize)?);
+            s.old = replace(&mut s.accounts, new);
+            while !s.old.is_empty() {
+                s.migrate_step()?;
+            }
+            s.flush()?;
+        }
+        s.used = s.accounts.iter().filter(|a| !a.from.unused()).count();
+        s.min = min(size, s.accounts.len());
+        s.multisig_used = s.multisig.iter().filter(|m| !m.key.unused()).count();
+        s.escrows_used = s.escrows.iter().filter(|e| !e.id.unused()).count();
+        let balances = s.accounts.iter().map(|a| a.balance as u128);
+        let escrowed = s.escrows.iter().map(|e| e.amount as u128);
+        let supply: u128 = balances.chain(escrowed).sum::<u128>() + s.fees as u128;
+        if supply > u64::MAX as u128 {
+            return Err(Error::SupplyMismatch);
+        }
+        s.supply = supply as u64;
+        Ok(s)
+    }
+    /// the files of the multisig records, the escrows, the counters and the faucet
+    fn paths(path: &str) -> (String, String, String, String) {
+        (
+            format!("{}.multisig", path),
+            format!("{}.escrows", path),
+            format!("{}.meta", path),
+            format!("{}.faucet", path),
+        )
+    }
+    fn seed(&mut self, v: &[data::Account]) -> Result<()> {
         for a in v {
-            let fp = data::AccountT::find(&s.accounts, &a.from)?;
-            assert!(s.accounts[fp].from.unused());
-            s.accounts[fp].balance = a.balance;
-            s.accounts[fp].from = a.from;
-        }
-        s.used = v.len();
-        return Ok(s);
-    }
-    fn double(&mut self) -> Result<()> {
-        let size = self.accounts.len() * 2;
-        let mut v = vec![data::Account::default(); size];
-        data::AccountT::migrate(&self.accounts, &mut v)?;
-        self.accounts = v;
+            let fp = data::AccountT::find(&self.accounts, &a.from)?;
+            assert!(self.accounts[fp].from.unused());
+            self.accounts[fp].balance = a.balance;
+            self.accounts[fp].from = a.from;
+            self.supply = match self.supply.checked_add(a.balance) {
+                Some(s) => s,
+                None => return Err(Error::SupplyMismatch),
+            };
+        }
+        self.used = v.len();
+        Ok(())
+    }
+    pub fn supply(&self) -> u64 {
+        self.supply
+    }
+    pub fn fees(&self) -> u64 {
+        self.fees
+    }
+    /// PoH height of the last executed batch
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+    /// hash of the previous batch hash, the height and time of the last batch,
+    /// the outcome of each of its groups in execution order, see
+    /// `State::hash_group`, and the supply and fees after it.
+    /// nodes that replay the same batches end up with the same hash.
+    pub fn hash(&self) -> [u8; 32] {
+        self.hash
+    }
+    /// pay `Airdrop` requests from the account at `key`. the faucet is part
+    /// of the state, a state that already has another one isn't changed
+    pub fn set_faucet(&mut self, key: [u8; 32]) -> Result<()> {
+        let f = &mut self.faucet[0];
+        if f.key.unused() {
+            *f = Faucet::new(key);
+        } else if f.key != key {
+            return Err(Error::FaucetMismatch);
+        }
+        self.flush()
+    }
+    /// keep the accounts of shard `index` out of `num`, 1 without shards.
+    /// the keys are split by the number of shards, a state that was already
+    /// set to another shard or number isn't changed
+    pub fn set_shard(&mut self, index: usize, num: usize) -> Result<()> {
+        let (index, num) = (index as u64, num as u64);
+        if self.shards == 0 {
+            self.shard = index;
+            self.shards = num;
+        } else if self.shard != index || self.shards != num {
+            return Err(Error::ShardMismatch(self.shard, self.shards));
+        }
+        self.flush()
+    }
+    /// check the supply after every batch, on by default in debug builds
+    pub fn set_audit(&mut self, audit: bool) {
+        self.audit = audit;
+    }
+    /// audits that failed, see `State::set_audit`
+    pub fn failures(&self) -> u64 {
+        self.failures
+    }
+    /// check that the balances, the escrowed funds and the collected fees
+    /// add up to the supply
+    pub fn audit(&self) -> Result<()> {
+        let mut total: u128 = self.fees as u128;
+        for a in self.accounts.iter() {
+            total += a.balance as u128;
+        }
+        //accounts that haven't been migrated yet
+        for a in self.old.iter().filter(|a| !a.from.unused()) {
+            let p = data::AccountT::find(&self.accounts, &a.from)?;
+            if self.accounts[p].from != a.from {
+                total += a.balance as u128;
+            }
+        }
+        for e in self.escrows.iter().filter(|e| !e.id.unused()) {
+            total += e.amount as u128;
+        }
+        if total != self.supply as u128 {
+            error!("supply {} but found {}", self.supply, total);
+            return Err(Error::SupplyMismatch);
+        }
+        Ok(())
+    }
+    /// sync the memory-mapped tables to disk, then write and sync the
+    /// counters, which commits the tables
+    pub fn flush(&mut self) -> Result<()> {
+        self.accounts.flush()?;
+        self.old.flush()?;
+        self.multisig.flush()?;
+        self.escrows.flush()?;
+        self.faucet.flush()?;
+        self.meta[0] = Meta {
+            height: self.height,
+            time: self.time,
+            fees: self.fees,
+            hash: self.hash,
+            busy: 0,
+            shard: self.shard,
+            shards: self.shards,
+        };
+        self.meta.flush()
+    }
+    /// make sure `num` more accounts fit before executing a group
+    fn prepare(&mut self, num: usize) -> Result<()> {
+        if !self.old.is_empty() {
+            self.migrate_step()?;
+        }
+        let len = self.accounts.len();
+        if (self.used + num) * 4 > len * 3 {
+            //out of room before the last resize is done, finish it first
+            while !self.old.is_empty() {
+                self.migrate_step()?;
+            }
+            let mut size = max(len * 2, 1);
+            while (self.used + num) * 4 > size * 3 {
+                size *= 2;
+            }
+            self.resize(size)?;
+        } else if self.old.is_empty() && self.used * 4 < len && len / 2 >= self.min {
+            self.shrink()?;
+        }
+        Ok(())
+    }
+    fn shrink(&mut self) -> Result<()> {
+        let size = self.accounts.len() / 2;
+        self.resize(size)
+    }
+    /// start moving the accounts into a table of `size`, the old table is
+    /// migrated a few slots at a time by `migrate_step` so no single message
+    /// pays for copying the whole table
+    fn resize(&mut self, size: usize) -> Result<()> {
+        assert!(self.old.is_empty());
+        let v = self.accounts.alloc(size)?;
+        self.old = replace(&mut self.accounts, v);
+        self.moved = 0;
+        Ok(())
+    }
+    fn migrate_step(&mut self) -> Result<()> {
+        self.moved =
+            data::AccountT::migrate_some(&self.old, &mut self.accounts, self.moved, MIGRATE_STEP)?;
+        if self.moved == self.old.len() {
+            let old = replace(&mut self.old, Table::new(0));
+            self.accounts.replace(old)?;
+            self.moved = 0;
+        }
         Ok(())
     }
     fn find_accounts(
-        state: &[data::Account],
+        old: &[data::Account],
+        state: &mut [data::Account],
         fk: &[u8; 32],
         tk: &[u8; 32],
     ) -> Result<(usize, usize)> {
-        let sf = data::AccountT::find(&state, fk)?;
-        let st = data::AccountT::find(&state, tk)?;
+        let sf = data::AccountT::find_migrating(old, state, fk)?;
+        let st = data::AccountT::find_migrating(old, state, tk)?;
         Ok((sf, st))
     }
     fn load_accounts<'a>(
@@ -68,12 +380,13 @@ impl State {
 
     fn get_balance(
         ports: &Ports,
+        old: &[data::Account],
         state: &mut [data::Account],
         m: &mut data::Message,
         addr: SocketAddr,
     ) -> Result<()> {
         assert_eq!(m.pld.kind, data::Kind::GetBalance, "{:?}", m.pld.from);
-        let pos = Self::find_accounts(state, &m.pld.from, &m.pld.get_bal().key)?;
+        let pos = Self::find_accounts(old, state, &m.pld.from, &m.pld.get_bal().key)?;
         let (mut from, to) = Self::load_accounts(state, pos);
         if from.from != m.pld.from {
             return Ok(());
@@ -94,9 +407,55 @@ impl State {
         Ok(())
     }
 
-    fn tx(state: &mut [data::Account], m: &mut data::Message, num_new: &mut usize) -> Result<()> {
+    /// answer a `GetBalances` group, the head pays the fee and each
+    /// `BalanceKey` part gets the balance of its key, zero if it has no account
+    fn get_balances(
+        ports: &Ports,
+        old: &[data::Account],
+        state: &mut [data::Account],
+        group: &mut [data::Message],
+        addr: SocketAddr,
+    ) -> Result<()> {
+        if group.len() > data::MAX_BALANCES + 1 {
+            return Ok(());
+        }
+        let m = &mut group[0];
+        let pos = Self::find_accounts(old, state, &m.pld.from, &m.pld.from)?;
+        let (from, _) = Self::load_accounts(state, pos);
+        if from.from != m.pld.from || from.from.unused() {
+            return Ok(());
+        }
+        let fee = m.pld.fee;
+        Self::charge(from, m, fee);
+        if m.pld.state != data::State::Withdrawn {
+            return Ok(());
+        }
+        for part in group[1..].iter_mut() {
+            if part.pld.kind != data::Kind::BalanceKey {
+                continue;
+            }
+            let key = part.pld.get_bal().key;
+            let p = data::AccountT::find_migrating(old, state, &key)?;
+            let amount = if state[p].from == key && !key.unused() {
+                state[p].balance
+            } else {
+                0
+            };
+            part.pld.get_bal_mut().amount = amount;
+        }
+        OTP::send(ports, Port::Sender, Data::SendMessages(group.to_vec(), addr))?;
+        Ok(())
+    }
+
+    fn tx(
+        old: &[data::Account],
+        state: &mut [data::Account],
+        group: &mut [data::Message],
+        num_new: &mut usize,
+    ) -> Result<()> {
+        let m = &mut group[0];
         assert_eq!(m.pld.kind, data::Kind::Transaction, "{:?}", m.pld.from);
-        let pos = Self::find_accounts(state, &m.pld.from, &m.pld.get_tx().to)?;
+        let pos = Self::find_accounts(old, state, &m.pld.from, &m.pld.get_tx().to)?;
         let (mut from, mut to) = Self::load_accounts(state, pos);
         if from.from != m.pld.from {
             return Ok(());
@@ -104,7 +463,11 @@ impl State {
         if !to.from.unused() && to.from != m.pld.get_tx().to {
             return Ok(());
         }
-        let combined = m.pld.get_tx().amount + m.pld.fee;
+        let amount = m.pld.get_tx().amount;
+        let combined = match amount.checked_add(m.pld.fee) {
+            Some(c) if Self::fits(to, amount) => c,
+            _ => return Ok(()),
+        };
         Self::charge(&mut from, m, combined);
         if m.pld.state != data::State::Withdrawn {
             return Ok(());
@@ -114,34 +477,699 @@ impl State {
         assert_eq!(m.pld.state, data::State::Deposited, "{:?}", m.pld.from);
         Ok(())
     }
+    /// pay every leg of a `Batch` group or none of them
+    fn batch(
+        old: &[data::Account],
+        state: &mut [data::Account],
+        group: &mut [data::Message],
+        num_new: &mut usize,
+    ) -> Result<()> {
+        if group.len() > data::MAX_BATCH {
+            return Ok(());
+        }
+        let (head, parts) = group.split_at_mut(1);
+        let m = &mut head[0];
+        let legs: Vec<data::Transaction> = Some(*m.pld.get_tx())
+            .into_iter()
+            .chain(
+                parts
+                    .iter()
+                    .filter(|p| p.pld.kind == data::Kind::Leg)
+                    .map(|p| *p.pld.get_tx()),
+            )
+            .collect();
+        let mut combined = m.pld.fee;
+        for l in &legs {
+            combined = match combined.checked_add(l.amount) {
+                Some(c) => c,
+                None => return Ok(()),
+            };
+        }
+        for l in &legs {
+            let p = data::AccountT::find_migrating(old, state, &l.to)?;
+            if !state[p].from.unused() && state[p].from != l.to {
+                return Ok(());
+            }
+            //no recipient gets more than all of the legs together
+            if !Self::fits(&state[p], combined) {
+                return Ok(());
+            }
+        }
+        let pos = Self::find_accounts(old, state, &m.pld.from, &m.pld.from)?;
+        let (from, _) = Self::load_accounts(state, pos);
+        if from.from != m.pld.from || from.from.unused() {
+            return Ok(());
+        }
+        Self::charge(from, m, combined);
+        if m.pld.state != data::State::Withdrawn {
+            return Ok(());
+        }
+        for l in &legs {
+            let p = data::AccountT::find_migrating(old, state, &l.to)?;
+            let to = &mut state[p];
+            Self::new_account(to, num_new);
+            to.from = l.to;
+            to.balance += l.amount;
+        }
+        m.pld.state = data::State::Deposited;
+        Ok(())
+    }
+    /// settle both halves of a `Swap` group or neither, the `Counter` part
+    /// has to be signed by the counterparty
+    fn swap(
+        old: &[data::Account],
+        state: &mut [data::Account],
+        group: &mut [data::Message],
+    ) -> Result<()> {
+        if group.len() < 2 || group[1].pld.kind != data::Kind::Counter {
+            return Ok(());
+        }
+        if !wallet::verify(group, 1) {
+            return Ok(());
+        }
+        let (head, parts) = group.split_at_mut(1);
+        let (a, b) = (&mut head[0], &mut parts[0]);
+        let (ta, tb) = (*a.pld.get_tx(), *b.pld.get_tx());
+        if ta.to != b.pld.from || tb.to != a.pld.from || a.pld.from == b.pld.from {
+            return Ok(());
+        }
+        let (ca, cb) = match (
+            ta.amount.checked_add(a.pld.fee),
+            tb.amount.checked_add(b.pld.fee),
+        ) {
+            (Some(ca), Some(cb)) => (ca, cb),
+            _ => return Ok(()),
+        };
+        let pos = Self::find_accounts(old, state, &a.pld.from, &b.pld.from)?;
+        let (fa, fb) = Self::load_accounts(state, pos);
+        if fa.from != a.pld.from || fb.from != b.pld.from || fa.from.unused() || fb.from.unused() {
+            return Ok(());
+        }
+        if fa.balance < ca || fb.balance < cb {
+            return Ok(());
+        }
+        if !Self::fits(fa, tb.amount) || !Self::fits(fb, ta.amount) {
+            return Ok(());
+        }
+        Self::charge(fa, a, ca);
+        Self::charge(fb, b, cb);
+        fa.balance += tb.amount;
+        fb.balance += ta.amount;
+        a.pld.state = data::State::Deposited;
+        b.pld.state = data::State::Deposited;
+        Ok(())
+    }
+    /// pay an `Airdrop` of up to `faucet::AMOUNT` from the faucet account
+    fn airdrop(&mut self, m: &mut data::Message, num_new: &mut usize) -> Result<()> {
+        let f = &mut self.faucet[0];
+        if f.key.unused() {
+            return Ok(());
+        }
+        m.pld.from = f.key;
+        m.pld.fee = 0;
+        let to = m.pld.get_tx().to;
+        if to.unused() || to == f.key || !f.allow(&to, self.time) {
+            return Ok(());
+        }
+        let amount = match m.pld.get_tx().amount {
+            0 => faucet::AMOUNT,
+            a => min(a, faucet::AMOUNT),
+        };
+        m.pld.get_tx_mut().amount = amount;
+        let pos = Self::find_accounts(&self.old, &mut self.accounts, &f.key, &to)?;
+        let (from, to_acc) = Self::load_accounts(&mut self.accounts, pos);
+        if from.from != f.key || (!to_acc.from.unused() && to_acc.from != to) {
+            return Ok(());
+        }
+        if !Self::fits(to_acc, amount) {
+            return Ok(());
+        }
+        Self::charge(from, m, amount);
+        if m.pld.state != data::State::Withdrawn {
+            return Ok(());
+        }
+        Self::new_account(to_acc, num_new);
+        Self::deposit(to_acc, m);
+        f.paid(&to, self.time);
+        Ok(())
+    }
+    /// a group from a multisig account needs `Signature` parts from at least
+    /// `threshold` distinct signers, other accounts are always authorized here.
+    /// checked for every group before it's executed
+    fn authorized(msigs: &[data::MultiSigAccount], group: &[data::Message]) -> bool {
+        let from = &group[0].pld.from;
+        let acc = match data::MultiSigT::find(msigs, from) {
+            Ok(p) if msigs[p].key == *from => &msigs[p],
+            _ => return true,
+        };
+        let mut signed = 0;
+        for ix in 1..group.len() {
+            let part = &group[ix];
+            if part.pld.kind != data::Kind::Signature {
+                continue;
+            }
+            let k = &part.pld.from;
+            if !acc.signers().contains(k) || group[1..ix].iter().any(|p| p.pld.from == *k) {
+                continue;
+            }
+            if wallet::verify(group, ix) {
+                signed += 1;
+            }
+        }
+        signed >= acc.threshold
+    }
+    /// register a multisig account, the signers are listed by the `Signature`
+    /// parts that follow the head and `from` pays the fee
+    fn multisig(
+        old: &[data::Account],
+        state: &mut [data::Account],
+        msigs: &mut Table<data::MultiSigAccount>,
+        msig_used: &mut usize,
+        group: &mut [data::Message],
+    ) -> Result<()> {
+        let ms = *group[0].pld.get_msig();
+        let signers: Vec<[u8; 32]> = group[1..].iter().map(|m| m.pld.from).collect();
+        let num = ms.num as usize;
+        if num != signers.len() || num > data::MAX_SIGNERS {
+            return Ok(());
+        }
+        if ms.threshold == 0 || ms.threshold > ms.num {
+            return Ok(());
+        }
+        if (1..num).any(|i| signers[..i].contains(&signers[i])) {
+            return Ok(());
+        }
+        if wallet::multisig_key(ms.threshold, &signers) != ms.key {
+            return Ok(());
+        }
+        Self::reserve(msigs, *msig_used)?;
+        let mp = data::MultiSigT::find(msigs, &ms.key)?;
+        if !msigs[mp].key.unused() {
+            return Ok(());
+        }
+        let m = &mut group[0];
+        let pos = Self::find_accounts(old, state, &m.pld.from, &m.pld.from)?;
+        let (from, _) = Self::load_accounts(state, pos);
+        if from.from != m.pld.from || from.from.unused() {
+            return Ok(());
+        }
+        let fee = m.pld.fee;
+        Self::charge(from, m, fee);
+        if m.pld.state != data::State::Withdrawn {
+            return Ok(());
+        }
+        let acc = &mut msigs[mp];
+        acc.key = ms.key;
+        acc.threshold = ms.threshold;
+        acc.num = ms.num;
+        acc.signers[..num].copy_from_slice(&signers);
+        *msig_used += 1;
+        Ok(())
+    }
+    /// make room for one more record in one of the small tables, they are
+    /// migrated at once
+    fn reserve<V>(tbl: &mut Table<V>, used: usize) -> Result<()>
+    where
+        V: Val<[u8; 32]> + Default + Copy,
+    {
+        let len = tbl.len();
+        if (used + 1) * 4 <= len * 3 {
+            return Ok(());
+        }
+        let mut v = tbl.alloc(max(16, len * 2))?;
+        HashT::<[u8; 32], V>::migrate(&tbl[..], &mut v)?;
+        let old = replace(tbl, v);
+        tbl.replace(old)
+    }
+    /// hold the payment of an `Escrow` group until it's claimed or cancelled
+    fn escrow(
+        old: &[data::Account],
+        state: &mut [data::Account],
+        escrows: &mut Table<data::Escrow>,
+        escrows_used: &mut usize,
+        group: &mut [data::Message],
+    ) -> Result<()> {
+        if group.len() < 2 || group[1].pld.kind != data::Kind::Condition {
+            return Ok(());
+        }
+        //the `Condition` can only be followed by the signatures of a multisig account
+        if group[2..].iter().any(|m| m.pld.kind != data::Kind::Signature) {
+            return Ok(());
+        }
+        let id = wallet::escrow_id(group);
+        let cond = *group[1].pld.get_cond();
+        Self::reserve(escrows, *escrows_used)?;
+        let ep = data::EscrowT::find(escrows, &id)?;
+        if !escrows[ep].id.unused() {
+            return Ok(());
+        }
+        let m = &mut group[0];
+        let tx = *m.pld.get_tx();
+        let pos = Self::find_accounts(old, state, &m.pld.from, &m.pld.from)?;
+        let (from, _) = Self::load_accounts(state, pos);
+        if from.from != m.pld.from || from.from.unused() {
+            return Ok(());
+        }
+        let combined = match tx.amount.checked_add(m.pld.fee) {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        Self::charge(from, m, combined);
+        if m.pld.state != data::State::Withdrawn {
+            return Ok(());
+        }
+        escrows[ep] = data::Escrow {
+            id,
+            from: m.pld.from,
+            to: tx.to,
+            amount: tx.amount,
+            height: cond.height,
+            time: cond.time,
+        };
+        *escrows_used += 1;
+        Ok(())
+    }
+    /// pay out an escrow, to its recipient on `Claim` once the condition is met,
+    /// or back to its sender on `Cancel` before that. the fee comes out of the payment.
+    fn redeem(
+        &mut self,
+        at: usize,
+        m: &mut data::Message,
+        num_new: &mut usize,
+    ) -> Result<()> {
+        let id = m.pld.get_redeem().id;
+        let ep = match data::EscrowT::find(&self.escrows, &id) {
+            Ok(p) if self.escrows[p].id == id => p,
+            _ => return Ok(()),
+        };
+        let e = self.escrows[ep];
+        let ready = (e.height == 0 || self.height >= e.height) && (e.time == 0 || self.time >= e.time);
+        let dest = match m.pld.kind {
+            data::Kind::Claim if ready && m.pld.from == e.to => e.to,
+            data::Kind::Cancel if !ready && m.pld.from == e.from => e.from,
+            _ => return Ok(()),
+        };
+        if e.amount < m.pld.fee {
+            return Ok(());
+        }
+        let pos = Self::find_accounts(&self.old, &mut self.accounts, &dest, &dest)?;
+        let (to, _) = Self::load_accounts(&mut self.accounts, pos);
+        if (!to.from.unused() && to.from != dest) || !Self::fits(to, e.amount - m.pld.fee) {
+            return Ok(());
+        }
+        Self::new_account(to, num_new);
+        to.from = dest;
+        to.balance += e.amount - m.pld.fee;
+        m.pld.state = data::State::Deposited;
+        //the sender's side was indexed when the escrow was made
+        let other = if dest == e.to { &e.from } else { &e.to };
+        self.history.record_one(self.height, at, &dest, other, e.amount, true);
+        data::EscrowT::remove(&mut self.escrows, &id)?;
+        self.escrows_used -= 1;
+        Ok(())
+    }
+    /// add the transfers of a settled `group` at message `at` to the history,
+    /// an escrow is indexed for its sender when it's made and for the account
+    /// that gets the funds when it's redeemed
+    fn index(&mut self, at: usize, group: &[data::Message]) {
+        let head = &group[0];
+        if head.pld.kind == data::Kind::Escrow && head.pld.state == data::State::Withdrawn {
+            let tx = head.pld.get_tx();
+            let (h, from) = (self.height, head.pld.from);
+            self.history.record_one(h, at, &from, &tx.to, tx.amount, false);
+            return;
+        }
+        if head.pld.state != data::State::Deposited {
+            return;
+        }
+        let kind = head.pld.kind;
+        if kind != data::Kind::Transaction && kind != data::Kind::Batch && kind != data::Kind::Swap
+            && kind != data::Kind::Airdrop
+        {
+            return;
+        }
+        for (i, m) in group.iter().enumerate() {
+            if !m.pld.kind.is_transfer() {
+                continue;
+            }
+            let from = if m.pld.kind == data::Kind::Leg {
+                &head.pld.from
+            } else {
+                &m.pld.from
+            };
+            let tx = m.pld.get_tx();
+            self.history.record(self.height, at + i, from, &tx.to, tx.amount);
+        }
+    }
+    /// answer a `GetHistory` with a `Record` part for each transfer found
+    fn get_history(&mut self, ports: &Ports, m: &mut data::Message, addr: SocketAddr) -> Result<()> {
+        let pos = Self::find_accounts(&self.old, &mut self.accounts, &m.pld.from, &m.pld.from)?;
+        let (from, _) = Self::load_accounts(&mut self.accounts, pos);
+        if from.from != m.pld.from || from.from.unused() {
+            return Ok(());
+        }
+        let fee = m.pld.fee;
+        Self::charge(from, m, fee);
+        if m.pld.state != data::State::Withdrawn {
+            return Ok(());
+        }
+        let q = *m.pld.get_hist();
+        let num = min(q.num as usize, data::MAX_BATCH - 1);
+        let page = self.history.page(&q.key, q.start as usize, num);
+        m.pld.get_hist_mut().num = page.len() as u32;
+        let mut reply = vec![*m];
+        for (other, rec) in page {
+            let mut r = data::Message::default();
+            r.pld.kind = data::Kind::Record;
+            r.pld.from = other;
+            r.pld.data = data::MessageData { rec };
+            reply.push(r);
+        }
+        OTP::send(ports, Port::Sender, Data::SendMessages(reply, addr))?;
+        Ok(())
+    }
+    /// answer a `GetHash` with the hash after the last executed batch
+    fn get_hash(&mut self, ports: &Ports, m: &mut data::Message, addr: SocketAddr) -> Result<()> {
+        let pos = Self::find_accounts(&self.old, &mut self.accounts, &m.pld.from, &m.pld.from)?;
+        let (from, _) = Self::load_accounts(&mut self.accounts, pos);
+        if from.from != m.pld.from || from.from.unused() {
+            return Ok(());
+        }
+        let fee = m.pld.fee;
+        Self::charge(from, m, fee);
+        if m.pld.state != data::State::Withdrawn {
+            return Ok(());
+        }
+        *m.pld.get_hash_mut() = data::GetHash {
+            hash: self.hash,
+            height: self.hashed,
+        };
+        OTP::send(ports, Port::Sender, Data::SendMessage(*m, addr))?;
+        Ok(())
+    }
+    /// answer a `GetAudit` with the supply and the outcome of an audit, which
+    /// is run before the fee is charged
+    fn get_audit(&mut self, ports: &Ports, m: &mut data::Message, addr: SocketAddr) -> Result<()> {
+        let pos = Self::find_accounts(&self.old, &mut self.accounts, &m.pld.from, &m.pld.from)?;
+        let (from, _) = Self::load_accounts(&mut self.accounts, pos);
+        if from.from != m.pld.from || from.from.unused() || from.balance < m.pld.fee {
+            return Ok(());
+        }
+        let ok = self.audit().is_ok();
+        if !ok {
+            self.failures += 1;
+        }
+        let (supply, fees, failures) = (self.supply, self.fees, self.failures);
+        let (from, _) = Self::load_accounts(&mut self.accounts, pos);
+        let fee = m.pld.fee;
+        Self::charge(from, m, fee);
+        *m.pld.get_audit_mut() = data::Audit {
+            supply,
+            fees,
+            failures,
+            ok: ok as u32,
+            unused: 0,
+        };
+        OTP::send(ports, Port::Sender, Data::SendMessage(*m, addr))?;
+        Ok(())
+    }
+    /// accounts that `group` can change, its sender and every recipient
+    fn touched(group: &[data::Message]) -> Vec<[u8; 32]> {
+        let keys = group
+            .iter()
+            .filter(|m| m.pld.kind.is_transfer())
+            .map(|m| m.pld.get_tx().to);
+        Some(group[0].pld.from).into_iter().chain(keys).collect()
+    }
+    /// drop the accounts touched by `group` once their balance reaches zero
+    fn reclaim(&mut self, group: &[data::Message]) -> Result<()> {
+        for k in Self::touched(group) {
+            self.reclaim_key(&k)?;
+        }
+        Ok(())
+    }
+    fn reclaim_key(&mut self, k: &[u8; 32]) -> Result<()> {
+        let pos = data::AccountT::find_migrating(&self.old, &mut self.accounts, k)?;
+        if self.accounts[pos].from != *k || self.accounts[pos].balance != 0 {
+            return Ok(());
+        }
+        if data::AccountT::remove_migrating(&mut self.old, &mut self.accounts, k)? {
+            self.used -= 1;
+        }
+        Ok(())
+    }
+    /// add the outcome of `group` to the batch hash, the state of each message,
+    /// the balance of each touched account, zero if it was removed, and the
+    /// multisig or escrow record that it created or removed
+    fn hash_group(&mut self, h: &mut Sha256, group: &[data::Message]) -> Result<()> {
+        for m in group {
+            h.input(&[m.pld.state as u8]);
+        }
+        for k in Self::touched(group) {
+            self.hash_key(h, &k)?;
+        }
+        let head = &group[0];
+        if head.pld.state == data::State::Unknown {
+            return Ok(());
+        }
+        match head.pld.kind {
+            data::Kind::MultiSig => {
+                let key = head.pld.get_msig().key;
+                let p = data::MultiSigT::find(&self.multisig, &key)?;
+                let acc = &self.multisig[p];
+                if acc.key == key {
+                    h.input(&acc.key);
+                    h.input(&acc.threshold.to_le_bytes());
+                    for s in acc.signers() {
+                        h.input(s);
+                    }
+                }
+            }
+            data::Kind::Escrow => {
+                let id = wallet::escrow_id(group);
+                let p = data::EscrowT::find(&self.escrows, &id)?;
+                let e = &self.escrows[p];
+                if e.id == id {
+                    h.input(&e.id);
+                    h.input(&e.from);
+                    h.input(&e.to);
+                    h.input(&e.amount.to_le_bytes());
+                    h.input(&e.height.to_le_bytes());
+                    h.input(&e.time.to_le_bytes());
+                }
+            }
+            data::Kind::Claim | data::Kind::Cancel => h.input(&head.pld.get_redeem().id),
+            _ => (),
+        }
+        Ok(())
+    }
+    /// add the balance of `k` to the batch hash, zero if it has no account
+    pub fn hash_key(&mut self, h: &mut Sha256, k: &[u8; 32]) -> Result<()> {
+        let pos = data::AccountT::find_migrating(&self.old, &mut self.accounts, k)?;
+        let a = &self.accounts[pos];
+        let balance = if a.from == *k { a.balance } else { 0 };
+        h.input(k);
+        h.input(&balance.to_le_bytes());
+        Ok(())
+    }
+    /// start the batch at PoH `height` and unix `time`, the hash of the batch
+    /// starts from the hash of the previous one. the counters on disk are
+    /// marked busy before any table is changed
+    pub fn begin_batch(&mut self, height: u64, time: u64) -> Result<Sha256> {
+        self.meta[0].busy = 1;
+        self.meta.flush()?;
+        self.height = height;
+        self.time = time;
+        let mut h = Sha256::new();
+        h.input(&self.hash);
+        h.input(&height.to_le_bytes());
+        h.input(&time.to_le_bytes());
+        Ok(h)
+    }
+    /// store the hash of the batch and check the supply, a mismatch is logged
+    /// and counted but the state keeps executing. the batch is committed to
+    /// disk with `flush`
+    pub fn end_batch(&mut self, mut h: Sha256) -> Result<()> {
+        h.input(&self.supply.to_le_bytes());
+        h.input(&self.fees.to_le_bytes());
+        h.result(&mut self.hash);
+        self.hashed = self.height;
+        if self.audit && self.audit().is_err() {
+            self.failures += 1;
+        }
+        self.flush()
+    }
+    /// take `amount` and `fee` from `key` for a transfer that is paid out with
+    /// `credit`, possibly by another shard, so the amount leaves this supply.
+    /// returns false if the account can't pay
+    pub fn debit(&mut self, key: &[u8; 32], amount: u64, fee: u64) -> Result<bool> {
+        self.prepare(0)?;
+        let combined = match amount.checked_add(fee) {
+            Some(c) => c,
+            None => return Ok(false),
+        };
+        let pos = data::AccountT::find_migrating(&self.old, &mut self.accounts, key)?;
+        let acc = &mut self.accounts[pos];
+        if acc.from != *key || acc.from.unused() || acc.balance < combined {
+            return Ok(false);
+        }
+        acc.balance -= combined;
+        self.fees += fee;
+        self.supply -= amount;
+        self.reclaim_key(key)?;
+        Ok(true)
+    }
+    /// pay `amount` to `key` from a `debit`, the account is created if needed
+    pub fn credit(&mut self, key: &[u8; 32], amount: u64) -> Result<()> {
+        assert!(!key.unused());
+        self.prepare(1)?;
+        let pos = data::AccountT::find_migrating(&self.old, &mut self.accounts, key)?;
+        let acc = &mut self.accounts[pos];
+        //the amount was in the supply of the debited shard, it can't overflow
+        //unless the supplies of the shards add up to more than `u64::MAX`
+        let (balance, supply) = match (
+            acc.balance.checked_add(amount),
+            self.supply.checked_add(amount),
+        ) {
+            (Some(b), Some(s)) => (b, s),
+            _ => return Err(Error::SupplyMismatch),
+        };
+        if acc.from.unused() {
+            acc.from = *key;
+            self.used += 1;
+        }
+        acc.balance = balance;
+        self.supply = supply;
+        self.reclaim_key(key)
+    }
+    /// give back a `debit` whose `credit` failed, fee included. the account
+    /// is created again if the debit emptied it
+    pub fn refund(&mut self, key: &[u8; 32], amount: u64, fee: u64) -> Result<()> {
+        //the debit checked that they add up
+        self.credit(key, amount + fee)?;
+        self.fees -= fee;
+        self.supply -= fee;
+        Ok(())
+    }
+    /// balance of `key`, none if it has no account
+    pub fn balance(&mut self, key: &[u8; 32]) -> Result<Option<u64>> {
+        let pos = data::AccountT::find_migrating(&self.old, &mut self.accounts, key)?;
+        let a = &self.accounts[pos];
+        if a.from.unused() || a.from != *key {
+            return Ok(None);
+        }
+        Ok(Some(a.balance))
+    }
     fn execute(&mut self, p: &Ports, ms: &mut data::Messages) -> Result<()> {
+        let mut h = self.begin_batch(ms.height, ms.time)?;
         ms.with_mut(
             &mut |msgs: &mut Vec<data::Message>, data: &mut Vec<(usize, SocketAddr)>| {
                 let mut total = 0;
                 for &(z, a) in data.iter() {
-                    for m in msgs[total..total + z].iter_mut() {
-                        let len = self.accounts.len();
-                        if self.used * 4 > len * 3 {
-                            self.double()?;
+                    let mut ix = total;
+                    while ix < total + z {
+                        let num = data::group_len(&msgs[ix..total + z]);
+                        let at = ix;
+                        let group = &mut msgs[at..at + num];
+                        ix += num;
+                        for m in group.iter_mut() {
+                            m.pld.state = data::State::Unknown;
                         }
-                        match m.pld.kind {
+                        self.prepare(num)?;
+                        let kind = group[0].pld.kind;
+                        //an airdrop is paid by the faucet, not by its sender
+                        let allowed =
+                            kind == data::Kind::Airdrop || Self::authorized(&self.multisig, group);
+                        match if allowed { kind } else { data::Kind::Invalid } {
                             data::Kind::Transaction => {
                                 let mut num_new = 0;
-                                Self::tx(&mut self.accounts, m, &mut num_new)?;
-                                assert_eq!(m.pld.state, data::State::Deposited);
+                                Self::tx(&self.old, &mut self.accounts, group, &mut num_new)?;
                                 self.used += num_new;
                             }
                             data::Kind::GetBalance => {
-                                Self::get_balance(p, &mut self.accounts, m, a)?;
+                                Self::get_balance(
+                                    p,
+                                    &self.old,
+                                    &mut self.accounts,
+                                    &mut group[0],
+                                    a,
+                                )?;
+                            }
+                            data::Kind::GetBalances => {
+                                Self::get_balances(
+                                    p,
+                                    &self.old,
+                                    &mut self.accounts,
+                                    group,
+                                    a,
+                                )?;
+                            }
+                            data::Kind::MultiSig => {
+                                Self::multisig(
+                                    &self.old,
+                                    &mut self.accounts,
+                                    &mut self.multisig,
+                                    &mut self.multisig_used,
+                                    group,
+                                )?;
+                            }
+                            data::Kind::Escrow => {
+                                Self::escrow(
+                                    &self.old,
+                                    &mut self.accounts,
+                                    &mut self.escrows,
+                                    &mut self.escrows_used,
+                                    group,
+                                )?;
+                            }
+                            data::Kind::Batch => {
+                                let mut num_new = 0;
+                                Self::batch(&self.old, &mut self.accounts, group, &mut num_new)?;
+                                self.used += num_new;
+                            }
+                            data::Kind::Swap => {
+                                Self::swap(&self.old, &mut self.accounts, group)?;
+                            }
+                            data::Kind::Claim | data::Kind::Cancel => {
+                                let mut num_new = 0;
+                                self.redeem(at, &mut group[0], &mut num_new)?;
+                                self.used += num_new;
+                            }
+                            data::Kind::Airdrop => {
+                                let mut num_new = 0;
+                                self.airdrop(&mut group[0], &mut num_new)?;
+                                self.used += num_new;
+                            }
+                            data::Kind::GetHistory => {
+                                self.get_history(p, &mut group[0], a)?;
+                            }
+                            data::Kind::GetAudit => {
+                                self.get_audit(p, &mut group[0], a)?;
+                            }
+                            data::Kind::GetHash => {
+                                self.get_hash(p, &mut group[0], a)?;
                             }
                             _ => (),
                         }
+                        self.index(at, group);
+                        //every message that was charged paid its fee
+                        self.fees += group
+                            .iter()
+                            .filter(|m| m.pld.state != data::State::Unknown)
+                            .map(|m| m.pld.fee)
+                            .sum::<u64>();
+                        self.reclaim(group)?;
+                        self.hash_group(&mut h, group)?;
                     }
                     total += z;
                 }
                 Ok(())
             },
-        )
+        )?;
+        self.end_batch(h)?;
+        ms.hash = self.hash;
+        debug!("batch {} hash {}", self.height, HEXLOWER.encode(&self.hash));
+        Ok(())
     }
     fn charge(acc: &mut data::Account, m: &mut data::Message, combined: u64) -> () {
         if acc.balance >= combined {
@@ -149,6 +1177,11 @@ impl State {
             acc.balance = acc.balance - combined;
         }
     }
+    /// true if `amount` can be paid to `to` without overflowing its balance,
+    /// checked before anything is charged so a group pays all or nothing
+    fn fits(to: &data::Account, amount: u64) -> bool {
+        to.balance.checked_add(amount).is_some()
+    }
     fn new_account(to: &data::Account, num: &mut usize) -> () {
         if to.from.unused() {
             *num = *num + 1;
@@ -177,6 +1210,13 @@ mod tests {
     use otp::Port;
     use otp::Data::{SharedMessages, Signal};
     use env_logger;
+    use table::Table;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::process;
+    use wallet::{escrow_id, multisig_key, to32b, Wallet};
+    use result::Error;
+    use faucet;
 
     #[test]
     fn state_test() {
@@ -213,6 +1253,596 @@ mod tests {
         assert_eq!(s.accounts[fp].balance, 2u64);
     }
     #[test]
+    fn state_audit_test() {
+        let mut w = Wallet::new();
+        w.add_keypair(Wallet::new_keypair());
+        let from = to32b(w.pubkeys[0]);
+        let list = [
+            data::Account {
+                from: from,
+                balance: 1000,
+            },
+        ];
+        let mut s = State::from_list(&list).expect("from list");
+        assert_eq!(s.supply(), 1000);
+        let tx = vec![w.tx(0, [1u8; 32], 10, 2)];
+        let escrow = w.escrow(0, [2u8; 32], 20, 100, 0, 3);
+        //can't pay, no fee is taken
+        let over = vec![w.tx(0, [1u8; 32], 2000, 1)];
+        run_groups(&mut s, &[tx, escrow, over]);
+        assert_eq!(s.fees(), 5);
+        assert_eq!(balance(&mut s, &from), 1000 - 12 - 23);
+        s.audit().expect("audit");
+        //coins that appear out of nowhere are caught
+        let p = data::AccountT::find(&s.accounts, &from).expect("find");
+        s.accounts[p].balance += 1;
+        assert_matches!(s.audit(), Err(Error::SupplyMismatch));
+        //the batch is still executed, the failure is counted
+        s.set_audit(true);
+        let out = run_groups(&mut s, &[vec![w.tx(0, [1u8; 32], 10, 1)]]);
+        assert_eq!(out[0].pld.state, data::State::Deposited);
+        assert_eq!(s.failures(), 1);
+    }
+    #[test]
+    fn state_overflow_test() {
+        let mut w = Wallet::new();
+        w.add_keypair(Wallet::new_keypair());
+        let from = to32b(w.pubkeys[0]);
+        let full = [3u8; 32];
+        let list = [
+            data::Account {
+                from: from,
+                balance: 1000,
+            },
+            data::Account {
+                from: full,
+                balance: u64::MAX - 1000,
+            },
+        ];
+        let mut more = list.to_vec();
+        more.push(data::Account {
+            from: [4u8; 32],
+            balance: 1,
+        });
+        assert_matches!(State::from_list(&more).err(), Some(Error::SupplyMismatch));
+        let mut s = State::from_list(&list).expect("from list");
+        //a balance that can't be reached by transfers, the recipient would
+        //overflow so the group isn't executed and nothing is charged
+        let p = data::AccountT::find(&s.accounts, &full).expect("find");
+        s.accounts[p].balance = u64::MAX - 100;
+        let legs = [([1u8; 32], 10), (full, 500)];
+        let batch = w.batch(0, &legs, 1).expect("batch");
+        let tx = vec![w.tx(0, full, 500, 1)];
+        let fits = w.batch(0, &legs[..1], 1).expect("batch");
+        let out = run_groups(&mut s, &[batch, tx, fits]);
+        assert_eq!(out[0].pld.state, data::State::Unknown);
+        assert_eq!(out[2].pld.state, data::State::Unknown);
+        assert_eq!(out[3].pld.state, data::State::Deposited);
+        assert_eq!(balance(&mut s, &from), 1000 - 11);
+        assert_eq!(balance(&mut s, &full), u64::MAX - 100);
+    }
+    #[test]
+    fn state_hash_test() {
+        let mut w = Wallet::new();
+        w.add_keypair(Wallet::new_keypair());
+        let from = to32b(w.pubkeys[0]);
+        let list = [
+            data::Account {
+                from: from,
+                balance: 1000,
+            },
+        ];
+        let batch = [vec![w.tx(0, [1u8; 32], 10, 1)], vec![w.tx(0, [2u8; 32], 20, 1)]];
+        let mut a = State::from_list(&list).expect("from list");
+        let mut b = State::from_list(&list).expect("from list");
+        assert_eq!(a.hash(), b.hash());
+        run_groups(&mut a, &batch);
+        run_groups(&mut b, &batch);
+        assert_eq!(a.hash(), b.hash());
+        assert!(a.hash() != [0u8; 32]);
+        let mut msgs = data::Messages::new();
+        msgs.with_mut(|m, d| {
+            m[0] = w.tx(0, [1u8; 32], 10, 1);
+            d[0].0 = 1;
+            Ok(())
+        }).expect("init");
+        let ports = vec![];
+        a.execute(&ports, &mut msgs).expect("execute");
+        assert_eq!(msgs.hash, a.hash());
+        let prev = b.hash();
+        run_groups(&mut b, &[vec![w.tx(0, [2u8; 32], 10, 1)]]);
+        assert!(a.hash() != b.hash());
+        assert!(b.hash() != prev);
+
+        //escrows that only differ in their condition move the same balances
+        let mut a = State::from_list(&list).expect("from list");
+        let mut b = State::from_list(&list).expect("from list");
+        run_groups(&mut a, &[w.escrow(0, [1u8; 32], 10, 5, 0, 1)]);
+        run_groups(&mut b, &[w.escrow(0, [1u8; 32], 10, 6, 0, 1)]);
+        assert_eq!(balance(&mut a, &from), balance(&mut b, &from));
+        assert!(a.hash() != b.hash());
+        assert_eq!(a.hashed, 1);
+    }
+    #[test]
+    fn state_airdrop_test() {
+        let f = [255u8; 32];
+        let list = [
+            data::Account {
+                from: f,
+                balance: 10000,
+            },
+        ];
+        let mut s = State::from_list(&list).expect("from list");
+        let drop = vec![Wallet::airdrop([1u8; 32], 10)];
+        //no faucet, nothing is paid
+        let out = run_groups(&mut s, &[drop.clone()]);
+        assert_eq!(out[0].pld.state, data::State::Unknown);
+        s.set_faucet(f).expect("faucet");
+        s.time = 100;
+        let big = vec![Wallet::airdrop([2u8; 32], 0)];
+        let out = run_groups(&mut s, &[drop.clone(), drop.clone(), big]);
+        assert_eq!(out[0].pld.state, data::State::Deposited);
+        assert_eq!(out[1].pld.state, data::State::Unknown);
+        assert_eq!(out[2].pld.state, data::State::Deposited);
+        assert_eq!(balance(&mut s, &[1u8; 32]), 10);
+        assert_eq!(balance(&mut s, &[2u8; 32]), faucet::AMOUNT);
+        assert_eq!(balance(&mut s, &f), 10000 - 10 - faucet::AMOUNT);
+        assert_eq!(s.history.page(&[1u8; 32], 0, 10)[0].0, f);
+        s.time += faucet::INTERVAL;
+        let out = run_groups(&mut s, &[drop]);
+        assert_eq!(out[0].pld.state, data::State::Deposited);
+        assert_eq!(balance(&mut s, &[1u8; 32]), 20);
+    }
+    #[test]
+    fn state_reclaim_test() {
+        let f = [255u8; 32];
+        let mut s: State = State::new(4);
+        let list = [
+            data::Account {
+                from: f,
+                balance: 100,
+            },
+        ];
+        s.seed(&list).expect("seed");
+        let mut msgs = data::Messages::new();
+        msgs.with_mut(|m, d| {
+            for (i, m) in m[..11].iter_mut().enumerate() {
+                m.pld.kind = data::Kind::Transaction;
+                m.pld.fee = 1;
+                let k = [(i % 5) as u8 + 1; 32];
+                if i < 5 {
+                    m.pld.from = f;
+                    m.pld.get_tx_mut().to = k;
+                    m.pld.get_tx_mut().amount = 1;
+                } else if i < 10 {
+                    m.pld.from = k;
+                    m.pld.get_tx_mut().to = f;
+                } else {
+                    m.pld.from = f;
+                    m.pld.get_tx_mut().to = f;
+                    m.pld.get_tx_mut().amount = 1;
+                }
+            }
+            d[0].0 = 11;
+            Ok(())
+        }).expect("init");
+        let ports = vec![];
+        s.execute(&ports, &mut msgs).expect("execute");
+        assert_eq!(s.used, 1);
+        assert_eq!(s.accounts.len(), 4);
+        let fp = data::AccountT::find(&s.accounts, &f).expect("f");
+        assert_eq!(s.accounts[fp].balance, 89);
+        let kp = data::AccountT::find(&s.accounts, &[1u8; 32]).expect("k");
+        assert!(s.accounts[kp].from.unused());
+    }
+    #[test]
+    fn state_resize_test() {
+        const NUM: usize = 1000;
+        let f = [255u8; 32];
+        let mut s: State = State::new(4);
+        let list = [
+            data::Account {
+                from: f,
+                balance: NUM as u64 * 2 + 1,
+            },
+        ];
+        s.seed(&list).expect("seed");
+        let key = |i: usize| {
+            let mut k = [0u8; 32];
+            k[0] = (i >> 8) as u8;
+            k[1] = i as u8;
+            k[31] = 1;
+            k
+        };
+        let mut msgs = data::Messages::new();
+        msgs.with_mut(|m, d| {
+            for (i, m) in m[..NUM].iter_mut().enumerate() {
+                m.pld.kind = data::Kind::Transaction;
+                m.pld.fee = 1;
+                m.pld.from = f;
+                m.pld.get_tx_mut().to = key(i);
+                m.pld.get_tx_mut().amount = 1;
+            }
+            d[0].0 = NUM;
+            Ok(())
+        }).expect("init");
+        let ports = vec![];
+        s.execute(&ports, &mut msgs).expect("execute");
+        assert_eq!(s.used, NUM + 1);
+        assert!(s.accounts.len() >= 2048);
+        for i in 0..NUM {
+            let k = key(i);
+            let p = data::AccountT::find_migrating(&s.old, &mut s.accounts, &k).expect("k");
+            assert_eq!(s.accounts[p].from, k);
+            assert_eq!(s.accounts[p].balance, 1);
+        }
+    }
+    /// an empty directory for the files of one test
+    fn test_dir(name: &str) -> String {
+        let dir = temp_dir().join(format!("{}-{}", name, process::id()));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).expect("create dir");
+        dir.to_str().unwrap().to_string()
+    }
+    #[test]
+    fn state_open_test() {
+        let dir = test_dir("TESTACCOUNTS");
+        let path = &format!("{}/accounts", dir);
+        let mut w = Wallet::new();
+        for _ in 0..2 {
+            w.add_keypair(Wallet::new_keypair());
+        }
+        let f = [255u8; 32];
+        let k = to32b(w.pubkeys[0]);
+        let signers = [k, to32b(w.pubkeys[1])];
+        let list = [
+            data::Account {
+                from: f,
+                balance: 1000u64,
+            },
+            data::Account {
+                from: k,
+                balance: 1000u64,
+            },
+        ];
+        let escrow = w.escrow(0, signers[1], 100, 5, 0, 1);
+        let id = escrow_id(&escrow);
+        let hash;
+        {
+            let mut s = State::open(path, 128, &list).expect("create");
+            assert_eq!(s.used, 2);
+            s.set_faucet(f).expect("faucet");
+            s.set_shard(1, 2).expect("shard");
+            let drop = vec![Wallet::airdrop(k, 10)];
+            run_groups(&mut s, &[w.multisig(0, 2, &signers, 1), escrow, drop]);
+            let mut msgs = data::Messages::new();
+            msgs.with_mut(|m, d| {
+                init_msgs(&mut m[..96]);
+                d[0].0 = 96;
+                Ok(())
+            }).expect("init");
+            msgs.height = 2;
+            let ports = vec![];
+            s.execute(&ports, &mut msgs).expect("execute");
+            s.flush().expect("flush");
+            hash = s.hash();
+            //stop in the middle of a resize
+            assert!(!s.old.is_empty());
+        }
+        assert!(Table::<data::Account>::exists(&Table::<data::Account>::resize_path(path)));
+        {
+            let mut s = State::open(path, 128, &[]).expect("open");
+            assert!(s.old.is_empty());
+            assert!(!Table::<data::Account>::exists(&Table::<data::Account>::resize_path(path)));
+            assert_eq!(s.used, 98);
+            assert_eq!(s.accounts.len(), 256);
+            let fp = data::AccountT::find(&s.accounts, &f).expect("f");
+            assert_eq!(s.accounts[fp].balance, 1000 - 96 * 3 - 10);
+            let mut to = [255u8; 32];
+            to[0] = 7;
+            let tp = data::AccountT::find(&s.accounts, &to).expect("to");
+            assert_eq!(s.accounts[tp].balance, 2);
+            //the records, the escrow and the counters are kept too
+            assert_eq!(s.multisig_used, 1);
+            assert_eq!(s.escrows_used, 1);
+            let ep = data::EscrowT::find(&s.escrows, &id).expect("escrow");
+            assert_eq!(s.escrows[ep].amount, 100);
+            assert_eq!(s.fees(), 96 + 2);
+            assert_eq!(s.height, 2);
+            assert_eq!(s.hash(), hash);
+            s.audit().expect("audit");
+            //so is the faucet, with the airdrops it paid
+            assert!(!s.faucet[0].allow(&k, 0));
+            s.set_faucet(f).expect("same faucet");
+            assert!(!s.faucet[0].allow(&k, 0));
+            assert_matches!(s.set_faucet([1u8; 32]), Err(Error::FaucetMismatch));
+            //and the shard it belongs to
+            s.set_shard(1, 2).expect("same shard");
+            assert_matches!(s.set_shard(0, 2), Err(Error::ShardMismatch(1, 2)));
+            assert_matches!(s.set_shard(1, 3), Err(Error::ShardMismatch(1, 2)));
+        }
+        remove_dir_all(&dir).expect("remove");
+    }
+    #[test]
+    fn state_crash_test() {
+        let dir = test_dir("TESTCRASH");
+        let path = &format!("{}/accounts", dir);
+        let f = [255u8; 32];
+        let list = [
+            data::Account {
+                from: f,
+                balance: 1000u64,
+            },
+        ];
+        let mut w = Wallet::new();
+        w.add_keypair(Wallet::new_keypair());
+        let to = to32b(w.pubkeys[0]);
+        //a state in memory that replays the same batches
+        let mut replica = State::from_list(&list).expect("replica");
+        {
+            let mut s = State::open(path, 128, &list).expect("create");
+            for _ in 0..3 {
+                let mut g = vec![data::Message::default()];
+                init_msgs(&mut g);
+                g[0].pld.get_tx_mut().to = to;
+                run_groups(&mut s, &[g.clone()]);
+                run_groups(&mut replica, &[g]);
+            }
+        }
+        //every batch is committed as it ends, nothing needs a flush
+        {
+            let mut s = State::open(path, 128, &[]).expect("open");
+            assert_eq!(s.height(), 3);
+            assert_eq!((s.height(), s.hash()), (replica.height(), replica.hash()));
+            assert_eq!(balance(&mut s, &f), balance(&mut replica, &f));
+            assert_eq!(balance(&mut s, &to), 6);
+            assert_eq!(s.fees(), replica.fees());
+            s.audit().expect("audit");
+            //stop in the middle of the next batch
+            s.begin_batch(4, 0).expect("begin");
+            assert!(s.debit(&f, 10, 1).expect("debit"));
+        }
+        assert_matches!(State::open(path, 128, &[]).err(), Some(Error::UnfinishedBatch(3)));
+        remove_dir_all(&dir).expect("remove");
+    }
+    fn run_groups(s: &mut State, groups: &[Vec<data::Message>]) -> Vec<data::Message> {
+        let mut msgs = data::Messages::new();
+        let num = msgs.with_mut(|m, d| {
+            let mut num = 0;
+            for g in groups {
+                m[num..num + g.len()].copy_from_slice(g);
+                num += g.len();
+            }
+            d[0].0 = num;
+            Ok(num)
+        }).expect("init");
+        msgs.height = s.height + 1;
+        msgs.time = s.time;
+        let ports = vec![];
+        s.execute(&ports, &mut msgs).expect("execute");
+        msgs.msgs[..num].to_vec()
+    }
+    fn balance(s: &mut State, k: &[u8; 32]) -> u64 {
+        let p = data::AccountT::find_migrating(&s.old, &mut s.accounts, k).expect("find");
+        s.accounts[p].balance
+    }
+    #[test]
+    fn state_multisig_test() {
+        let mut w = Wallet::new();
+        for _ in 0..3 {
+            w.add_keypair(Wallet::new_keypair());
+        }
+        let mut b = Wallet::new();
+        b.add_keypair(Wallet::new_keypair());
+        let funder = to32b(w.pubkeys[0]);
+        let signers = [to32b(w.pubkeys[1]), to32b(w.pubkeys[2]), to32b(b.pubkeys[0])];
+        let msig = multisig_key(2, &signers);
+        let list = [
+            data::Account {
+                from: funder,
+                balance: 1000,
+            },
+        ];
+        let mut s = State::from_list(&list).expect("from list");
+        let create = w.multisig(0, 2, &signers, 1);
+        let fund = vec![w.tx(0, msig, 500, 1)];
+        run_groups(&mut s, &[create, fund]);
+        assert_eq!(s.multisig_used, 1);
+        assert_eq!(balance(&mut s, &msig), 500);
+        assert_eq!(balance(&mut s, &funder), 1000 - 502);
+
+        let to = [7u8; 32];
+        //a plain transaction and a single signer are both rejected
+        let plain = Wallet::multisig_tx(msig, &[], to, 100, 1);
+        let mut one = Wallet::multisig_tx(msig, &[signers[2]], to, 100, 1);
+        b.cosign(&mut one).expect("cosign");
+        //the same signer twice only counts once
+        let mut twice = Wallet::multisig_tx(msig, &[signers[2], signers[2]], to, 100, 1);
+        b.cosign(&mut twice).expect("cosign");
+        //every kind is checked, not only transactions
+        let mut batch = Wallet::multisig_tx(msig, &[signers[2]], to, 100, 1);
+        batch[0].pld.kind = data::Kind::Batch;
+        b.cosign(&mut batch).expect("cosign");
+        run_groups(&mut s, &[plain, one, twice, batch]);
+        assert_eq!(balance(&mut s, &msig), 500);
+
+        let mut two = Wallet::multisig_tx(msig, &signers, to, 100, 1);
+        let mut other = two.clone();
+        w.cosign(&mut two).expect("cosign");
+        b.cosign(&mut other).expect("cosign");
+        Wallet::merge(&mut two, &other).expect("merge");
+        //a tampered copy doesn't verify
+        let mut bad = two.clone();
+        bad[0].pld.get_tx_mut().amount = 400;
+        let out = run_groups(&mut s, &[bad, two]);
+        assert_eq!(out[0].pld.state, data::State::Unknown);
+        assert_eq!(out[4].pld.state, data::State::Deposited);
+        assert_eq!(balance(&mut s, &msig), 399);
+        assert_eq!(balance(&mut s, &to), 100);
+
+        //one signer can't take the funds through an escrow and claim them
+        let thief = signers[0];
+        let signed = |group: &mut Vec<data::Message>, keys: &[[u8; 32]]| {
+            group[0].pld.from = msig;
+            for k in keys {
+                let mut m = data::Message::default();
+                m.pld.kind = data::Kind::Signature;
+                m.pld.from = *k;
+                group.push(m);
+            }
+        };
+        let mut steal = w.escrow(1, thief, 399, 0, 0, 0);
+        signed(&mut steal, &[thief]);
+        w.cosign(&mut steal).expect("cosign");
+        let id = escrow_id(&steal);
+        let out = run_groups(&mut s, &[steal, vec![w.claim(1, id, 0)]]);
+        assert_eq!(out[0].pld.state, data::State::Unknown);
+        assert_eq!(out[3].pld.state, data::State::Unknown);
+        assert_eq!(s.escrows_used, 0);
+        assert_eq!(balance(&mut s, &msig), 399);
+
+        //with enough signatures the account can escrow
+        let mut held = w.escrow(1, thief, 100, 0, 0, 1);
+        signed(&mut held, &[thief, signers[2]]);
+        w.cosign(&mut held).expect("cosign");
+        b.cosign(&mut held).expect("cosign");
+        let out = run_groups(&mut s, &[held]);
+        assert_eq!(out[0].pld.state, data::State::Withdrawn);
+        assert_eq!(s.escrows_used, 1);
+        assert_eq!(balance(&mut s, &msig), 298);
+    }
+    #[test]
+    fn state_escrow_test() {
+        let mut w = Wallet::new();
+        w.add_keypair(Wallet::new_keypair());
+        w.add_keypair(Wallet::new_keypair());
+        let from = to32b(w.pubkeys[0]);
+        let to = to32b(w.pubkeys[1]);
+        let list = [
+            data::Account {
+                from: from,
+                balance: 1000,
+            },
+        ];
+        let mut s = State::from_list(&list).expect("from list");
+        s.time = 100;
+        let by_height = w.escrow(0, to, 100, 3, 0, 1);
+        let by_time = w.escrow(0, to, 200, 0, 200, 1);
+        let hid = escrow_id(&by_height);
+        let tid = escrow_id(&by_time);
+        run_groups(&mut s, &[by_height.clone(), by_time, by_height]);
+        assert_eq!(s.escrows_used, 2);
+        assert_eq!(balance(&mut s, &from), 1000 - 101 - 201);
+        assert_eq!(s.height, 1);
+
+        //too early to claim, the recipient can't cancel
+        let out = run_groups(&mut s, &[vec![w.claim(1, hid, 1)], vec![w.cancel(1, tid, 1)]]);
+        assert_eq!(out[0].pld.state, data::State::Unknown);
+        assert_eq!(out[1].pld.state, data::State::Unknown);
+        assert_eq!(s.escrows_used, 2);
+
+        //height 3 is reached, the time lock is cancelled by the sender
+        let out = run_groups(&mut s, &[vec![w.claim(1, hid, 1)], vec![w.cancel(0, tid, 1)]]);
+        assert_eq!(out[0].pld.state, data::State::Deposited);
+        assert_eq!(out[1].pld.state, data::State::Deposited);
+        assert_eq!(s.escrows_used, 0);
+        assert_eq!(balance(&mut s, &to), 99);
+        assert_eq!(balance(&mut s, &from), 1000 - 101 - 201 + 199);
+        //made, claimed and cancelled escrows are in the history of the account they paid
+        let h = s.history.page(&from, 0, 10);
+        let h: Vec<_> = h.iter().map(|r| (r.0, r.1.amount, r.1.incoming, r.1.height)).collect();
+        assert_eq!(h, [(to, 200, 1, 3), (to, 200, 0, 1), (to, 100, 0, 1)]);
+        let h = s.history.page(&to, 0, 10);
+        assert_eq!((h.len(), h[0].0, h[0].1.incoming, h[0].1.height), (1, from, 1, 3));
+
+        //nothing left to claim
+        let again = w.escrow(0, to, 10, 0, 150, 1);
+        let aid = escrow_id(&again);
+        run_groups(&mut s, &[again, vec![w.claim(1, hid, 1)]]);
+        assert_eq!(balance(&mut s, &to), 99);
+        s.time = 150;
+        run_groups(&mut s, &[vec![w.cancel(0, aid, 1)], vec![w.claim(1, aid, 1)]]);
+        assert_eq!(balance(&mut s, &to), 108);
+    }
+    #[test]
+    fn state_batch_test() {
+        let mut w = Wallet::new();
+        w.add_keypair(Wallet::new_keypair());
+        let from = to32b(w.pubkeys[0]);
+        let list = [
+            data::Account {
+                from: from,
+                balance: 1000,
+            },
+        ];
+        let mut s = State::from_list(&list).expect("from list");
+        let legs: Vec<([u8; 32], u64)> = (0..data::MAX_BATCH)
+            .map(|i| ([i as u8 + 1; 32], i as u64 + 1))
+            .collect();
+        assert_matches!(w.batch(0, &[], 1).err(), Some(Error::ToLarge));
+        let mut over = legs.clone();
+        over.push(([99u8; 32], 1));
+        assert_matches!(w.batch(0, &over, 1).err(), Some(Error::ToLarge));
+
+        let small = w.batch(0, &legs[..3], 1).expect("batch");
+        assert_eq!(small.len(), 3);
+        //more than the balance, none of the legs are paid
+        let big = w.batch(0, &[([1u8; 32], 500), ([2u8; 32], 500)], 1).expect("batch");
+        let full = w.batch(0, &legs, 1).expect("batch");
+        let out = run_groups(&mut s, &[small, big, full]);
+        assert_eq!(out[0].pld.state, data::State::Deposited);
+        assert_eq!(out[3].pld.state, data::State::Unknown);
+        assert_eq!(out[5].pld.state, data::State::Deposited);
+        let total: u64 = legs.iter().map(|l| l.1).sum();
+        assert_eq!(balance(&mut s, &from), 1000 - 7 - total - 1);
+        assert_eq!(balance(&mut s, &[1u8; 32]), 2);
+        assert_eq!(balance(&mut s, &[3u8; 32]), 6);
+        let last = legs[data::MAX_BATCH - 1];
+        assert_eq!(balance(&mut s, &last.0), last.1);
+        assert_eq!(s.used, data::MAX_BATCH + 1);
+        assert_eq!(s.history.page(&[1u8; 32], 0, 10).len(), 2);
+        assert_eq!(s.history.page(&from, 0, 100).len(), 3 + data::MAX_BATCH);
+    }
+    #[test]
+    fn state_swap_test() {
+        let mut a = Wallet::new();
+        a.add_keypair(Wallet::new_keypair());
+        let mut b = Wallet::new();
+        b.add_keypair(Wallet::new_keypair());
+        let (ka, kb) = (to32b(a.pubkeys[0]), to32b(b.pubkeys[0]));
+        let list = [
+            data::Account {
+                from: ka,
+                balance: 100,
+            },
+            data::Account {
+                from: kb,
+                balance: 50,
+            },
+        ];
+        let mut s = State::from_list(&list).expect("from list");
+        //b never signed its half
+        let unsigned = a.swap(0, kb, 10, 20, 1);
+        let mut signed = a.swap(0, kb, 10, 20, 1);
+        assert_eq!(b.cosign(&mut signed).expect("cosign"), 1);
+        //b can't cover its half, neither side moves
+        let mut short = a.swap(0, kb, 10, 40, 1);
+        b.cosign(&mut short).expect("cosign");
+        let out = run_groups(&mut s, &[unsigned, signed, short]);
+        assert_eq!(out[0].pld.state, data::State::Unknown);
+        assert_eq!(out[2].pld.state, data::State::Deposited);
+        assert_eq!(out[3].pld.state, data::State::Deposited);
+        assert_eq!(out[4].pld.state, data::State::Unknown);
+        assert_eq!(out[5].pld.state, data::State::Unknown);
+        assert_eq!(balance(&mut s, &ka), 100 - 10 - 1 + 20);
+        assert_eq!(balance(&mut s, &kb), 50 - 20 - 1 + 10);
+        let h = s.history.page(&ka, 0, 10);
+        assert_eq!(h.len(), 2);
+        assert_eq!(h[0].0, kb);
+        assert_eq!(h[0].1.incoming, 1);
+        assert_eq!(h[0].1.amount, 20);
+    }
+    #[test]
     fn state_send_test() {
         const NUM: usize = 128usize;
         let f = [255u8; 32];
@@ -286,7 +1916,7 @@ mod tests {
             b_reader.recycle(d_);
             Ok(())
         }).is_ok());
-        assert!(o.listen(Port::Sender, move |_p, d| sender.run(d)).is_ok());
+        assert!(o.listen_idle(Port::Sender, move |_p, d| sender.run(d)).is_ok());
 
         let mut msgs = [data::Message::default(); NUM];
         init_msgs(&mut msgs);
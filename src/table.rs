@@ -0,0 +1,257 @@
+//! backing storage for the hash tables, either a heap vector or a memory-mapped file.
+//!
+//! the mapped file is a `HEADER` sized header followed by the raw C layout of
+//! the table, so the element type must be `repr(C)` and valid when all of its
+//! bytes are zero. a file with another magic, version or element size isn't opened.
+
+use std::fs::{remove_file, rename, File, OpenOptions};
+use std::mem::size_of;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr::null_mut;
+use std::slice::{from_raw_parts, from_raw_parts_mut};
+use nix::libc::c_void;
+use nix::sys::mman::{mmap, msync, munmap, MapFlags, MsFlags, ProtFlags};
+use result::{Error, Result};
+
+/// bytes in front of the elements of a mapped file, keeps them aligned
+const HEADER: usize = 64;
+const MAGIC: [u8; 8] = *b"loomtbl\0";
+/// bumped whenever the layout of a mapped file changes
+pub const VERSION: u32 = 1;
+
+#[repr(C)]
+struct Header {
+    magic: [u8; 8],
+    version: u32,
+    /// size of one element
+    size: u32,
+    len: u64,
+}
+
+pub struct Mapped<T: Copy> {
+    /// start of the mapping, the header
+    base: *mut u8,
+    ptr: *mut T,
+    len: usize,
+    path: String,
+    _file: File,
+}
+
+//the mapping is owned, it's safe to hand it to another thread
+unsafe impl<T: Copy> Send for Mapped<T> {}
+
+impl<T: Copy> Mapped<T> {
+    /// create or truncate `path` to hold `len` zeroed elements
+    pub fn create(path: &str, len: usize) -> Result<Mapped<T>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len((HEADER + len * size_of::<T>()) as u64)?;
+        let m = Self::map(path, file, len)?;
+        let h = Header {
+            magic: MAGIC,
+            version: VERSION,
+            size: size_of::<T>() as u32,
+            len: len as u64,
+        };
+        unsafe { (m.base as *mut Header).write(h) };
+        Ok(m)
+    }
+    /// map an existing table file, the length is read from its header
+    pub fn open(path: &str) -> Result<Mapped<T>> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let size = file.metadata()?.len() as usize;
+        let len = size.saturating_sub(HEADER) / size_of::<T>();
+        if size != HEADER + len * size_of::<T>() {
+            return Err(Error::InvalidTable);
+        }
+        let m = Self::map(path, file, len)?;
+        let h = unsafe { &*(m.base as *const Header) };
+        if h.magic != MAGIC || h.version != VERSION || h.size as usize != size_of::<T>()
+            || h.len as usize != m.len
+        {
+            return Err(Error::InvalidTable);
+        }
+        Ok(m)
+    }
+    fn map(path: &str, file: File, len: usize) -> Result<Mapped<T>> {
+        let base = unsafe {
+            mmap(
+                null_mut(),
+                HEADER + len * size_of::<T>(),
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )? as *mut u8
+        };
+        Ok(Mapped {
+            base,
+            ptr: unsafe { base.add(HEADER) } as *mut T,
+            len,
+            path: path.to_string(),
+            _file: file,
+        })
+    }
+    fn size(&self) -> usize {
+        HEADER + self.len * size_of::<T>()
+    }
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    /// write the dirty pages back to the file and wait for them to land
+    pub fn flush(&self) -> Result<()> {
+        unsafe { msync(self.base as *mut c_void, self.size(), MsFlags::MS_SYNC)? };
+        Ok(())
+    }
+    /// move the backing file to `path`, the mapping stays valid
+    pub fn rename(&mut self, path: &str) -> Result<()> {
+        rename(&self.path, path)?;
+        self.path = path.to_string();
+        Ok(())
+    }
+}
+
+impl<T: Copy> Drop for Mapped<T> {
+    fn drop(&mut self) {
+        let _ = unsafe { munmap(self.base as *mut c_void, self.size()) };
+    }
+}
+
+impl<T: Copy> Deref for Mapped<T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        unsafe { from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl<T: Copy> DerefMut for Mapped<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+pub enum Table<T: Copy> {
+    Mem(Vec<T>),
+    Mapped(Mapped<T>),
+}
+
+impl<T: Copy + Default> Table<T> {
+    pub fn new(len: usize) -> Table<T> {
+        Table::Mem(vec![T::default(); len])
+    }
+    /// a table of `len` with the same kind of storage as `self`.
+    /// mapped tables are created next to the current file at `<path>.resize`
+    pub fn alloc(&self, len: usize) -> Result<Table<T>> {
+        match *self {
+            Table::Mem(_) => Ok(Self::new(len)),
+            Table::Mapped(ref m) => {
+                let path = Self::resize_path(m.path());
+                Ok(Table::Mapped(Mapped::create(&path, len)?))
+            }
+        }
+    }
+    pub fn resize_path(path: &str) -> String {
+        format!("{}.resize", path)
+    }
+    /// replace the old table once `self` has all of its entries,
+    /// `old` is consumed and its file, if any, is removed
+    pub fn replace(&mut self, old: Table<T>) -> Result<()> {
+        if let Table::Mapped(o) = old {
+            let path = o.path().to_string();
+            drop(o);
+            if let Table::Mapped(ref mut m) = *self {
+                return m.rename(&path);
+            }
+            remove_file(&path)?;
+        }
+        Ok(())
+    }
+    pub fn flush(&self) -> Result<()> {
+        match *self {
+            Table::Mem(_) => Ok(()),
+            Table::Mapped(ref m) => m.flush(),
+        }
+    }
+    pub fn exists(path: &str) -> bool {
+        Path::new(path).exists()
+    }
+}
+
+impl<T: Copy> Deref for Table<T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        match *self {
+            Table::Mem(ref v) => v,
+            Table::Mapped(ref m) => m,
+        }
+    }
+}
+
+impl<T: Copy> DerefMut for Table<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        match *self {
+            Table::Mem(ref mut v) => v,
+            Table::Mapped(ref mut m) => m,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use table::{Mapped, Table};
+    use result::Error;
+    use std::env::temp_dir;
+    use std::fs::{remove_file, write};
+    use std::process;
+
+    fn test_path(name: &str) -> String {
+        let p = temp_dir().join(format!("{}-{}", name, process::id()));
+        p.to_str().unwrap().to_string()
+    }
+    #[test]
+    fn mapped_test() {
+        let path = &test_path("TESTTABLE");
+        {
+            let mut m = Mapped::<u64>::create(path, 16).expect("create");
+            assert_eq!(m.len(), 16);
+            assert!(m.iter().all(|v| *v == 0));
+            m[3] = 42;
+            m.flush().expect("flush");
+        }
+        {
+            let m = Mapped::<u64>::open(path).expect("open");
+            assert_eq!(m.len(), 16);
+            assert_eq!(m[3], 42);
+        }
+        //another element size or a file without a header isn't opened
+        assert_matches!(Mapped::<u32>::open(path).err(), Some(Error::InvalidTable));
+        write(path, &[0u8; 128]).expect("write");
+        assert_matches!(Mapped::<u64>::open(path).err(), Some(Error::InvalidTable));
+        let empty = Mapped::<u64>::create(path, 0).expect("create");
+        assert_eq!(empty.len(), 0);
+        assert_eq!(Mapped::<u64>::open(path).expect("open").len(), 0);
+        remove_file(path).expect("remove");
+    }
+    #[test]
+    fn table_replace_test() {
+        let path = &test_path("TESTTABLE2");
+        let old = Table::Mapped(Mapped::<u64>::create(path, 4).expect("create"));
+        let mut new = old.alloc(8).expect("alloc");
+        assert!(Table::<u64>::exists(&Table::<u64>::resize_path(path)));
+        new[7] = 7;
+        new.replace(old).expect("replace");
+        assert!(!Table::<u64>::exists(&Table::<u64>::resize_path(path)));
+        let m = Mapped::<u64>::open(path).expect("open");
+        assert_eq!(m.len(), 8);
+        assert_eq!(m[7], 7);
+        remove_file(path).expect("remove");
+        let mem = Table::<u64>::new(2);
+        assert_eq!(mem.alloc(4).expect("alloc").len(), 4);
+    }
+}
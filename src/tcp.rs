@@ -0,0 +1,254 @@
+//! tcp ingress for clients that can't use udp. a frame is a little endian
+//! `u32` length followed by a packet in the `codec` format, so a group is
+//! never split. the messages of a frame go through the `Reader` checks like
+//! a datagram, and the replies to them are written back to the connection
+//! by the `Sender`. a connection that is idle for `IDLE_SECS` or doesn't take
+//! a reply for `WRITE_SECS` is closed.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::Duration;
+use codec;
+use data::{Message, MAX_PACKET, MAX_PACKET_MSGS};
+use otp::Ports;
+use reader::Reader;
+use result::{Error, Result};
+
+/// connections served at once, more are closed as they are accepted
+const MAX_CONNS: usize = 1024;
+/// seconds a connection can be idle before it's closed
+const IDLE_SECS: u64 = 60;
+/// seconds a write of replies can block before the connection is closed
+const WRITE_SECS: u64 = 5;
+
+/// write `msgs` as frames of as many messages as fit in a packet
+pub fn write_frames(s: &mut TcpStream, msgs: &[Message]) -> Result<()> {
+    let mut buf = [0u8; MAX_PACKET];
+    let mut num = 0;
+    while num < msgs.len() {
+        let (size, n) = codec::encode_packet(&msgs[num..], &mut buf)?;
+        s.write_all(&(size as u32).to_le_bytes())?;
+        s.write_all(&buf[..size])?;
+        num += n;
+    }
+    Ok(())
+}
+
+/// read the next frame into `msgs`, returns the number of messages
+pub fn read_frame(s: &mut TcpStream, msgs: &mut [Message]) -> Result<usize> {
+    let mut buf = [0u8; MAX_PACKET];
+    let size = read_size(s)?;
+    s.read_exact(&mut buf[..size])?;
+    codec::decode_packet(&buf[..size], msgs)
+}
+
+fn read_size(s: &mut TcpStream) -> Result<usize> {
+    let mut len = [0u8; 4];
+    s.read_exact(&mut len)?;
+    let size = u32::from_le_bytes(len) as usize;
+    if size == 0 || size > MAX_PACKET {
+        return Err(Error::InvalidLength(size));
+    }
+    Ok(size)
+}
+
+type Conns = Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<TcpStream>>>>>;
+
+pub struct Tcp {
+    listener: TcpListener,
+    reader: Arc<Reader>,
+    /// the write half of every open connection by peer address, each is
+    /// locked on its own so a slow client only holds up its own replies
+    conns: Conns,
+    /// the thread of each connection, see `Tcp::close`
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Tcp {
+    pub fn new(addr: SocketAddr, reader: Arc<Reader>) -> Result<Tcp> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Tcp {
+            listener,
+            reader,
+            conns: Arc::new(Mutex::new(HashMap::new())),
+            threads: Mutex::new(Vec::new()),
+        })
+    }
+    /// accept the pending connections, each is read by its own thread
+    pub fn run(&self, ports: &Ports) -> Result<()> {
+        match self.listener.accept() {
+            Ok((s, addr)) => {
+                if self.conns.lock().unwrap().len() >= MAX_CONNS {
+                    debug!("tcp connection from {:?} refused, too many", addr);
+                    return Ok(());
+                }
+                s.set_nonblocking(false)?;
+                s.set_nodelay(true)?;
+                s.set_read_timeout(Some(Duration::new(IDLE_SECS, 0)))?;
+                s.set_write_timeout(Some(Duration::new(WRITE_SECS, 0)))?;
+                let w = Arc::new(Mutex::new(s.try_clone()?));
+                self.conns.lock().unwrap().insert(addr, w);
+                let reader = self.reader.clone();
+                let conns = self.conns.clone();
+                let ports = ports.clone();
+                let t = spawn(move || {
+                    let r = Self::serve(s, addr, &reader, &ports);
+                    debug!("tcp connection from {:?} closed {:?}", addr, r);
+                    conns.lock().unwrap().remove(&addr);
+                });
+                let mut threads = self.threads.lock().unwrap();
+                threads.retain(|t| !t.is_finished());
+                threads.push(t);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                sleep(Duration::new(0, 10_000_000));
+            }
+            Err(e) => return Err(Error::IO(e)),
+        }
+        Ok(())
+    }
+    fn serve(mut s: TcpStream, addr: SocketAddr, reader: &Reader, ports: &Ports) -> Result<()> {
+        loop {
+            let size = read_size(&mut s).map_err(|e| {
+                //the stream can't be framed anymore
+                if let Error::InvalidLength(_) = e {
+                    reader.invalid(addr, &e);
+                }
+                e
+            })?;
+            let mut buf = [0u8; MAX_PACKET];
+            s.read_exact(&mut buf[..size])?;
+            let mut msgs = [Message::default(); MAX_PACKET_MSGS];
+            let num = match codec::decode_packet(&buf[..size], &mut msgs) {
+                Ok(num) => num,
+                Err(e) => {
+                    reader.invalid(addr, &e);
+                    continue;
+                }
+            };
+            let m = reader.allocate();
+            {
+                let mut v = m.write().unwrap();
+                v.msgs.clear();
+                v.msgs.extend_from_slice(&msgs[..num]);
+                v.data.clear();
+                v.data.push((num, addr));
+            }
+            reader.submit(ports, m)?;
+        }
+    }
+    /// write `msgs` to the connection from `addr`, false if there isn't one
+    pub fn send(&self, msgs: &[Message], addr: SocketAddr) -> Result<bool> {
+        let conn = match self.conns.lock().unwrap().get(&addr) {
+            Some(c) => c.clone(),
+            None => return Ok(false),
+        };
+        let mut s = conn.lock().unwrap();
+        let r = write_frames(&mut s, msgs);
+        if r.is_err() {
+            //a frame may be cut, the thread of the connection sees it closed
+            let _ = s.shutdown(Shutdown::Both);
+            self.conns.lock().unwrap().remove(&addr);
+        }
+        r.map(|_| true)
+    }
+    /// close every connection and wait for their threads to finish
+    pub fn close(&self) {
+        for c in self.conns.lock().unwrap().values() {
+            let _ = c.lock().unwrap().shutdown(Shutdown::Both);
+        }
+        let threads: Vec<_> = self.threads.lock().unwrap().drain(..).collect();
+        for t in threads {
+            let _ = t.join();
+        }
+    }
+}
+
+impl Drop for Tcp {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data;
+    use otp::{Data, Port, OTP};
+    use reader::{Reader, Reject};
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::sync::{Arc, Mutex};
+    use std::thread::sleep;
+    use std::time::Duration;
+    use tcp::{self, Tcp};
+
+    #[test]
+    fn tcp_test() {
+        let reader = Arc::new(Reader::new(12010).expect("reader"));
+        let t = Arc::new(Tcp::new("127.0.0.1:12010".parse().unwrap(), reader.clone()).expect("tcp"));
+        let mut o = OTP::new();
+        let a_t = t.clone();
+        assert_matches!(o.source(Port::Tcp, move |p| a_t.run(p)), Ok(()));
+        let b_reader = reader.clone();
+        assert_matches!(
+            o.listen(Port::Recycle, move |_p, d| {
+                b_reader.recycle(d);
+                Ok(())
+            }),
+            Ok(())
+        );
+        let mut sender = reader.sender().expect("sender");
+        sender.set_tcp(t.clone());
+        assert_matches!(o.listen_idle(Port::Sender, move |_p, d| sender.run(d)), Ok(()));
+        let got = Arc::new(Mutex::new(Vec::new()));
+        let c_got = got.clone();
+        assert_matches!(
+            o.listen(Port::State, move |p, d| {
+                if let Data::SharedMessages(m) = d.clone() {
+                    let v = m.read().unwrap();
+                    c_got.lock().unwrap().extend_from_slice(&v.data);
+                    //answer with the messages of the batch
+                    let r = Data::SendMessages(v.msgs.clone(), v.data[0].1);
+                    OTP::send(p, Port::Sender, r)?;
+                }
+                OTP::send(p, Port::Recycle, d)
+            }),
+            Ok(())
+        );
+        let mut s = TcpStream::connect("127.0.0.1:12010").expect("connect");
+        let mut m = data::Message::default();
+        m.pld.kind = data::Kind::Transaction;
+        let mut msgs = vec![m; data::MAX_BATCH + 1];
+        for (i, m) in msgs.iter_mut().enumerate() {
+            m.sig[0] = i as u8;
+        }
+        tcp::write_frames(&mut s, &msgs).expect("write");
+        let mut r = vec![data::Message::default(); data::MAX_BATCH];
+        assert_eq!(tcp::read_frame(&mut s, &mut r).expect("read"), data::MAX_BATCH);
+        assert_eq!(tcp::read_frame(&mut s, &mut r).expect("read"), 1);
+        let local = s.local_addr().expect("addr");
+        assert_eq!(got.lock().unwrap()[1], (1, local));
+
+        //a frame that is too large closes the connection
+        s.write_all(&(data::MAX_PACKET as u32 + 1).to_le_bytes()).expect("write");
+        assert!(tcp::read_frame(&mut s, &mut r).is_err());
+        sleep(Duration::new(0, 100_000_000));
+        assert_eq!(reader.rejected(Reject::Length), 1);
+        assert!(!t.send(&msgs, local).expect("send"));
+
+        //closing ends the connections that are still open
+        let mut s = TcpStream::connect("127.0.0.1:12010").expect("connect");
+        let mut m = msgs[0];
+        m.sig[0] = 0xff;
+        tcp::write_frames(&mut s, &[m]).expect("write");
+        assert_eq!(tcp::read_frame(&mut s, &mut r).expect("read"), 1);
+        t.close();
+        assert!(t.threads.lock().unwrap().is_empty());
+        assert!(tcp::read_frame(&mut s, &mut r).is_err());
+        assert!(o.shutdown().is_ok());
+    }
+}
@@ -1,15 +1,16 @@
 //! wallet library
 
-use std::slice::from_raw_parts;
+use std::slice::from_mut;
 use std::fs::File;
-use std::mem::transmute;
-use std::mem::size_of;
 use std::io::Read;
 use std::io::Write;
 use crypto::ed25519;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
 use rand::Rng;
 use rand::os::OsRng;
 
+use codec;
 use data;
 use result::Result;
 use result::Error;
@@ -56,22 +57,98 @@ impl EncryptedWallet {
             pubkeys: self.pubkeys.clone(),
             privkeys: pks,
         };
+        //the secret of an old key is lost, it can't be migrated
+        for (i, (s, p)) in w.privkeys.iter().zip(&w.pubkeys).enumerate() {
+            if !matched((*s, *p)) {
+                return Err(Error::OldKeypair(i));
+            }
+        }
         Ok(w)
     }
 }
 
+//the keys are kept as little endian words in the wallet file
+fn to_bytes(k: &[u64], b: &mut [u8]) {
+    for (w, c) in k.iter().zip(b.chunks_mut(8)) {
+        c.copy_from_slice(&w.to_le_bytes());
+    }
+}
+fn from_bytes(b: &[u8], k: &mut [u64]) {
+    for (w, c) in k.iter_mut().zip(b.chunks(8)) {
+        let mut le = [0u8; 8];
+        le.copy_from_slice(c);
+        *w = u64::from_le_bytes(le);
+    }
+}
+
 pub fn to32b(k: [u64; 4]) -> [u8; 32] {
-    unsafe { transmute::<[u64; 4], [u8; 32]>(k) }
+    let mut b = [0u8; 32];
+    to_bytes(&k, &mut b);
+    b
 }
 pub fn from32b(k: [u8; 32]) -> [u64; 4] {
-    unsafe { transmute::<[u8; 32], [u64; 4]>(k) }
+    let mut w = [0u64; 4];
+    from_bytes(&k, &mut w);
+    w
 }
 
 pub fn to64b(k: [u64; 8]) -> [u8; 64] {
-    unsafe { transmute::<[u64; 8], [u8; 64]>(k) }
+    let mut b = [0u8; 64];
+    to_bytes(&k, &mut b);
+    b
 }
 pub fn from64b(k: [u8; 64]) -> [u64; 8] {
-    unsafe { transmute::<[u8; 64], [u64; 8]>(k) }
+    let mut w = [0u64; 8];
+    from_bytes(&k, &mut w);
+    w
+}
+
+/// the bytes that every key in `group` signs, the encoded payloads of the
+/// group with the `state` field cleared
+pub fn group_bytes(group: &[data::Message]) -> Vec<u8> {
+    let mut v = Vec::with_capacity(codec::PAYLOAD_SIZE * group.len());
+    for m in group {
+        let mut pld = m.pld;
+        pld.state = data::State::Unknown;
+        codec::encode_payload(&pld, &mut v);
+    }
+    v
+}
+
+/// true if the public key of `kp` is the one its secret signs for. the keys
+/// that were made from a 64 byte seed aren't, ed25519 keeps only the first
+/// 32 bytes of the seed in the secret but derived the public key from all of
+/// them, so every signature they make fails `verify`
+fn matched(kp: Keypair) -> bool {
+    let secret = to64b(kp.0);
+    ed25519::keypair(&secret[..32]).1 == to32b(kp.1)
+}
+
+/// check the signature of `group[ix]` by the key in its `from`
+pub fn verify(group: &[data::Message], ix: usize) -> bool {
+    let d = group_bytes(group);
+    ed25519::verify(&d, &group[ix].pld.from, &group[ix].sig)
+}
+
+/// address of the multisig account that needs `threshold` of `signers`
+pub fn multisig_key(threshold: u32, signers: &[[u8; 32]]) -> [u8; 32] {
+    let mut h = Sha256::new();
+    h.input(&threshold.to_le_bytes());
+    for k in signers {
+        h.input(k);
+    }
+    let mut key = [0u8; 32];
+    h.result(&mut key);
+    key
+}
+
+/// id of the escrow created by the `Escrow` group
+pub fn escrow_id(group: &[data::Message]) -> [u8; 32] {
+    let mut h = Sha256::new();
+    h.input(&group_bytes(group));
+    let mut id = [0u8; 32];
+    h.result(&mut id);
+    id
 }
 
 impl Wallet {
@@ -103,21 +180,23 @@ impl Wallet {
     }
     pub fn new_keypair() -> Keypair {
         let mut rnd: OsRng = OsRng::new().unwrap();
-        let mut seed = [0u8; 64];
+        //ed25519 only keeps the first 32 bytes of the seed in the secret key,
+        //a longer seed derives a public key that doesn't match the signatures
+        let mut seed = [0u8; 32];
         rnd.fill_bytes(&mut seed);
         let (a, b) = ed25519::keypair(&seed);
-        assert!(cfg!(target_endian = "little"));
         let ap = from64b(a);
         let bp = from32b(b);
         (ap, bp)
     }
     pub fn sign(kp: Keypair, msg: &mut data::Message) {
-        let sz = size_of::<data::Payload>();
-        let p = &msg.pld as *const data::Payload;
-        assert!(cfg!(target_endian = "little"));
-        let buf = unsafe { transmute(from_raw_parts(p as *const u8, sz)) };
+        Self::sign_group(kp, from_mut(msg), 0);
+    }
+    /// sign all of `group` with `kp` and store the signature in `group[ix]`
+    pub fn sign_group(kp: Keypair, group: &mut [data::Message], ix: usize) {
+        let d = group_bytes(group);
         let pk = to64b(kp.0);
-        msg.sig = ed25519::signature(buf, &pk);
+        group[ix].sig = ed25519::signature(&d, &pk);
     }
     pub fn find(&self, from: [u8; 32]) -> Result<usize> {
         let fk = from32b(from);
@@ -129,6 +208,18 @@ impl Wallet {
         Err(Error::PubKeyNotFound)
     }
     pub fn tx(&self, key: usize, to: [u8; 32], amnt: u64, fee: u64) -> data::Message {
+        self.tx_nonce(key, to, amnt, fee, 0)
+    }
+    /// `tx` with `nonce` in `lvh_count`, so that equal transfers get different
+    /// signatures and loomd doesn't take the second for a retransmission
+    pub fn tx_nonce(
+        &self,
+        key: usize,
+        to: [u8; 32],
+        amnt: u64,
+        fee: u64,
+        nonce: u64,
+    ) -> data::Message {
         let data = data::MessageData {
             tx: data::Transaction {
                 to: to,
@@ -141,6 +232,7 @@ impl Wallet {
         msg.pld.fee = fee;
         msg.pld.data = data;
         msg.pld.kind = data::Kind::Transaction;
+        msg.pld.lvh_count = nonce;
         Self::sign((self.privkeys[key], self.pubkeys[key]), &mut msg);
         msg
     }
@@ -160,12 +252,287 @@ impl Wallet {
         Self::sign((self.privkeys[key], self.pubkeys[key]), &mut msg);
         msg
     }
+    /// ask for the balances of all of `accs` with one signed group,
+    /// the answer is the same group with the amounts filled in
+    pub fn check_balances(
+        &self,
+        key: usize,
+        accs: &[[u8; 32]],
+        fee: u64,
+    ) -> Result<Vec<data::Message>> {
+        if accs.is_empty() || accs.len() > data::MAX_BALANCES {
+            return Err(Error::ToLarge);
+        }
+        let mut group = vec![data::Message::default(); accs.len() + 1];
+        group[0].pld.kind = data::Kind::GetBalances;
+        group[0].pld.from = to32b(self.pubkeys[key]);
+        group[0].pld.fee = fee;
+        for (m, k) in group[1..].iter_mut().zip(accs) {
+            m.pld.kind = data::Kind::BalanceKey;
+            m.pld.data = data::MessageData {
+                bal: data::GetBalance {
+                    key: *k,
+                    amount: 0,
+                },
+            };
+        }
+        Self::sign_group((self.privkeys[key], self.pubkeys[key]), &mut group, 0);
+        Ok(group)
+    }
+    /// ask for up to `num` transfers of `acc`, newest first, skipping the
+    /// `start` newest, see `data::GetHistory`
+    pub fn get_history(
+        &self,
+        key: usize,
+        acc: [u8; 32],
+        start: u32,
+        num: u32,
+        fee: u64,
+    ) -> data::Message {
+        let mut msg = data::Message::default();
+        msg.pld.kind = data::Kind::GetHistory;
+        msg.pld.from = to32b(self.pubkeys[key]);
+        msg.pld.fee = fee;
+        msg.pld.data = data::MessageData {
+            hist: data::GetHistory {
+                key: acc,
+                start,
+                num,
+            },
+        };
+        Self::sign((self.privkeys[key], self.pubkeys[key]), &mut msg);
+        msg
+    }
+    /// ask the faucet of a testnet daemon to send `amnt` to `to`, zero asks for
+    /// the most it pays. the request isn't signed
+    pub fn airdrop(to: [u8; 32], amnt: u64) -> data::Message {
+        let mut msg = data::Message::default();
+        msg.pld.kind = data::Kind::Airdrop;
+        msg.pld.data = data::MessageData {
+            tx: data::Transaction { to, amount: amnt },
+        };
+        msg
+    }
+    /// ask the daemon for the rate limit state of `key`
+    pub fn get_stats(key: [u8; 32]) -> data::Message {
+        let mut msg = data::Message::default();
+        msg.pld.kind = data::Kind::GetStats;
+        msg.pld.from = key;
+        msg.pld.data = data::MessageData {
+            stats: data::Stats {
+                key,
+                ..data::Stats::default()
+            },
+        };
+        msg
+    }
+    /// ask the daemon to audit its supply, `key` pays the fee
+    pub fn get_audit(&self, key: usize, fee: u64) -> data::Message {
+        let mut msg = data::Message::default();
+        msg.pld.kind = data::Kind::GetAudit;
+        msg.pld.from = to32b(self.pubkeys[key]);
+        msg.pld.fee = fee;
+        Self::sign((self.privkeys[key], self.pubkeys[key]), &mut msg);
+        msg
+    }
+    /// ask the daemon for its state hash, `key` pays the fee
+    pub fn get_hash(&self, key: usize, fee: u64) -> data::Message {
+        let mut msg = data::Message::default();
+        msg.pld.kind = data::Kind::GetHash;
+        msg.pld.from = to32b(self.pubkeys[key]);
+        msg.pld.fee = fee;
+        Self::sign((self.privkeys[key], self.pubkeys[key]), &mut msg);
+        msg
+    }
+    /// create a multisig account that needs `threshold` of `signers` to spend,
+    /// `key` pays the fee
+    pub fn multisig(
+        &self,
+        key: usize,
+        threshold: u32,
+        signers: &[[u8; 32]],
+        fee: u64,
+    ) -> Vec<data::Message> {
+        let mut group = vec![data::Message::default(); signers.len() + 1];
+        group[0].pld.kind = data::Kind::MultiSig;
+        group[0].pld.from = to32b(self.pubkeys[key]);
+        group[0].pld.fee = fee;
+        group[0].pld.data = data::MessageData {
+            msig: data::MultiSig {
+                key: multisig_key(threshold, signers),
+                threshold,
+                num: signers.len() as u32,
+            },
+        };
+        for (m, k) in group[1..].iter_mut().zip(signers) {
+            m.pld.kind = data::Kind::Signature;
+            m.pld.from = *k;
+        }
+        Self::sign_group((self.privkeys[key], self.pubkeys[key]), &mut group, 0);
+        group
+    }
+    /// pay every `(to, amount)` in `legs` with a single signed group,
+    /// the daemon applies all of them or none
+    pub fn batch(
+        &self,
+        key: usize,
+        legs: &[([u8; 32], u64)],
+        fee: u64,
+    ) -> Result<Vec<data::Message>> {
+        if legs.is_empty() || legs.len() > data::MAX_BATCH {
+            return Err(Error::ToLarge);
+        }
+        let mut group = vec![data::Message::default(); legs.len()];
+        for (m, &(to, amount)) in group.iter_mut().zip(legs) {
+            m.pld.kind = data::Kind::Leg;
+            m.pld.data = data::MessageData {
+                tx: data::Transaction { to, amount },
+            };
+        }
+        group[0].pld.kind = data::Kind::Batch;
+        group[0].pld.from = to32b(self.pubkeys[key]);
+        group[0].pld.fee = fee;
+        Self::sign_group((self.privkeys[key], self.pubkeys[key]), &mut group, 0);
+        Ok(group)
+    }
+    /// trade `give` from `key` for `take` from `with`, each side pays `fee`.
+    /// the `Counter` part is left for `with` to sign with `cosign`
+    pub fn swap(
+        &self,
+        key: usize,
+        with: [u8; 32],
+        give: u64,
+        take: u64,
+        fee: u64,
+    ) -> Vec<data::Message> {
+        let from = to32b(self.pubkeys[key]);
+        let mut group = vec![data::Message::default(); 2];
+        group[0].pld.kind = data::Kind::Swap;
+        group[0].pld.from = from;
+        group[0].pld.data = data::MessageData {
+            tx: data::Transaction {
+                to: with,
+                amount: give,
+            },
+        };
+        group[1].pld.kind = data::Kind::Counter;
+        group[1].pld.from = with;
+        group[1].pld.data = data::MessageData {
+            tx: data::Transaction {
+                to: from,
+                amount: take,
+            },
+        };
+        for m in &mut group {
+            m.pld.fee = fee;
+        }
+        Self::sign_group((self.privkeys[key], self.pubkeys[key]), &mut group, 0);
+        group
+    }
+    /// pay `amnt` to `to` once the PoH `height` and unix `time` are reached,
+    /// the payment can be cancelled until then, see `escrow_id`
+    pub fn escrow(
+        &self,
+        key: usize,
+        to: [u8; 32],
+        amnt: u64,
+        height: u64,
+        time: u64,
+        fee: u64,
+    ) -> Vec<data::Message> {
+        let mut group = vec![data::Message::default(); 2];
+        group[0].pld.kind = data::Kind::Escrow;
+        group[0].pld.from = to32b(self.pubkeys[key]);
+        group[0].pld.fee = fee;
+        group[0].pld.data = data::MessageData {
+            tx: data::Transaction { to, amount: amnt },
+        };
+        group[1].pld.kind = data::Kind::Condition;
+        group[1].pld.data = data::MessageData {
+            cond: data::Condition { height, time },
+        };
+        Self::sign_group((self.privkeys[key], self.pubkeys[key]), &mut group, 0);
+        group
+    }
+    /// claim the escrow `id` as its recipient, the fee is taken from the payment
+    pub fn claim(&self, key: usize, id: [u8; 32], fee: u64) -> data::Message {
+        self.redeem(data::Kind::Claim, key, id, fee)
+    }
+    /// cancel the escrow `id` as its sender, the fee is taken from the refund
+    pub fn cancel(&self, key: usize, id: [u8; 32], fee: u64) -> data::Message {
+        self.redeem(data::Kind::Cancel, key, id, fee)
+    }
+    fn redeem(&self, kind: data::Kind, key: usize, id: [u8; 32], fee: u64) -> data::Message {
+        let mut msg = data::Message::default();
+        msg.pld.kind = kind;
+        msg.pld.from = to32b(self.pubkeys[key]);
+        msg.pld.fee = fee;
+        msg.pld.data = data::MessageData {
+            redeem: data::Redeem { id },
+        };
+        Self::sign((self.privkeys[key], self.pubkeys[key]), &mut msg);
+        msg
+    }
+    /// unsigned transfer out of the multisig account `msig` with an empty
+    /// `Signature` part for each of `signers`, see `cosign` and `merge`
+    pub fn multisig_tx(
+        msig: [u8; 32],
+        signers: &[[u8; 32]],
+        to: [u8; 32],
+        amnt: u64,
+        fee: u64,
+    ) -> Vec<data::Message> {
+        let mut group = vec![data::Message::default(); signers.len() + 1];
+        group[0].pld.kind = data::Kind::Transaction;
+        group[0].pld.from = msig;
+        group[0].pld.fee = fee;
+        group[0].pld.data = data::MessageData {
+            tx: data::Transaction { to, amount: amnt },
+        };
+        for (m, k) in group[1..].iter_mut().zip(signers) {
+            m.pld.kind = data::Kind::Signature;
+            m.pld.from = *k;
+        }
+        group
+    }
+    /// sign every `Signature` or `Counter` part of `group` that belongs to a key
+    /// in this wallet, returns the number of parts signed
+    pub fn cosign(&self, group: &mut [data::Message]) -> Result<usize> {
+        let mut num = 0;
+        for ix in 1..group.len() {
+            let kind = group[ix].pld.kind;
+            if kind != data::Kind::Signature && kind != data::Kind::Counter {
+                continue;
+            }
+            if let Ok(key) = self.find(group[ix].pld.from) {
+                Self::sign_group((self.privkeys[key], self.pubkeys[key]), group, ix);
+                num += 1;
+            }
+        }
+        if num == 0 {
+            return Err(Error::PubKeyNotFound);
+        }
+        Ok(num)
+    }
+    /// collect the signatures from a copy of `group` that was signed by someone else
+    pub fn merge(group: &mut [data::Message], other: &[data::Message]) -> Result<()> {
+        if group.len() != other.len() || group_bytes(group) != group_bytes(other) {
+            return Err(Error::InvalidGroup);
+        }
+        for (m, o) in group.iter_mut().zip(other) {
+            if m.sig[..] == [0u8; 64][..] {
+                m.sig = o.sig;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod test {
     use wallet::Wallet;
-    use wallet::to32b;
+    use wallet::{from32b, from64b, multisig_key, to32b, verify};
+    use crypto::ed25519;
     use wallet::EncryptedWallet;
     use std::fs::remove_file;
     use result::Error;
@@ -198,6 +565,34 @@ mod test {
         assert_eq!(kp2.0, w.privkeys[f2]);
     }
     #[test]
+    fn test_multisig() {
+        let mut a = Wallet::new();
+        a.add_keypair(Wallet::new_keypair());
+        let mut b = Wallet::new();
+        b.add_keypair(Wallet::new_keypair());
+        let signers = [to32b(a.pubkeys[0]), to32b(b.pubkeys[0])];
+        let create = a.multisig(0, 2, &signers, 1);
+        assert_eq!(create.len(), 3);
+        assert!(verify(&create, 0));
+        assert_eq!(create[0].pld.get_msig().key, multisig_key(2, &signers));
+        assert!(multisig_key(1, &signers) != multisig_key(2, &signers));
+
+        let key = multisig_key(2, &signers);
+        let mut g = Wallet::multisig_tx(key, &signers, [1u8; 32], 10, 1);
+        let mut h = g.clone();
+        assert_eq!(a.cosign(&mut g).expect("a signs"), 1);
+        assert_eq!(b.cosign(&mut h).expect("b signs"), 1);
+        assert!(verify(&g, 1));
+        assert!(!verify(&g, 2));
+        Wallet::merge(&mut g, &h).expect("merge");
+        assert!(verify(&g, 1));
+        assert!(verify(&g, 2));
+        let other = Wallet::multisig_tx(key, &signers, [1u8; 32], 11, 1);
+        assert_matches!(Wallet::merge(&mut g, &other), Err(Error::InvalidGroup));
+        let c = Wallet::new();
+        assert_matches!(c.cosign(&mut g), Err(Error::PubKeyNotFound));
+    }
+    #[test]
     fn test_bad_file() {
         let e = EncryptedWallet::from_file("testdata/test_accounts.json");
         assert_matches!(e, Err(Error::JSON(_)));
@@ -218,6 +613,17 @@ mod test {
         assert_eq!(nw, ow);
     }
     #[test]
+    fn test_old_keypair() {
+        let mut w = Wallet::new();
+        let (a, b) = ed25519::keypair(&[7u8; 64]);
+        w.add_keypair((from64b(a), from32b(b)));
+        let ew = w.encrypt("foobar".as_bytes()).expect("encrypted");
+        assert_matches!(ew.decrypt("foobar".as_bytes()), Err(Error::OldKeypair(0)));
+        //the test wallet before its keys were made again
+        let ew = EncryptedWallet::from_file("testdata/old.wallet").expect("from file");
+        assert_matches!(ew.decrypt("foobar".as_bytes()), Err(Error::OldKeypair(0)));
+    }
+    #[test]
     fn test_saved() {
         let path = "testdata/loom.wallet";
         let ew = EncryptedWallet::from_file(&path).expect("from file");